@@ -24,6 +24,8 @@ pub struct CollisionResult {
     pub collided: bool,
 }
 
+/// A list of colliding voxels, as `(voxel of a, voxel of b, penetration)`.
+/// The penetration points from b towards a.
 pub type VoxelCollisionList<P> = Vec<(P, P, FVec)>;
 
 pub trait CollisionResolver {
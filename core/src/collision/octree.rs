@@ -1,38 +1,100 @@
 use super::*;
 use crate::collision::cube::collide_cube;
-use crate::collision::cube::collide_cube_sloppy;
 use crate::collision::cube::Cube;
 use crate::geometry::IVec;
 
 use crate::octree::*;
 use std::marker::PhantomData;
 
+/// How far two octrees may move relative to each other before the node pairs
+/// within a `CollisionCache` can no longer be reused.
+pub const CACHE_MARGIN: f32 = 0.5;
+
 #[allow(clippy::type_complexity)]
 pub struct OctreeCollisionResolver<'a, Set: OctreeSet>(PhantomData<(&'a (), fn(Set) -> Set)>);
 
-impl<'a, Set: 'a + OctreeSet> CollisionResolver for OctreeCollisionResolver<'a, Set> {
-    type Collider = &'a Set;
-    type Position = IVec;
-    fn collide(
-        a: Positioned<Self::Collider>,
-        b: Positioned<Self::Collider>,
-    ) -> VoxelCollisionList<Self::Position> {
-        let mut colliding_nodes = vec![((a.object.root(), a), (b.object.root(), b))];
-        let mut voxel_collisions: VoxelCollisionList<Self::Position> = vec![];
-        fn cube_from<Set: OctreeSet>(
-            (node, global): (Set::Node, Positioned<&Set>),
-        ) -> Positioned<Cube> {
-            let half_size = node.size() as f32 / 2.0;
-            let pos = node.position();
-            Positioned {
-                object: Cube::new(half_size),
-                rotation: global.rotation,
-                position: global.position
-                    + global.rotation
-                        * (FVec::new(pos.x as f32, pos.y as f32, pos.z as f32)
-                            + FVec::one() * half_size),
-            }
+/// The unit node pairs that were close to colliding the last time two octrees
+/// were collided, along with the pose of the second relative to the first.
+pub struct CollisionCache<N> {
+    pose: Option<(FVec, Rot)>,
+    /// The generations of the two octrees.
+    generations: (u64, u64),
+    pairs: Vec<(N, N)>,
+}
+impl<N> Default for CollisionCache<N> {
+    fn default() -> Self {
+        CollisionCache {
+            pose: None,
+            generations: (0, 0),
+            pairs: vec![],
         }
+    }
+}
+impl<N> CollisionCache<N> {
+    /// Makes the next collision descend from the roots.
+    /// This is done anyway whenever the generation of either octree changes.
+    pub fn clear(&mut self) {
+        self.pose = None;
+        self.pairs.clear();
+    }
+
+    /// Whether no nodes were close to colliding.
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+/// The bottom corner of the cube of a node. Each voxel is the unit cube
+/// centered on its position.
+fn corner<Node: OctreeNode>(node: Node) -> FVec {
+    let pos = node.position();
+    FVec::new(pos.x as f32, pos.y as f32, pos.z as f32) - FVec::one() * 0.5
+}
+
+fn cube_from<Set: OctreeSet>(node: Set::Node, global: Positioned<&Set>) -> Positioned<Cube> {
+    let half_size = node.size() as f32 / 2.0;
+    Positioned {
+        object: Cube::new(half_size),
+        rotation: global.rotation,
+        position: global.position + global.rotation * (corner(node) + FVec::one() * half_size),
+    }
+}
+
+/// Whether the bounding spheres of two cubes are within `margin` of each other.
+fn overlaps(a: Positioned<Cube>, b: Positioned<Cube>, margin: f32) -> bool {
+    let max_dist = 3.0f32.sqrt() * (a.object.size + b.object.size) + margin;
+    (a.position - b.position).mag_sq() < max_dist * max_dist
+}
+
+/// The position and rotation of b relative to a.
+fn relative_pose<T>(a: Positioned<T>, b: Positioned<T>) -> (FVec, Rot) {
+    let inverse = a.rotation.reversed();
+    (inverse * (b.position - a.position), inverse * b.rotation)
+}
+
+/// The furthest distance from the origin of an octree to any of its voxels.
+fn reach<Set: OctreeSet>(set: &Set) -> f32 {
+    let root = set.root();
+    let min = corner(root);
+    let max = min + FVec::one() * root.size() as f32;
+    FVec::new(
+        min.x.abs().max(max.x.abs()),
+        min.y.abs().max(max.y.abs()),
+        min.z.abs().max(max.z.abs()),
+    )
+    .mag()
+}
+
+impl<'a, Set: 'a + OctreeSet> OctreeCollisionResolver<'a, Set> {
+    /// Finds every pair of unit nodes, as `(node of a, node of b)`, whose
+    /// bounding spheres are within `margin` of each other.
+    fn descend(
+        a: Positioned<&'a Set>,
+        b: Positioned<&'a Set>,
+        margin: f32,
+    ) -> Vec<(Set::Node, Set::Node)> {
+        let mut colliding_nodes = vec![((a.object.root(), a), (b.object.root(), b))];
+        let mut unit_pairs = vec![];
         while !colliding_nodes.is_empty() {
             take_mut::take(&mut colliding_nodes, |nodes| {
                 let mut next = vec![];
@@ -41,26 +103,20 @@ impl<'a, Set: 'a + OctreeSet> CollisionResolver for OctreeCollisionResolver<'a,
                         std::mem::swap(&mut x, &mut y);
                     }
                     // x is always the larger one.
-                    let x_collide = cube_from(x);
-                    let y_collide = cube_from(y);
-                    if x.0.size() == 1 {
-                        let collision = collide_cube(x_collide, y_collide);
-                        if collision.collided {
-                            if std::ptr::eq(x.1.object, a.object) {
-                                voxel_collisions.push((
-                                    x.0.position(),
-                                    y.0.position(),
-                                    collision.penetration,
-                                ))
-                            } else {
-                                voxel_collisions.push((
-                                    y.0.position(),
-                                    x.0.position(),
-                                    collision.penetration,
-                                ));
-                            }
+                    if !overlaps(cube_from(x.0, x.1), cube_from(y.0, y.1), margin) {
+                        continue;
+                    }
+                    if x.0.is_unit() {
+                        // An empty root may be a unit node.
+                        if !x.0.is_full() || !y.0.is_full() {
+                            continue;
+                        }
+                        if std::ptr::eq(x.1.object, a.object) {
+                            unit_pairs.push((x.0, y.0));
+                        } else {
+                            unit_pairs.push((y.0, x.0));
                         }
-                    } else if collide_cube_sloppy(x_collide, y_collide).collided {
+                    } else {
                         for a in x.1.object.children(x.0) {
                             next.push((y, (a, x.1)));
                         }
@@ -69,6 +125,99 @@ impl<'a, Set: 'a + OctreeSet> CollisionResolver for OctreeCollisionResolver<'a,
                 next
             })
         }
-        voxel_collisions
+        unit_pairs
+    }
+
+    /// Collides the unit node pairs found by `descend`.
+    fn collide_pairs(
+        a: Positioned<&'a Set>,
+        b: Positioned<&'a Set>,
+        pairs: &[(Set::Node, Set::Node)],
+    ) -> VoxelCollisionList<IVec> {
+        pairs
+            .iter()
+            .filter_map(|&(x, y)| {
+                let collision = collide_cube(cube_from(x, a), cube_from(y, b));
+                if collision.collided {
+                    Some((x.position(), y.position(), collision.penetration))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Collides two octrees, starting from the node pairs in the cache when
+    /// the octrees have not moved far relative to each other since it was
+    /// filled. Otherwise, this descends from the roots and refills the cache.
+    pub fn collide_cached(
+        a: Positioned<&'a Set>,
+        b: Positioned<&'a Set>,
+        cache: &mut CollisionCache<Set::Node>,
+    ) -> VoxelCollisionList<IVec> {
+        let pose = relative_pose(a, b);
+        let generations = (a.object.generation(), b.object.generation());
+        let reusable = generations == cache.generations
+            && cache.pose.map_or(false, |(position, rotation)| {
+                let angle = 2.0 * (pose.1 * rotation.reversed()).s.abs().min(1.0).acos();
+                // The furthest that any point within b could have moved relative to a.
+                let moved = (pose.0 - position).mag() + angle * reach(b.object);
+                moved < CACHE_MARGIN
+            });
+        if !reusable {
+            cache.pairs = Self::descend(a, b, CACHE_MARGIN);
+            cache.pose = Some(pose);
+            cache.generations = generations;
+        }
+        Self::collide_pairs(a, b, &cache.pairs)
+    }
+}
+
+impl<'a, Set: 'a + OctreeSet> CollisionResolver for OctreeCollisionResolver<'a, Set> {
+    type Collider = &'a Set;
+    type Position = IVec;
+    fn collide(
+        a: Positioned<Self::Collider>,
+        b: Positioned<Self::Collider>,
+    ) -> VoxelCollisionList<Self::Position> {
+        Self::collide_pairs(a, b, &Self::descend(a, b, 0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::octree_set::BBOctreeSet;
+    use crate::octree::test_util::{cube, solid_storage};
+
+    fn block(size: i32) -> BBOctreeSet {
+        BBOctreeSet::from_chunk_storage(&solid_storage(cube(IVec::zero(), size)))
+    }
+
+    fn sorted(mut list: VoxelCollisionList<IVec>) -> VoxelCollisionList<IVec> {
+        list.sort_by_key(|(a, b, _)| (a.x, a.y, a.z, b.x, b.y, b.z));
+        list
+    }
+
+    #[test]
+    fn test_cached_collisions() {
+        let a = block(4);
+        let b = block(3);
+        let mut cache = CollisionCache::default();
+        let mut last = vec![];
+        for i in 0..40 {
+            let t = i as f32 * 0.05;
+            let x = Positioned::new(&a, FVec::zero(), Rot::identity());
+            let y = Positioned::new(
+                &b,
+                FVec::new(5.5 - t, 0.5, 0.0),
+                Rot::from_rotation_xy(t * 0.1),
+            );
+            let cached = OctreeCollisionResolver::collide_cached(x, y, &mut cache);
+            let fresh = OctreeCollisionResolver::<BBOctreeSet>::collide(x, y);
+            last = sorted(fresh);
+            assert_eq!(sorted(cached), last);
+        }
+        assert!(!last.is_empty());
     }
 }
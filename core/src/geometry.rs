@@ -4,6 +4,7 @@ use ultraviolet::Mat3;
 use ultraviolet::Rotor3;
 use ultraviolet::UVec3;
 use ultraviolet::Vec3;
+mod fixed;
 mod lmat;
 mod lvec;
 mod ulmat;
@@ -18,3 +19,7 @@ pub type Rot = Rotor3;
 pub type FMat = Mat3;
 pub type LMat = lmat::LMat;
 pub type ULMat = ulmat::ULMat;
+pub type Fixed = fixed::Fixed;
+pub type FixedVec = fixed::FixedVec;
+pub type FixedMat = fixed::FixedMat;
+pub type FixedRot = fixed::FixedRot;
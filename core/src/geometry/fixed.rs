@@ -0,0 +1,507 @@
+//! Fixed point numbers, for simulations that must give bit-identical results
+//! on every machine. Every operation here is done with integers, including
+//! the square roots and trigonometry.
+use crate::geometry::lmat::LMat;
+use crate::geometry::FMat;
+use crate::geometry::FVec;
+use crate::geometry::Rot;
+use std::ops::*;
+use ultraviolet::Bivec3;
+
+const FRACTION_BITS: u32 = 32;
+
+/// A signed 32.32 fixed point number.
+/// This covers magnitudes up to about two billion, with a precision of about
+/// 2e-10.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Default, Debug, Hash)]
+pub struct Fixed(pub i64);
+
+impl Fixed {
+    /// The number of bits below the point.
+    pub const FRACTION_BITS: u32 = FRACTION_BITS;
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(1 << FRACTION_BITS);
+    pub const HALF: Fixed = Fixed(1 << (FRACTION_BITS - 1));
+    pub const FRAC_PI_2: Fixed = Fixed(6_746_518_852);
+
+    pub const fn from_int(x: i64) -> Self {
+        Fixed(x << FRACTION_BITS)
+    }
+
+    /// The nearest number below `numerator / denominator`.
+    pub fn from_ratio(numerator: i64, denominator: i64) -> Self {
+        Fixed((((numerator as i128) << FRACTION_BITS) / denominator as i128) as i64)
+    }
+
+    /// Converts a float as `from_f64` does. Floats from about 0.002 (`2^-9`)
+    /// up to the range of `Fixed` are converted exactly, while smaller ones
+    /// lose their bits below `2^-32`, so that 1e-12 becomes zero. Either way
+    /// the result is the same on every machine, so this can be used to set up
+    /// a deterministic simulation.
+    pub fn from_f32(x: f32) -> Self {
+        Fixed::from_f64(x as f64)
+    }
+
+    /// Converts a float, truncating it towards zero to a multiple of `2^-32`.
+    /// Floats out of range saturate.
+    pub fn from_f64(x: f64) -> Self {
+        Fixed((x * (1u64 << FRACTION_BITS) as f64) as i64)
+    }
+
+    pub fn to_f32(self) -> f32 {
+        self.to_f64() as f32
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / (1u64 << FRACTION_BITS) as f64
+    }
+
+    pub fn abs(self) -> Self {
+        Fixed(self.0.abs())
+    }
+
+    /// The square root, rounded down. Negative numbers have a root of zero.
+    pub fn sqrt(self) -> Self {
+        if self.0 <= 0 {
+            return Fixed::ZERO;
+        }
+        Fixed(isqrt((self.0 as u128) << FRACTION_BITS) as i64)
+    }
+
+    /// The sine and cosine, found by reducing the angle to within a quarter
+    /// turn of zero and summing their Taylor series.
+    pub fn sin_cos(self) -> (Self, Self) {
+        // The nearest number of quarter turns.
+        let quarter_turns = ((((self.0 as i128) << FRACTION_BITS) / Fixed::FRAC_PI_2.0 as i128
+            + (1 << (FRACTION_BITS - 1)))
+            >> FRACTION_BITS) as i64;
+        let x = self - Fixed::FRAC_PI_2 * Fixed::from_int(quarter_turns);
+        let x2 = x * x;
+        let term = |n: i64, rest: Fixed| Fixed::ONE - x2 * rest / n;
+        let sin = x * term(
+            6,
+            term(20, term(42, term(72, term(110, term(156, Fixed::ONE))))),
+        );
+        let cos = term(
+            2,
+            term(12, term(30, term(56, term(90, term(132, Fixed::ONE))))),
+        );
+        match quarter_turns.rem_euclid(4) {
+            0 => (sin, cos),
+            1 => (cos, -sin),
+            2 => (-sin, -cos),
+            _ => (-cos, sin),
+        }
+    }
+}
+
+/// The square root of an integer, rounded down.
+fn isqrt(n: u128) -> u128 {
+    if n == 0 {
+        return 0;
+    }
+    // Newton's method, starting from a power of two above the root.
+    let bits = 128 - n.leading_zeros();
+    let mut x = 1u128 << (bits / 2 + 1);
+    loop {
+        let y = (x + n / x) / 2;
+        if y >= x {
+            return x;
+        }
+        x = y;
+    }
+}
+
+impl Add for Fixed {
+    type Output = Self;
+    fn add(self, other: Self) -> Self::Output {
+        Fixed(self.0 + other.0)
+    }
+}
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, other: Self) {
+        self.0 += other.0;
+    }
+}
+impl Sub for Fixed {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self::Output {
+        Fixed(self.0 - other.0)
+    }
+}
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, other: Self) {
+        self.0 -= other.0;
+    }
+}
+impl Mul for Fixed {
+    type Output = Self;
+    fn mul(self, other: Self) -> Self::Output {
+        Fixed(((self.0 as i128 * other.0 as i128) >> FRACTION_BITS) as i64)
+    }
+}
+impl Mul<i64> for Fixed {
+    type Output = Self;
+    fn mul(self, other: i64) -> Self::Output {
+        Fixed(self.0 * other)
+    }
+}
+impl Div for Fixed {
+    type Output = Self;
+    fn div(self, other: Self) -> Self::Output {
+        Fixed((((self.0 as i128) << FRACTION_BITS) / other.0 as i128) as i64)
+    }
+}
+impl Div<i64> for Fixed {
+    type Output = Self;
+    fn div(self, other: i64) -> Self::Output {
+        Fixed(self.0 / other)
+    }
+}
+impl Neg for Fixed {
+    type Output = Self;
+    fn neg(self) -> Self::Output {
+        Fixed(-self.0)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Default, Debug, Hash)]
+pub struct FixedVec {
+    pub x: Fixed,
+    pub y: Fixed,
+    pub z: Fixed,
+}
+
+impl FixedVec {
+    pub const fn new(x: Fixed, y: Fixed, z: Fixed) -> Self {
+        FixedVec { x, y, z }
+    }
+
+    pub fn zero() -> Self {
+        FixedVec::default()
+    }
+
+    pub fn from_f32(x: FVec) -> Self {
+        FixedVec::new(
+            Fixed::from_f32(x.x),
+            Fixed::from_f32(x.y),
+            Fixed::from_f32(x.z),
+        )
+    }
+
+    pub fn to_f32(self) -> FVec {
+        FVec::new(self.x.to_f32(), self.y.to_f32(), self.z.to_f32())
+    }
+
+    pub fn dot(self, other: Self) -> Fixed {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(self, other: Self) -> Self {
+        FixedVec::new(
+            self.y * other.z - self.z * other.y,
+            self.z * other.x - self.x * other.z,
+            self.x * other.y - self.y * other.x,
+        )
+    }
+
+    pub fn mag_sq(self) -> Fixed {
+        self.dot(self)
+    }
+
+    pub fn mag(self) -> Fixed {
+        self.mag_sq().sqrt()
+    }
+
+    /// The vector scaled to unit length, or zero if it is too short to have a
+    /// direction.
+    pub fn normalized(self) -> Self {
+        let mag = self.mag();
+        if mag == Fixed::ZERO {
+            FixedVec::zero()
+        } else {
+            self / mag
+        }
+    }
+}
+impl Add for FixedVec {
+    type Output = Self;
+    fn add(self, other: Self) -> Self::Output {
+        FixedVec::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+impl AddAssign for FixedVec {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+impl Sub for FixedVec {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self::Output {
+        FixedVec::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+impl SubAssign for FixedVec {
+    fn sub_assign(&mut self, other: Self) {
+        *self = *self - other;
+    }
+}
+impl Mul<Fixed> for FixedVec {
+    type Output = Self;
+    fn mul(self, other: Fixed) -> Self::Output {
+        FixedVec::new(self.x * other, self.y * other, self.z * other)
+    }
+}
+impl Div<Fixed> for FixedVec {
+    type Output = Self;
+    fn div(self, other: Fixed) -> Self::Output {
+        FixedVec::new(self.x / other, self.y / other, self.z / other)
+    }
+}
+impl Neg for FixedVec {
+    type Output = Self;
+    fn neg(self) -> Self::Output {
+        FixedVec::new(-self.x, -self.y, -self.z)
+    }
+}
+
+/// A matrix of fixed point numbers, stored as columns.
+#[derive(Copy, Clone, Eq, PartialEq, Default, Debug, Hash)]
+pub struct FixedMat(pub FixedVec, pub FixedVec, pub FixedVec);
+
+impl FixedMat {
+    pub fn zero() -> Self {
+        FixedMat::default()
+    }
+
+    pub fn identity() -> Self {
+        let (zero, one) = (Fixed::ZERO, Fixed::ONE);
+        FixedMat(
+            FixedVec::new(one, zero, zero),
+            FixedVec::new(zero, one, zero),
+            FixedVec::new(zero, zero, one),
+        )
+    }
+
+    pub fn from_f64(columns: [[f64; 3]; 3]) -> Self {
+        let column = |c: [f64; 3]| {
+            FixedVec::new(
+                Fixed::from_f64(c[0]),
+                Fixed::from_f64(c[1]),
+                Fixed::from_f64(c[2]),
+            )
+        };
+        FixedMat(column(columns[0]), column(columns[1]), column(columns[2]))
+    }
+
+    pub fn to_f32(self) -> FMat {
+        FMat::new(self.0.to_f32(), self.1.to_f32(), self.2.to_f32())
+    }
+
+    pub fn transposed(self) -> Self {
+        FixedMat(
+            FixedVec::new(self.0.x, self.1.x, self.2.x),
+            FixedVec::new(self.0.y, self.1.y, self.2.y),
+            FixedVec::new(self.0.z, self.1.z, self.2.z),
+        )
+    }
+}
+impl From<LMat> for FixedMat {
+    fn from(x: LMat) -> Self {
+        let column = |c: crate::geometry::LVec| {
+            FixedVec::new(
+                Fixed::from_int(c.0),
+                Fixed::from_int(c.1),
+                Fixed::from_int(c.2),
+            )
+        };
+        FixedMat(column(x.0), column(x.1), column(x.2))
+    }
+}
+impl Mul<FixedVec> for FixedMat {
+    type Output = FixedVec;
+    fn mul(self, other: FixedVec) -> FixedVec {
+        self.0 * other.x + self.1 * other.y + self.2 * other.z
+    }
+}
+impl Mul for FixedMat {
+    type Output = Self;
+    fn mul(self, other: Self) -> Self::Output {
+        FixedMat(self * other.0, self * other.1, self * other.2)
+    }
+}
+
+/// A rotor of fixed point numbers, with the same conventions as `Rot`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct FixedRot {
+    pub s: Fixed,
+    pub xy: Fixed,
+    pub xz: Fixed,
+    pub yz: Fixed,
+}
+
+impl Default for FixedRot {
+    fn default() -> Self {
+        FixedRot::identity()
+    }
+}
+
+impl FixedRot {
+    pub fn identity() -> Self {
+        FixedRot {
+            s: Fixed::ONE,
+            xy: Fixed::ZERO,
+            xz: Fixed::ZERO,
+            yz: Fixed::ZERO,
+        }
+    }
+
+    /// A rotation right handed around `w`, by an angle of its magnitude.
+    pub fn from_rotation_vector(w: FixedVec) -> Self {
+        let theta = w.mag();
+        if theta == Fixed::ZERO {
+            return FixedRot::identity();
+        }
+        let (sin, cos) = (theta / 2).sin_cos();
+        let axis = w / theta;
+        FixedRot {
+            s: cos,
+            xy: -axis.z * sin,
+            xz: axis.y * sin,
+            yz: -axis.x * sin,
+        }
+    }
+
+    pub fn from_f32(x: Rot) -> Self {
+        FixedRot {
+            s: Fixed::from_f32(x.s),
+            xy: Fixed::from_f32(x.bv.xy),
+            xz: Fixed::from_f32(x.bv.xz),
+            yz: Fixed::from_f32(x.bv.yz),
+        }
+    }
+
+    pub fn to_f32(self) -> Rot {
+        Rot::new(
+            self.s.to_f32(),
+            Bivec3::new(self.xy.to_f32(), self.xz.to_f32(), self.yz.to_f32()),
+        )
+    }
+
+    pub fn reversed(self) -> Self {
+        FixedRot {
+            s: self.s,
+            xy: -self.xy,
+            xz: -self.xz,
+            yz: -self.yz,
+        }
+    }
+
+    pub fn normalized(self) -> Self {
+        let mag =
+            (self.s * self.s + self.xy * self.xy + self.xz * self.xz + self.yz * self.yz).sqrt();
+        FixedRot {
+            s: self.s / mag,
+            xy: self.xy / mag,
+            xz: self.xz / mag,
+            yz: self.yz / mag,
+        }
+    }
+
+    pub fn into_matrix(self) -> FixedMat {
+        let (s, xy, xz, yz) = (self.s, self.xy, self.xz, self.yz);
+        let (s2, xy2, xz2, yz2) = (s * s, xy * xy, xz * xz, yz * yz);
+        FixedMat(
+            FixedVec::new(
+                s2 - xy2 - xz2 + yz2,
+                -(xz * yz + s * xy) * 2,
+                (xy * yz - s * xz) * 2,
+            ),
+            FixedVec::new(
+                (s * xy - xz * yz) * 2,
+                s2 - xy2 + xz2 - yz2,
+                -(s * yz + xy * xz) * 2,
+            ),
+            FixedVec::new(
+                (s * xz + xy * yz) * 2,
+                (s * yz - xy * xz) * 2,
+                s2 + xy2 - xz2 - yz2,
+            ),
+        )
+    }
+}
+/// The composition of two rotations, applying the right one first.
+impl Mul for FixedRot {
+    type Output = Self;
+    fn mul(self, q: Self) -> Self::Output {
+        FixedRot {
+            s: self.s * q.s - self.xy * q.xy - self.xz * q.xz - self.yz * q.yz,
+            xy: self.xy * q.s + self.s * q.xy + self.yz * q.xz - self.xz * q.yz,
+            xz: self.xz * q.s + self.s * q.xz - self.yz * q.xy + self.xy * q.yz,
+            yz: self.yz * q.s + self.s * q.yz + self.xz * q.xy - self.xy * q.xz,
+        }
+    }
+}
+impl Mul<FixedVec> for FixedRot {
+    type Output = FixedVec;
+    fn mul(self, v: FixedVec) -> FixedVec {
+        let fx = self.s * v.x + self.xy * v.y + self.xz * v.z;
+        let fy = self.s * v.y - self.xy * v.x + self.yz * v.z;
+        let fz = self.s * v.z - self.xz * v.x - self.yz * v.y;
+        let fw = self.xy * v.z - self.xz * v.y + self.yz * v.x;
+        FixedVec::new(
+            self.s * fx + self.xy * fy + self.xz * fz + self.yz * fw,
+            self.s * fy - self.xy * fx - self.xz * fw + self.yz * fz,
+            self.s * fz + self.xy * fw - self.xz * fx - self.yz * fy,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        if (a - b).abs() > 1e-8 {
+            assert_eq!(a, b);
+        }
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let a = Fixed::from_f64(2.5);
+        let b = Fixed::from_f64(-0.75);
+        assert_eq!((a + b).to_f64(), 1.75);
+        assert_eq!((a * b).to_f64(), -1.875);
+        assert_close((a / b).to_f64(), -10.0 / 3.0);
+        assert_eq!(Fixed::from_ratio(7, 2), Fixed::from_f64(3.5));
+        assert_eq!(Fixed::from_int(9).sqrt(), Fixed::from_int(3));
+        assert_close(Fixed::from_int(2).sqrt().to_f64(), 2f64.sqrt());
+        assert_close(Fixed::from_f64(1e-4).sqrt().to_f64(), 1e-2);
+    }
+
+    #[test]
+    fn test_sin_cos() {
+        for i in -200..200 {
+            let x = i as f64 * 0.173;
+            let (sin, cos) = Fixed::from_f64(x).sin_cos();
+            assert_close(sin.to_f64(), x.sin());
+            assert_close(cos.to_f64(), x.cos());
+        }
+    }
+
+    #[test]
+    fn test_rotor() {
+        let w = FVec::new(0.3, -1.2, 0.7);
+        let a = FixedRot::from_rotation_vector(FixedVec::from_f32(w));
+        let b = FixedRot::from_rotation_vector(FixedVec::from_f32(FVec::new(2.0, 0.1, -0.4)));
+        let expected_a = Rot::from_angle_plane(w.mag(), Bivec3::from_normalized_axis(w / w.mag()));
+        let v = FVec::new(1.0, 2.0, -3.0);
+        let fv = FixedVec::from_f32(v);
+        assert!((a.to_f32() * v - expected_a * v).mag() < 1e-5);
+        assert!(((a * fv).to_f32() - expected_a * v).mag() < 1e-5);
+        assert!(((a.into_matrix() * fv).to_f32() - expected_a * v).mag() < 1e-5);
+        assert!(((a * b * fv).to_f32() - (a.to_f32() * b.to_f32()) * v).mag() < 1e-5);
+        assert!(((a.reversed() * (a * fv)) - fv).mag() < Fixed::from_f64(1e-6));
+    }
+}
@@ -1,10 +1,34 @@
 use crate::geometry::IVec;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+static LAST_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// A generation that no set has had before.
+pub fn next_generation() -> u64 {
+    LAST_GENERATION.fetch_add(1, Ordering::Relaxed) + 1
+}
 
 pub trait OctreeSet {
     type Node: OctreeNode;
     type Iter: Iterator<Item = Self::Node>;
     fn root(&self) -> Self::Node;
     fn children(&self, node: Self::Node) -> Self::Iter;
+    /// A number that changes whenever the contents of the set change.
+    /// Sets with the same generation have the same contents, so anything
+    /// computed from a set can be kept until its generation changes.
+    fn generation(&self) -> u64;
+    /// Calls `f` on every full node that is not within another full node.
+    fn for_each_full(&self, mut f: impl FnMut(Self::Node)) {
+        let mut nodes = vec![self.root()];
+        while let Some(node) = nodes.pop() {
+            if node.is_full() {
+                f(node);
+            } else {
+                nodes.extend(self.children(node));
+            }
+        }
+    }
 }
 pub trait OctreeNode: Copy {
     /// The bottom corner of the octree node.
@@ -18,5 +42,77 @@ pub trait OctreeNode: Copy {
         self.size() == 1
     }
 }
+/// An octree set that can be edited one voxel at a time.
+pub trait EditableOctreeSet: OctreeSet {
+    /// Adds the voxel at a position, growing the octree if it is outside.
+    fn set(&mut self, position: IVec);
+    /// Removes the voxel at a position.
+    fn clear(&mut self, position: IVec);
+}
 /* Implementations */
+pub mod linear_octree_set;
 pub mod octree_set;
+pub mod ops;
+
+/// Helpers shared by the tests of the octree sets and their users.
+#[cfg(test)]
+pub(crate) mod test_util {
+    use crate::geometry::IVec;
+    use crate::storage::chunk_map::ChunkStorage;
+    use crate::storage::{VoxelStorage, Writer};
+    use building_blocks::prelude::IsEmpty;
+
+    /// A voxel that is either full or empty.
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    pub struct Solid(pub bool);
+    impl IsEmpty for Solid {
+        fn is_empty(&self) -> bool {
+            !self.0
+        }
+    }
+
+    /// A storage with exactly the voxels at `positions` full.
+    pub fn solid_storage(positions: impl IntoIterator<Item = IVec>) -> ChunkStorage<Solid> {
+        let mut storage = ChunkStorage::new(Solid(false), 16);
+        for position in positions {
+            *storage.get_mut(position).get_mut() = Solid(true);
+        }
+        storage
+    }
+
+    /// The positions of a cube of voxels.
+    pub fn cube(min: IVec, size: i32) -> Vec<IVec> {
+        let mut positions = vec![];
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    positions.push(min + IVec::new(x, y, z));
+                }
+            }
+        }
+        positions
+    }
+
+    /// A linear congruential generator, to keep randomized tests deterministic.
+    pub struct Random(u64);
+    impl Random {
+        pub fn new(seed: u64) -> Self {
+            Random(seed)
+        }
+
+        /// The next number, below 2^31.
+        pub fn next(&mut self) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            self.0 >> 33
+        }
+
+        /// A position within a cube of side `range` around the origin.
+        pub fn position(&mut self, range: i32) -> IVec {
+            let mut coordinate = || (self.next() % range as u64) as i32 - range / 2;
+            IVec::new(coordinate(), coordinate(), coordinate())
+        }
+    }
+}
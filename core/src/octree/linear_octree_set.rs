@@ -0,0 +1,388 @@
+use crate::for_each::ForEach;
+use crate::geometry::IVec;
+use crate::octree::next_generation;
+use crate::octree::OctreeNode;
+use crate::octree::OctreeSet;
+use crate::storage::VoxelStorage;
+use std::collections::BTreeMap;
+
+/// The largest depth of a `LinearOctreeSet`, limited by the size of the
+/// morton codes.
+pub const MAX_DEPTH: u8 = 21;
+
+/// A pointerless octree set.
+///
+/// The partially full nodes of each level are stored in morton order as a pair
+/// of child masks. As the children of a node are stored in the same order as
+/// their parents, the index of a child is found by counting the partially full
+/// children of all the nodes before its parent.
+#[derive(Clone, Debug)]
+pub struct LinearOctreeSet {
+    /// The bottom corner of the root node.
+    origin: IVec,
+    /// The number of levels below the root; the root has a size of `1 << depth`.
+    depth: u8,
+    /// Whether the root is full, in which case `levels` is empty.
+    full: bool,
+    /// The partially full nodes of each level, as `(occupied mask, full mask)`.
+    levels: Vec<Vec<(u8, u8)>>,
+    /// The index of the first partially full child of each node in the next level.
+    first_child: Vec<Vec<u32>>,
+    /// Ignored when comparing sets.
+    generation: u64,
+}
+
+impl PartialEq for LinearOctreeSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.origin == other.origin
+            && self.depth == other.depth
+            && self.full == other.full
+            && self.levels == other.levels
+    }
+}
+impl Eq for LinearOctreeSet {}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LinearOctreeNode {
+    /// A partially full node, at an index within a level of the set.
+    Branch {
+        position: IVec,
+        level: u8,
+        index: u32,
+        size: u64,
+    },
+    Full(IVec, u64),
+}
+
+/// Spreads out the lower 21 bits of a number so that there are two zero bits
+/// between each bit.
+fn spread(x: u64) -> u64 {
+    let mut x = x & 0x1f_ffff;
+    x = (x | x << 32) & 0x1f_0000_0000_ffff;
+    x = (x | x << 16) & 0x1f_0000_ff00_00ff;
+    x = (x | x << 8) & 0x100f_00f0_0f00_f00f;
+    x = (x | x << 4) & 0x10c3_0c30_c30c_30c3;
+    x = (x | x << 2) & 0x1249_2492_4924_9249;
+    x
+}
+
+/// The morton code of a position relative to the origin.
+fn morton(delta: IVec) -> u64 {
+    spread(delta.x as u64) | spread(delta.y as u64) << 1 | spread(delta.z as u64) << 2
+}
+
+/// The offset of a child within its parent, in units of the child's size.
+pub(crate) fn octant_offset(octant: u8) -> IVec {
+    IVec::new(
+        (octant & 1) as i32,
+        (octant >> 1 & 1) as i32,
+        (octant >> 2 & 1) as i32,
+    )
+}
+
+impl LinearOctreeSet {
+    /// Creates a set from every voxel in a storage for which `solid` is true.
+    pub fn from_storage<S: VoxelStorage<Position = IVec>>(
+        storage: &S,
+        solid: impl Fn(S::T) -> bool,
+    ) -> Self {
+        let mut positions = vec![];
+        storage.for_each(|(position, voxel)| {
+            if solid(voxel) {
+                positions.push(position);
+            }
+        });
+        LinearOctreeSet::from_positions(&positions)
+    }
+
+    /// Creates a set containing the unit voxels at each position.
+    pub fn from_positions(positions: &[IVec]) -> Self {
+        if positions.is_empty() {
+            return LinearOctreeSet::from_full_nodes(IVec::zero(), 0, vec![]);
+        }
+        let mut min = positions[0];
+        let mut max = positions[0];
+        for &position in positions {
+            min = min.min_by_component(position);
+            max = max.max_by_component(position);
+        }
+        let shape = max - min;
+        let size = (shape.x.max(shape.y).max(shape.z) as u64 + 1).next_power_of_two();
+        let depth = size.trailing_zeros() as u8;
+        LinearOctreeSet::from_full_nodes(
+            min,
+            depth,
+            positions
+                .iter()
+                .map(|&position| (depth, morton(position - min))),
+        )
+    }
+
+    /// Creates a set from full nodes, given as `(level, morton code)`.
+    /// The code of a node is the morton code of its position relative to the
+    /// origin, divided by its size. Nodes may overlap.
+    pub fn from_full_nodes(
+        origin: IVec,
+        depth: u8,
+        nodes: impl IntoIterator<Item = (u8, u64)>,
+    ) -> Self {
+        assert!(depth <= MAX_DEPTH, "The octree is too deep.");
+        // The masks of the partially full nodes on each level, keyed by code.
+        let mut masks = vec![BTreeMap::<u64, (u8, u8)>::new(); depth as usize + 1];
+        masks[0].insert(0, (0, 0));
+        for (level, code) in nodes {
+            if level == 0 {
+                return LinearOctreeSet {
+                    origin,
+                    depth,
+                    full: true,
+                    levels: vec![],
+                    first_child: vec![],
+                    generation: next_generation(),
+                };
+            }
+            let bit = 1 << (code & 7);
+            let parent = masks[level as usize - 1].entry(code >> 3).or_insert((0, 0));
+            parent.0 |= bit;
+            parent.1 |= bit;
+            let mut code = code >> 3;
+            for level in (1..level as usize).rev() {
+                let parent = masks[level - 1].entry(code >> 3).or_insert((0, 0));
+                let bit = 1 << (code & 7);
+                if parent.0 & bit != 0 {
+                    break;
+                }
+                parent.0 |= bit;
+                code >>= 3;
+            }
+        }
+        // Merge the nodes whose children are all full into their parents.
+        for level in (1..depth as usize).rev() {
+            let full_codes = masks[level]
+                .iter()
+                .filter(|(_, masks)| masks.1 == 0xff)
+                .map(|(&code, _)| code)
+                .collect::<Vec<_>>();
+            for code in full_codes {
+                masks[level].remove(&code);
+                masks[level - 1].get_mut(&(code >> 3)).unwrap().1 |= 1 << (code & 7);
+            }
+        }
+        if masks[0][&0].1 == 0xff {
+            return LinearOctreeSet::from_full_nodes(origin, depth, vec![(0, 0)]);
+        }
+        // Remove the nodes that are within full nodes.
+        for level in 1..depth as usize {
+            let (parents, children) = masks.split_at_mut(level);
+            let parents = &parents[level - 1];
+            children[0].retain(|&code, _| {
+                parents
+                    .get(&(code >> 3))
+                    .map_or(false, |&(_, full)| full & (1 << (code & 7)) == 0)
+            });
+        }
+        let levels = masks
+            .into_iter()
+            .map(|level| {
+                level
+                    .into_iter()
+                    .map(|(_, masks)| masks)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let first_child = levels
+            .iter()
+            .map(|level| {
+                let mut count = 0;
+                level
+                    .iter()
+                    .map(|&(occupied, full)| {
+                        let first = count;
+                        count += (occupied & !full).count_ones();
+                        first
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        LinearOctreeSet {
+            origin,
+            depth,
+            full: false,
+            levels,
+            first_child,
+            generation: next_generation(),
+        }
+    }
+
+    /// The bottom corner of the root node.
+    pub fn origin(&self) -> IVec {
+        self.origin
+    }
+
+    /// The number of levels below the root.
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+}
+
+impl OctreeSet for LinearOctreeSet {
+    type Node = LinearOctreeNode;
+    type Iter = std::vec::IntoIter<Self::Node>;
+    fn root(&self) -> Self::Node {
+        if self.full {
+            LinearOctreeNode::Full(self.origin, 1 << self.depth)
+        } else {
+            LinearOctreeNode::Branch {
+                position: self.origin,
+                level: 0,
+                index: 0,
+                size: 1 << self.depth,
+            }
+        }
+    }
+    fn children(&self, node: Self::Node) -> Self::Iter {
+        let mut out_vec = vec![];
+        match node {
+            LinearOctreeNode::Branch {
+                position,
+                level,
+                index,
+                size,
+            } => {
+                let (occupied, full) = self.levels[level as usize][index as usize];
+                let mut next_index = self.first_child[level as usize][index as usize];
+                let half_size = size / 2;
+                for octant in 0..8 {
+                    let bit = 1 << octant;
+                    if occupied & bit == 0 {
+                        continue;
+                    }
+                    let child_position = position + octant_offset(octant) * half_size as i32;
+                    if full & bit != 0 {
+                        out_vec.push(LinearOctreeNode::Full(child_position, half_size));
+                    } else {
+                        out_vec.push(LinearOctreeNode::Branch {
+                            position: child_position,
+                            level: level + 1,
+                            index: next_index,
+                            size: half_size,
+                        });
+                        next_index += 1;
+                    }
+                }
+            }
+            LinearOctreeNode::Full(pos, size) => {
+                if size > 1 {
+                    let half_size = size / 2;
+                    for octant in 0..8 {
+                        out_vec.push(LinearOctreeNode::Full(
+                            pos + octant_offset(octant) * half_size as i32,
+                            half_size,
+                        ));
+                    }
+                }
+            }
+        }
+        out_vec.into_iter()
+    }
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl OctreeNode for LinearOctreeNode {
+    fn position(self) -> IVec {
+        match self {
+            LinearOctreeNode::Branch { position, .. } => position,
+            LinearOctreeNode::Full(pos, _) => pos,
+        }
+    }
+    fn size(self) -> u64 {
+        match self {
+            LinearOctreeNode::Branch { size, .. } => size,
+            LinearOctreeNode::Full(_, size) => size,
+        }
+    }
+    fn is_full(self) -> bool {
+        matches!(self, LinearOctreeNode::Full(..))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collision::octree::OctreeCollisionResolver;
+    use crate::collision::{CollisionResolver, Positioned, VoxelCollisionList};
+    use crate::geometry::{FVec, Rot};
+    use crate::octree::octree_set::BBOctreeSet;
+    use crate::octree::test_util::{cube, solid_storage, Random, Solid};
+    use crate::storage::chunk_map::ChunkStorage;
+
+    /// A storage filled randomly within a cube.
+    fn random_storage(seed: u64, min: i32, max: i32, chance: u64) -> ChunkStorage<Solid> {
+        let mut random = Random::new(seed);
+        let corner = IVec::new(min, min, min);
+        solid_storage(
+            cube(corner, max - min)
+                .into_iter()
+                .filter(|_| random.next() % 100 < chance),
+        )
+    }
+
+    fn voxels<S: OctreeSet>(set: &S) -> Vec<(i32, i32, i32)> {
+        let mut out = vec![];
+        set.for_each_full(|node| {
+            let pos = node.position();
+            let size = node.size() as i32;
+            for x in 0..size {
+                for y in 0..size {
+                    for z in 0..size {
+                        out.push((pos.x + x, pos.y + y, pos.z + z));
+                    }
+                }
+            }
+        });
+        out.sort_unstable();
+        out
+    }
+
+    fn collisions<S: OctreeSet>(set: &S) -> VoxelCollisionList<IVec> {
+        let mut list = OctreeCollisionResolver::<S>::collide(
+            Positioned::new(set, FVec::zero(), Rot::identity()),
+            Positioned::new(set, FVec::new(3.5, -2.0, 1.0), Rot::from_rotation_xz(0.4)),
+        );
+        list.sort_by_key(|(a, b, _)| (a.x, a.y, a.z, b.x, b.y, b.z));
+        list
+    }
+
+    #[test]
+    fn test_matches_bb_octree_set() {
+        for (seed, min, max, chance) in [
+            (1, -7, 5, 50),
+            (2, -3, 9, 90),
+            (3, -20, -4, 5),
+            (4, 0, 16, 99),
+        ]
+        .iter()
+        .copied()
+        {
+            let storage = random_storage(seed, min, max, chance);
+            let linear = LinearOctreeSet::from_storage(&storage, |voxel| voxel.0);
+            let bb = BBOctreeSet::from_chunk_storage(&storage);
+            assert_eq!(voxels(&linear), voxels(&bb));
+            assert_eq!(collisions(&linear), collisions(&bb));
+        }
+    }
+
+    #[test]
+    fn test_full_and_empty() {
+        let empty = LinearOctreeSet::from_positions(&[]);
+        assert!(voxels(&empty).is_empty());
+        let full = LinearOctreeSet::from_positions(&cube(IVec::new(-2, -2, -2), 4));
+        assert_eq!(
+            full.root(),
+            LinearOctreeNode::Full(IVec::new(-2, -2, -2), 4)
+        );
+        assert_eq!(voxels(&full).len(), 64);
+    }
+}
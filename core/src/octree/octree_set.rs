@@ -1,4 +1,6 @@
 use crate::geometry::IVec;
+use crate::octree::next_generation;
+use crate::octree::EditableOctreeSet;
 use crate::octree::OctreeNode as OctreeNodeTrait;
 use crate::octree::OctreeSet as OctreeSetTrait;
 use crate::storage::chunk_map::ChunkStorage;
@@ -6,16 +8,58 @@ use crate::storage::VoxelStorage;
 use building_blocks::prelude::IsEmpty;
 use building_blocks::prelude::*;
 use building_blocks::storage::{OctreeNode, OctreeSet, OffsetTable};
+use std::collections::HashMap;
 
+#[derive(Clone)]
 pub struct BBOctreeSet {
     pub set: OctreeSet,
     table: OffsetTable,
+    /// The bottom corner and size of the root node.
+    root: (IVec, u64),
+    /// The nodes that have been edited since the set was built, keyed by their
+    /// bottom corner and size. Every ancestor of an edited node is also edited.
+    /// Patches are merged back as they are edited, so only nodes whose
+    /// contents differ from the underlying set, and the root, are kept.
+    patches: HashMap<(IVec, u64), State>,
+    generation: u64,
+}
+
+/// What a node contained before it was edited.
+#[derive(Clone, Copy)]
+enum Base {
+    Empty,
+    Full,
+    Node(OctreeNode),
+}
+
+/// The contents of a node.
+#[derive(Clone, Copy)]
+enum State {
+    Empty,
+    Full,
+    /// An unedited node from the underlying set.
+    Node(OctreeNode),
+    /// An edited node that is partially full.
+    /// Children that have not been edited are taken from the base.
+    Branch(Base),
 }
 
 impl BBOctreeSet {
     pub fn new(set: OctreeSet) -> Self {
+        let root = set.root_node().map_or((IVec::zero(), 1), |node| {
+            (node.octant().minimum().0.into(), 1 << node.power())
+        });
+        BBOctreeSet::with_root(set, root)
+    }
+    fn with_root(set: OctreeSet, root: (IVec, u64)) -> Self {
         let table = set.offset_table();
-        BBOctreeSet { set, table }
+        BBOctreeSet {
+            set,
+            table,
+            root,
+            patches: HashMap::new(),
+            generation: next_generation(),
+        }
     }
     pub fn from_chunk_storage<T: IsEmpty + Eq + Copy>(storage: &ChunkStorage<T>) -> Self {
         fn next_pow(a: i32) -> i32 {
@@ -30,7 +74,195 @@ impl BBOctreeSet {
         extent.shape = PointN([max_dist, max_dist, max_dist]);
         let mut array = Array3::fill(extent, storage.ambient());
         copy_extent(&extent, map, &mut array);
-        BBOctreeSet::new(OctreeSet::from_array3(&array, extent))
+        BBOctreeSet::with_root(
+            OctreeSet::from_array3(&array, extent),
+            (extent.minimum.0.into(), max_dist as u64),
+        )
+    }
+
+    fn root_state(&self) -> State {
+        if let Some(&state) = self.patches.get(&self.root) {
+            return state;
+        }
+        match self.set.root_node() {
+            Some(node) if node.is_leaf() => State::Full,
+            Some(node) => State::Node(node),
+            None => State::Empty,
+        }
+    }
+
+    /// The state of a child of a node with the given base.
+    fn child_state(&self, child: (IVec, u64), base: Base) -> State {
+        match self.patches.get(&child) {
+            Some(&state) => state,
+            None => self.base_state(child, base),
+        }
+    }
+
+    /// The state of a child of a node with the given base, ignoring any patch
+    /// of the child.
+    fn base_state(&self, child: (IVec, u64), base: Base) -> State {
+        match base {
+            Base::Empty => State::Empty,
+            Base::Full => State::Full,
+            Base::Node(node) => {
+                for i in 0..8 {
+                    if let Some(child_node) = self.set.get_child(&self.table, &node, i) {
+                        let position: IVec = child_node.octant().minimum().0.into();
+                        if position == child.0 {
+                            return if child_node.is_leaf() {
+                                State::Full
+                            } else {
+                                State::Node(child_node)
+                            };
+                        }
+                    }
+                }
+                State::Empty
+            }
+        }
+    }
+
+    /// Doubles the size of the root until it contains the position.
+    fn grow_to(&mut self, position: IVec) {
+        while !contains(self.root, position) {
+            let (pos, size) = self.root;
+            let old_state = match self.root_state() {
+                State::Node(node) => State::Branch(Base::Node(node)),
+                state => state,
+            };
+            self.patches.insert(self.root, old_state);
+            let s = size as i32;
+            let grow = |p: i32, x: i32| if x < p { p - s } else { p };
+            self.root = (
+                IVec::new(
+                    grow(pos.x, position.x),
+                    grow(pos.y, position.y),
+                    grow(pos.z, position.z),
+                ),
+                size * 2,
+            );
+            self.patches.insert(self.root, State::Branch(Base::Empty));
+        }
+    }
+
+    /// Sets the voxel at a position, updating only the nodes containing it.
+    fn edit(&mut self, position: IVec, full: bool) {
+        if full {
+            self.grow_to(position);
+        } else if !contains(self.root, position) {
+            return;
+        }
+        // The nodes from the root down to the parent of the voxel.
+        let mut path = vec![];
+        let mut node = self.root;
+        let mut state = self.root_state();
+        while node.1 > 1 {
+            let base = base_of(state);
+            path.push((node, base));
+            let half_size = node.1 / 2;
+            let half = half_size as i32;
+            let delta = position - node.0;
+            let offset = IVec::new(delta.x / half, delta.y / half, delta.z / half);
+            node = (node.0 + offset * half, half_size);
+            state = self.child_state(node, base);
+        }
+        self.generation = next_generation();
+        self.patches
+            .insert((position, 1), if full { State::Full } else { State::Empty });
+        let mut child = (position, 1);
+        for (node, base) in path.into_iter().rev() {
+            if self.is_redundant(child, base) {
+                self.patches.remove(&child);
+            }
+            let states = octants(node)
+                .map(|child| self.child_state(child, base))
+                .collect::<Vec<_>>();
+            let state = if states.iter().all(|s| matches!(s, State::Full)) {
+                State::Full
+            } else if states.iter().all(|s| matches!(s, State::Empty)) {
+                State::Empty
+            } else {
+                State::Branch(base)
+            };
+            if !matches!(state, State::Branch(_)) {
+                self.remove_below(node);
+            }
+            self.patches.insert(node, state);
+            child = node;
+        }
+    }
+
+    /// Whether the patch of a child of a node with the given base has the same
+    /// contents as the base.
+    fn is_redundant(&self, child: (IVec, u64), base: Base) -> bool {
+        let patch = match self.patches.get(&child) {
+            Some(&patch) => patch,
+            None => return false,
+        };
+        match (patch, self.base_state(child, base)) {
+            (State::Full, State::Full) | (State::Empty, State::Empty) => true,
+            // Both are the node of the underlying set at the child, so they
+            // only differ if the branch has edited children.
+            (State::Branch(Base::Node(_)), State::Node(_)) => {
+                octants(child).all(|c| !self.patches.contains_key(&c))
+            }
+            _ => false,
+        }
+    }
+
+    /// Drops the patches of every node within a node.
+    fn remove_below(&mut self, node: (IVec, u64)) {
+        let mut nodes = vec![node];
+        while let Some(node) = nodes.pop() {
+            if node.1 > 1 {
+                for child in octants(node) {
+                    if self.patches.remove(&child).is_some() {
+                        nodes.push(child);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn contains((pos, size): (IVec, u64), position: IVec) -> bool {
+    let size = size as i32;
+    let delta = position - pos;
+    delta.x >= 0
+        && delta.y >= 0
+        && delta.z >= 0
+        && delta.x < size
+        && delta.y < size
+        && delta.z < size
+}
+
+fn base_of(state: State) -> Base {
+    match state {
+        State::Empty => Base::Empty,
+        State::Full => Base::Full,
+        State::Node(node) => Base::Node(node),
+        State::Branch(base) => base,
+    }
+}
+
+/// The eight children of a node.
+fn octants((pos, size): (IVec, u64)) -> impl Iterator<Item = (IVec, u64)> {
+    let half_size = size / 2;
+    (0..8).map(move |i| {
+        (
+            pos + IVec::new(i & 1, (i >> 1) & 1, (i >> 2) & 1) * half_size as i32,
+            half_size,
+        )
+    })
+}
+
+fn node_from((pos, size): (IVec, u64), state: State) -> Option<BBOctreeNode> {
+    match state {
+        State::Empty => None,
+        State::Full => Some(BBOctreeNode::Full(pos, size)),
+        State::Node(node) => Some(BBOctreeNode::Node(node)),
+        State::Branch(_) => Some(BBOctreeNode::Branch(pos, size)),
     }
 }
 
@@ -38,7 +270,9 @@ impl OctreeSetTrait for BBOctreeSet {
     type Node = BBOctreeNode;
     type Iter = std::vec::IntoIter<Self::Node>;
     fn root(&self) -> Self::Node {
-        BBOctreeNode::Node(self.set.root_node().unwrap())
+        // An empty root is given as a branch without any children.
+        node_from(self.root, self.root_state())
+            .unwrap_or(BBOctreeNode::Branch(self.root.0, self.root.1))
     }
     fn children(&self, node: Self::Node) -> Self::Iter {
         let mut out_vec = vec![];
@@ -72,32 +306,148 @@ impl OctreeSetTrait for BBOctreeSet {
                     }
                 }
             }
+            BBOctreeNode::Branch(pos, size) => {
+                if let Some(&State::Branch(base)) = self.patches.get(&(pos, size)) {
+                    for child in octants((pos, size)) {
+                        out_vec.extend(node_from(child, self.child_state(child, base)));
+                    }
+                }
+            }
         }
         out_vec.into_iter()
     }
+    fn generation(&self) -> u64 {
+        self.generation
+    }
 }
+
+impl EditableOctreeSet for BBOctreeSet {
+    fn set(&mut self, position: IVec) {
+        self.edit(position, true);
+    }
+    fn clear(&mut self, position: IVec) {
+        self.edit(position, false);
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum BBOctreeNode {
     Node(OctreeNode),
     Full(IVec, u64),
+    /// A partially full node that has been edited.
+    Branch(IVec, u64),
 }
 impl OctreeNodeTrait for BBOctreeNode {
     fn position(self) -> IVec {
         match self {
             BBOctreeNode::Node(node) => node.octant().minimum().0.into(),
-            BBOctreeNode::Full(pos, _) => pos,
+            BBOctreeNode::Full(pos, _) | BBOctreeNode::Branch(pos, _) => pos,
         }
     }
     fn size(self) -> u64 {
         match self {
             BBOctreeNode::Node(node) => 1 << node.power(),
-            BBOctreeNode::Full(_, size) => size,
+            BBOctreeNode::Full(_, size) | BBOctreeNode::Branch(_, size) => size,
         }
     }
     fn is_full(self) -> bool {
         match self {
             BBOctreeNode::Node(node) => node.is_leaf(),
             BBOctreeNode::Full(..) => true,
+            BBOctreeNode::Branch(..) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::test_util::{cube, solid_storage, Random};
+    use std::collections::HashSet;
+
+    /// All the unit voxels within a set.
+    fn voxels(set: &BBOctreeSet) -> HashSet<(i32, i32, i32)> {
+        let mut out = HashSet::new();
+        let mut nodes = vec![set.root()];
+        while let Some(node) = nodes.pop() {
+            if node.is_unit() && node.is_full() {
+                let pos = node.position();
+                out.insert((pos.x, pos.y, pos.z));
+            }
+            nodes.extend(set.children(node));
+        }
+        out
+    }
+
+    #[test]
+    fn test_edits() {
+        let mut positions = vec![];
+        let mut expected = HashSet::new();
+        for x in -3..5 {
+            for y in 0..3 {
+                for z in 0..2 {
+                    positions.push(IVec::new(x, y, z));
+                    expected.insert((x, y, z));
+                }
+            }
+        }
+        let mut set = BBOctreeSet::from_chunk_storage(&solid_storage(positions));
+        assert_eq!(voxels(&set), expected);
+        let mut random = Random::new(12345);
+        for i in 0..500 {
+            let pos = random.position(24);
+            if i % 3 == 0 {
+                set.clear(pos);
+                expected.remove(&(pos.x, pos.y, pos.z));
+            } else {
+                set.set(pos);
+                expected.insert((pos.x, pos.y, pos.z));
+            }
+            assert_eq!(voxels(&set), expected);
+        }
+    }
+
+    #[test]
+    fn test_fill() {
+        let mut set = BBOctreeSet::from_chunk_storage(&solid_storage(vec![IVec::zero()]));
+        for position in cube(IVec::zero(), 4) {
+            set.set(position);
+        }
+        assert!(set.root().is_full());
+        assert_eq!(set.root().size(), 4);
+        set.clear(IVec::new(1, 2, 3));
+        assert!(!set.root().is_full());
+        assert_eq!(voxels(&set).len(), 63);
+    }
+
+    #[test]
+    fn test_patches_merged() {
+        let mut set = BBOctreeSet::from_chunk_storage(&solid_storage(vec![IVec::zero()]));
+        let original: HashSet<_> = vec![(0, 0, 0)].into_iter().collect();
+        let mut expected = original.clone();
+        let mut random = Random::new(54321);
+        for i in 0..5000 {
+            let pos = random.position(40);
+            if i % 2 == 0 {
+                set.clear(pos);
+                expected.remove(&(pos.x, pos.y, pos.z));
+            } else {
+                set.set(pos);
+                expected.insert((pos.x, pos.y, pos.z));
+            }
+            // Only the nodes containing voxels that differ from the underlying
+            // set are patched.
+            let levels = set.root.1.trailing_zeros() as usize + 1;
+            let edited = expected.symmetric_difference(&original).count();
+            assert!(set.patches.len() <= levels * (edited + 1));
+        }
+        assert_eq!(voxels(&set), expected);
+        for &(x, y, z) in expected.iter() {
+            set.clear(IVec::new(x, y, z));
         }
+        set.set(IVec::zero());
+        assert_eq!(voxels(&set), original);
+        // What is left are the nodes that the root grew through.
+        assert!(set.patches.len() <= set.root.1.trailing_zeros() as usize + 1);
     }
 }
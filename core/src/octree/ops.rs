@@ -0,0 +1,239 @@
+//! Boolean operations between octree sets that share a frame.
+use crate::geometry::IVec;
+use crate::octree::linear_octree_set::{octant_offset, LinearOctreeSet};
+use crate::octree::{OctreeNode, OctreeSet};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Operation {
+    /// The voxels in either set.
+    Union,
+    /// The voxels in both sets.
+    Intersection,
+    /// The voxels in the first set but not the second.
+    Difference,
+}
+
+/// How much of a cell a set covers.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Cover {
+    Empty,
+    Full,
+    Partial,
+}
+
+impl Operation {
+    fn cover(self, a: Cover, b: Cover) -> Cover {
+        use Cover::*;
+        match self {
+            Operation::Union => match (a, b) {
+                (Full, _) | (_, Full) => Full,
+                (Empty, Empty) => Empty,
+                _ => Partial,
+            },
+            Operation::Intersection => match (a, b) {
+                (Empty, _) | (_, Empty) => Empty,
+                (Full, Full) => Full,
+                _ => Partial,
+            },
+            Operation::Difference => match (a, b) {
+                (Empty, _) | (_, Full) => Empty,
+                (Full, Empty) => Full,
+                _ => Partial,
+            },
+        }
+    }
+}
+
+pub fn union<A: OctreeSet, B: OctreeSet>(a: &A, b: &B) -> LinearOctreeSet {
+    combine(a, b, Operation::Union)
+}
+
+pub fn intersection<A: OctreeSet, B: OctreeSet>(a: &A, b: &B) -> LinearOctreeSet {
+    combine(a, b, Operation::Intersection)
+}
+
+pub fn difference<A: OctreeSet, B: OctreeSet>(a: &A, b: &B) -> LinearOctreeSet {
+    combine(a, b, Operation::Difference)
+}
+
+/// Whether two cubes, given by their bottom corner and size, overlap.
+fn intersects(a: (IVec, u64), b: (IVec, u64)) -> bool {
+    let overlaps = |a: i32, a_size: u64, b: i32, b_size: u64| {
+        (a as i64) < b as i64 + b_size as i64 && (b as i64) < a as i64 + a_size as i64
+    };
+    overlaps(a.0.x, a.1, b.0.x, b.1)
+        && overlaps(a.0.y, a.1, b.0.y, b.1)
+        && overlaps(a.0.z, a.1, b.0.z, b.1)
+}
+
+/// Whether the first cube contains the second.
+fn contains(a: (IVec, u64), b: (IVec, u64)) -> bool {
+    let within = |a: i32, a_size: u64, b: i32, b_size: u64| {
+        a <= b && b as i64 + b_size as i64 <= a as i64 + a_size as i64
+    };
+    within(a.0.x, a.1, b.0.x, b.1)
+        && within(a.0.y, a.1, b.0.y, b.1)
+        && within(a.0.z, a.1, b.0.z, b.1)
+}
+
+/// Finds how much of a cell a set covers, given the nodes of the set that may
+/// intersect it. Nodes containing the cell are only descended into when they
+/// are partially full, so full nodes are never split up.
+/// Also returns the nodes that intersect the cell, for classifying its
+/// children.
+fn classify<S: OctreeSet>(
+    set: &S,
+    mut nodes: Vec<S::Node>,
+    cell: (IVec, u64),
+) -> (Cover, Vec<S::Node>) {
+    let mut intersecting = vec![];
+    while let Some(node) = nodes.pop() {
+        let cube = (node.position(), node.size());
+        if !intersects(cube, cell) {
+            continue;
+        }
+        if contains(cube, cell) {
+            if node.is_full() {
+                return (Cover::Full, vec![node]);
+            }
+            nodes.extend(set.children(node));
+        } else {
+            intersecting.push(node);
+        }
+    }
+    if intersecting.is_empty() {
+        (Cover::Empty, intersecting)
+    } else {
+        (Cover::Partial, intersecting)
+    }
+}
+
+/// Combines two sets into a new one, keeping whole full nodes wherever the
+/// result allows. The result is aligned to the root of `a`, doubled as needed
+/// to contain `b` for a union.
+pub fn combine<A: OctreeSet, B: OctreeSet>(a: &A, b: &B, operation: Operation) -> LinearOctreeSet {
+    let (a_root, b_root) = (a.root(), b.root());
+    let (mut origin, mut size) = (a_root.position(), a_root.size());
+    if operation == Operation::Union {
+        let b_min = b_root.position();
+        while !contains((origin, size), (b_min, b_root.size())) {
+            let s = size as i32;
+            let grow = |p: i32, min: i32| if min < p { p - s } else { p };
+            origin = IVec::new(
+                grow(origin.x, b_min.x),
+                grow(origin.y, b_min.y),
+                grow(origin.z, b_min.z),
+            );
+            size *= 2;
+        }
+    }
+    let depth = size.trailing_zeros() as u8;
+
+    let mut full_nodes = vec![];
+    // The cells left to classify, with their level, morton code, and the
+    // nodes of each set that may intersect them.
+    let mut cells = vec![(origin, 0u8, 0u64, vec![a_root], vec![b_root])];
+    while let Some((position, level, code, a_nodes, b_nodes)) = cells.pop() {
+        let cell = (position, size >> level);
+        let (a_cover, a_nodes) = classify(a, a_nodes, cell);
+        let (b_cover, b_nodes) = classify(b, b_nodes, cell);
+        match operation.cover(a_cover, b_cover) {
+            Cover::Empty => {}
+            Cover::Full => full_nodes.push((level, code)),
+            Cover::Partial => {
+                // Unit cells are always either empty or full.
+                debug_assert!(level < depth);
+                let half_size = (cell.1 / 2) as i32;
+                for octant in 0..8 {
+                    cells.push((
+                        position + octant_offset(octant) * half_size,
+                        level + 1,
+                        code << 3 | octant as u64,
+                        a_nodes.clone(),
+                        b_nodes.clone(),
+                    ));
+                }
+            }
+        }
+    }
+    LinearOctreeSet::from_full_nodes(origin, depth, full_nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::octree_set::BBOctreeSet;
+    use crate::octree::test_util::{cube, solid_storage, Random};
+    use std::collections::HashSet;
+
+    fn voxels<S: OctreeSet>(set: &S) -> HashSet<(i32, i32, i32)> {
+        let mut out = HashSet::new();
+        set.for_each_full(|node| {
+            let pos = node.position();
+            let size = node.size() as i32;
+            for x in 0..size {
+                for y in 0..size {
+                    for z in 0..size {
+                        out.insert((pos.x + x, pos.y + y, pos.z + z));
+                    }
+                }
+            }
+        });
+        out
+    }
+
+    /// Random voxels within a cube.
+    fn random_positions(seed: u64, min: i32, max: i32) -> Vec<IVec> {
+        let mut random = Random::new(seed);
+        let corner = IVec::new(min, min, min);
+        cube(corner, max - min)
+            .into_iter()
+            .filter(|_| random.next() % 3 != 0)
+            .collect()
+    }
+
+    fn block(min: IVec, size: i32) -> LinearOctreeSet {
+        LinearOctreeSet::from_positions(&cube(min, size))
+    }
+
+    #[test]
+    fn test_random_operations() {
+        let a = LinearOctreeSet::from_positions(&random_positions(1, -6, 5));
+        let b = BBOctreeSet::from_chunk_storage(&solid_storage(random_positions(2, -2, 9)));
+        let (a_voxels, b_voxels) = (voxels(&a), voxels(&b));
+        assert_eq!(
+            voxels(&union(&a, &b)),
+            a_voxels.union(&b_voxels).copied().collect()
+        );
+        assert_eq!(
+            voxels(&intersection(&a, &b)),
+            a_voxels.intersection(&b_voxels).copied().collect()
+        );
+        assert_eq!(
+            voxels(&difference(&a, &b)),
+            a_voxels.difference(&b_voxels).copied().collect()
+        );
+        assert_eq!(
+            voxels(&difference(&b, &a)),
+            b_voxels.difference(&a_voxels).copied().collect()
+        );
+    }
+
+    #[test]
+    fn test_keeps_full_nodes() {
+        let a = block(IVec::zero(), 4);
+        let b = block(IVec::new(4, 0, 0), 4);
+        let mut full_nodes = vec![];
+        union(&a, &b).for_each_full(|node| full_nodes.push((node.position(), node.size())));
+        full_nodes.sort_by_key(|(pos, _)| pos.x);
+        assert_eq!(full_nodes, vec![(IVec::zero(), 4), (IVec::new(4, 0, 0), 4)]);
+
+        // Carving a corner out of a block keeps the untouched octants whole.
+        let carved = difference(&block(IVec::zero(), 8), &block(IVec::new(-2, -2, -2), 4));
+        let mut sizes = vec![];
+        carved.for_each_full(|node| sizes.push(node.size()));
+        assert_eq!(sizes.iter().filter(|&&size| size == 4).count(), 7);
+        assert_eq!(voxels(&carved).len(), 512 - 8);
+        assert!(voxels(&intersection(&a, &b)).is_empty());
+    }
+}
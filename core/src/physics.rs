@@ -7,16 +7,44 @@ use std::ops::Mul;
 use std::ops::Neg;
 use ultraviolet::Bivec3;
 
+pub mod contact;
+pub mod damping;
+pub mod deterministic;
+pub mod diagnostics;
+pub mod edit;
+pub mod explosion;
+pub mod field;
+pub mod flight;
+pub mod island;
+pub mod joint;
+pub mod mass_properties;
+pub mod sleep;
+pub mod snapshot;
+pub mod solver;
+pub mod stepping;
+pub mod structure;
+pub mod thruster;
+use damping::{AngularDamping, DampingSettings, LinearDamping};
+use mass_properties::MassProperties;
+use sleep::Sleeping;
+use stepping::{PreviousPosition, PreviousRotation, Stepping, Substep, Substepping};
+
 pub struct PhysicsPlugin {
     pub timestep: f64,
     // This is the schedule that the physics is added to.
     pub physics_schedule_name: Option<&'static str>,
+    // How often the physics schedule is run, see `Stepping`.
+    pub stepping: Stepping,
+    // How many times the physics schedule is run per step, see `Substepping`.
+    pub substepping: Substepping,
 }
 impl PhysicsPlugin {
     pub fn new(timestep: f64, physics_schedule_name: &'static str) -> Self {
         PhysicsPlugin {
             timestep,
             physics_schedule_name: Some(physics_schedule_name),
+            stepping: Stepping::default(),
+            substepping: Substepping::default(),
         }
     }
 }
@@ -25,6 +53,8 @@ impl Default for PhysicsPlugin {
         PhysicsPlugin {
             timestep: 1.0 / 60.0,
             physics_schedule_name: None,
+            stepping: Stepping::default(),
+            substepping: Substepping::default(),
         }
     }
 }
@@ -39,33 +69,77 @@ impl Plugin for PhysicsPlugin {
             );
             name
         });
-        app.add_resource(Timestep(self.timestep as f32)).stage(
-            schedule_name,
-            |schedule: &mut Schedule| {
+        app.add_resource(Timestep(self.timestep as f32))
+            .add_resource(solver::ContactSettings::default())
+            .add_resource(joint::JointSettings::default())
+            .add_resource(sleep::SleepSettings::default())
+            .add_resource(field::FieldSettings::default())
+            .add_resource(DampingSettings::default())
+            .add_resource(diagnostics::PhysicsDiagnostics::default())
+            .add_resource(self.stepping)
+            .add_resource(self.substepping)
+            .add_resource(Substep::default())
+            .add_resource(stepping::StepClock::default())
+            .add_resource(stepping::Interpolation::default())
+            .add_resource(snapshot::RestoreCount::default())
+            .add_event::<contact::CollisionEvent>()
+            .add_event::<explosion::Explosion>()
+            .stage(schedule_name, |schedule: &mut Schedule| {
                 schedule
+                    .set_run_criteria(stepping::step_criteria.system())
                     .add_stage_before(
                         "collide",
                         "physics-before",
                         SystemStage::parallel()
                             .with_system(linear_update_before.system())
-                            .with_system(angular_update_before.system()),
+                            .with_system(angular_update_before.system())
+                            .with_system(kinematic_update.system())
+                            .with_system(deterministic::fixed_update_before.system()),
                     )
                     .add_stage_after(
                         "collide",
                         "physics-after",
                         SystemStage::parallel()
                             .with_system(linear_update_after.system())
-                            .with_system(angular_update_after.system()),
+                            .with_system(angular_update_after.system())
+                            .with_system(deterministic::fixed_update_after.system()),
+                    )
+                    .add_stage_after(
+                        "physics-after",
+                        "sleep",
+                        SystemStage::serial().with_system(sleep::update_sleep.system()),
+                    )
+                    .add_stage_after(
+                        "sleep",
+                        "diagnostics",
+                        SystemStage::serial().with_system(diagnostics::record_diagnostics.system()),
+                    )
+                    .add_stage_after(
+                        "physics-after",
+                        "structure",
+                        SystemStage::serial().with_system(structure::compute_stress.system()),
+                    )
+                    .add_stage_after(
+                        "collide",
+                        "joints",
+                        SystemStage::serial().with_system(joint::solve_joints.system()),
                     )
                     .add_stage_before(
                         "physics-before",
                         "pre-physics",
                         SystemStage::serial()
+                            .with_system(stepping::choose_substeps.system())
+                            .with_system(stepping::store_previous_state.system())
+                            .with_system(flight::run_flight_computers.system())
+                            .with_system(thruster::apply_thrust.system())
+                            .with_system(sleep::wake_bodies.system())
                             .with_system(recompute_after_changed_body.system())
-                            .with_system(recompute_computed_after_changed.system()),
+                            .with_system(recompute_computed_after_changed.system())
+                            .with_system(deterministic::recompute_fixed_after_changed.system())
+                            .with_system(field::apply_force_fields.system())
+                            .with_system(deterministic::apply_fixed_force_fields.system()),
                     )
-            },
-        );
+            });
     }
 }
 
@@ -76,6 +150,8 @@ impl Plugin for PhysicsPlugin {
 pub struct PhysicsBundle {
     position: Position,
     rotation: Rotation,
+    previous_position: PreviousPosition,
+    previous_rotation: PreviousRotation,
     momentum: Momentum,
     angular_momentum: AngularMomentum,
     force: Force,
@@ -84,11 +160,13 @@ pub struct PhysicsBundle {
     mass: Mass,
     inertia: Inertia,
     changed_bodies: ChangedBodies,
+    sleep_timer: sleep::SleepTimer,
     // Computed fields
     center_of_mass: CenterOfMass,
     inv_mass: InvMass,
     inertia_around_center_of_mass: InertiaAroundCenterOfMass,
     inv_inertia_around_center_of_mass: InvInertiaAroundCenterOfMass,
+    voxel_anchor: VoxelAnchor,
 }
 
 impl PhysicsBundle {
@@ -97,36 +175,141 @@ impl PhysicsBundle {
         rotation: Rot,
         velocity: FVec,
         /* angular_velocity: FVec, */
-        mut masses_fn: impl ForEachMut<(IVec, i64)>,
+        masses_fn: impl ForEachMut<(IVec, i64)>,
     ) -> Self {
-        let mut total_mass = 0;
-        let mut total_mass_position = LVec::zero();
-        let mut total_inertia = LMat::zero();
-        masses_fn.for_each_mut(|(pos, mass)| {
-            total_mass_position += LVec::from(pos) * mass;
-            total_mass += mass;
-            total_inertia += voxel_inertia(pos, mass);
-        });
+        PhysicsBundle::from_mass_properties(
+            position,
+            rotation,
+            velocity,
+            MassProperties::from_voxels(masses_fn),
+        )
+    }
+
+    /// Creates a body from precomputed mass properties, such as those from
+    /// `MassProperties::from_octree`.
+    /// `position` is the world position of the origin of the voxels.
+    pub fn from_mass_properties(
+        position: FVec,
+        rotation: Rot,
+        velocity: FVec,
+        properties: MassProperties,
+    ) -> Self {
+        let com = properties.center_of_mass();
+        let (inv_mass, iacom, iiacom) =
+            properties_around_center_of_mass(com, properties.mass, properties.inertia, false);
+        let origin = position;
+        let position = origin + rotation * com;
         PhysicsBundle {
             position: Position(position),
             rotation: Rotation(rotation),
-            momentum: Momentum((total_mass as f32) * velocity),
+            previous_position: PreviousPosition(position),
+            previous_rotation: PreviousRotation(rotation),
+            momentum: Momentum((properties.mass as f32) * velocity),
             angular_momentum: AngularMomentum(FVec::zero()),
             force: Force(FVec::zero()),
             torque: Torque(FVec::zero()),
-            total_mass_position: TotalMassPosition(total_mass_position),
-            mass: Mass(total_mass),
-            inertia: Inertia(total_inertia),
+            total_mass_position: TotalMassPosition(properties.total_mass_position),
+            mass: Mass(properties.mass),
+            inertia: Inertia(properties.inertia),
             changed_bodies: ChangedBodies(vec![]),
-            // These are all computed in the pre-physics stage.
-            // As such, all of them are default values.
-            center_of_mass: CenterOfMass(FVec::zero()),
+            sleep_timer: sleep::SleepTimer(0.0),
+            // These are recomputed in the pre-physics stage whenever the mass
+            // changes.
+            center_of_mass: CenterOfMass(com),
+            inv_mass,
+            inertia_around_center_of_mass: iacom,
+            inv_inertia_around_center_of_mass: iiacom,
+            voxel_anchor: VoxelAnchor {
+                origin,
+                position,
+                rotation,
+            },
+        }
+    }
+}
+
+fn center_of_mass(mut masses_fn: impl ForEachMut<(IVec, i64)>) -> FVec {
+    let mut total_mass = 0;
+    let mut total_mass_position = LVec::zero();
+    masses_fn.for_each_mut(|(pos, mass)| {
+        total_mass_position += LVec::from(pos) * mass;
+        total_mass += mass;
+    });
+    if total_mass == 0 {
+        FVec::zero()
+    } else {
+        total_mass_position.as_f32() / (total_mass as f32)
+    }
+}
+
+#[derive(Bundle)]
+/// A bundle for bodies that never move, such as asteroids and space stations.
+/// Collisions treat these as infinitely heavy. Only what is needed to collide
+/// with them is stored, so that they are cheap to have in large numbers.
+pub struct StaticBundle {
+    position: Position,
+    rotation: Rotation,
+    center_of_mass: CenterOfMass,
+    inv_mass: InvMass,
+    inv_inertia_around_center_of_mass: InvInertiaAroundCenterOfMass,
+    kind: Static,
+}
+
+impl StaticBundle {
+    /// `position` is the world position of the origin of the voxels.
+    pub fn new(position: FVec, rotation: Rot, masses_fn: impl ForEachMut<(IVec, i64)>) -> Self {
+        let com = center_of_mass(masses_fn);
+        StaticBundle {
+            position: Position(position + rotation * com),
+            rotation: Rotation(rotation),
+            center_of_mass: CenterOfMass(com),
             inv_mass: InvMass(0.0),
-            inertia_around_center_of_mass: InertiaAroundCenterOfMass(FMat::identity()),
-            inv_inertia_around_center_of_mass: InvInertiaAroundCenterOfMass(FMat::identity()),
+            inv_inertia_around_center_of_mass: InvInertiaAroundCenterOfMass(FMat::from_scale(0.0)),
+            kind: Static,
         }
     }
 }
+
+#[derive(Bundle)]
+/// A bundle for bodies that move with a set velocity, which is not affected by
+/// forces or collisions. Collisions treat these as infinitely heavy.
+pub struct KinematicBundle {
+    position: Position,
+    rotation: Rotation,
+    previous_position: PreviousPosition,
+    previous_rotation: PreviousRotation,
+    center_of_mass: CenterOfMass,
+    inv_mass: InvMass,
+    inv_inertia_around_center_of_mass: InvInertiaAroundCenterOfMass,
+    kind: Kinematic,
+}
+
+impl KinematicBundle {
+    /// `position` is the world position of the origin of the voxels.
+    pub fn new(
+        position: FVec,
+        rotation: Rot,
+        velocity: FVec,
+        angular_velocity: FVec,
+        masses_fn: impl ForEachMut<(IVec, i64)>,
+    ) -> Self {
+        let com = center_of_mass(masses_fn);
+        KinematicBundle {
+            position: Position(position + rotation * com),
+            rotation: Rotation(rotation),
+            previous_position: PreviousPosition(position + rotation * com),
+            previous_rotation: PreviousRotation(rotation),
+            center_of_mass: CenterOfMass(com),
+            inv_mass: InvMass(0.0),
+            inv_inertia_around_center_of_mass: InvInertiaAroundCenterOfMass(FMat::from_scale(0.0)),
+            kind: Kinematic {
+                velocity,
+                angular_velocity,
+            },
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Default, Debug)]
 pub struct Timestep(pub f32);
 
@@ -165,6 +348,32 @@ pub struct InvMass(pub f32);
 pub struct InertiaAroundCenterOfMass(pub FMat);
 #[derive(Copy, Clone, PartialEq, Default, Debug)]
 pub struct InvInertiaAroundCenterOfMass(pub FMat);
+/// The world position of the origin of an object's voxels, along with the
+/// position and rotation that it was found at.
+/// When the center of mass of an object moves, its position is found again
+/// from this rather than shifted, so that edits to an object that has not
+/// moved since keep its voxels exactly where they are.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct VoxelAnchor {
+    pub origin: FVec,
+    pub position: FVec,
+    pub rotation: Rot,
+}
+
+/// Marks a body that never moves.
+/// Static bodies have zero inverse mass and inertia, and are skipped by the
+/// integrators.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct Static;
+/// Marks a body that moves with a set velocity, ignoring forces.
+/// Kinematic bodies have zero inverse mass and inertia, and are skipped by the
+/// integrators.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct Kinematic {
+    pub velocity: FVec,
+    /// The angular velocity in world space.
+    pub angular_velocity: FVec,
+}
 
 /// A struct representing a change in mass of an object.
 #[derive(Clone, PartialEq, Default, Debug)]
@@ -199,32 +408,67 @@ fn voxel_inertia(pos: IVec, mass: i64) -> LMat {
     LMat::identity() * mass + inertia_of_position(LVec::from(pos).into(), mass).into()
 }
 
+/// Applies the mass changes queued in the `ChangedBodies` of every body.
+/// Every body is checked, rather than only those changed this frame, as the
+/// changes may have been queued in a frame that ran no physics steps.
 fn recompute_after_changed_body(
     pool: Res<ComputeTaskPool>,
-    mut query: Query<
-        (
-            &mut ChangedBodies,
-            &mut TotalMassPosition,
-            &mut Mass,
-            &mut Inertia,
-        ),
-        Mutated<ChangedBodies>,
-    >,
+    mut query: Query<(
+        &mut ChangedBodies,
+        &mut TotalMassPosition,
+        &mut Mass,
+        &mut Inertia,
+    )>,
 ) {
     query
         .par_iter_mut(64)
         .for_each(&pool.0, |(mut cb, mut tmp, mut m, mut i)| {
+            if cb.0.is_empty() {
+                return;
+            }
             for (pos, mass) in std::mem::replace(&mut cb.0, vec![]).into_iter() {
-                let pos = LVec::from(pos);
                 m.0 += mass;
-                tmp.0 += pos * mass;
-                i.0 += inertia_of_position(pos.into(), mass).into();
+                tmp.0 += LVec::from(pos) * mass;
+                i.0 += voxel_inertia(pos, mass);
             }
         })
 }
 
-// TODO: AT THE START, com.0 IS ZERO. MAKE IT SO THAT THE POSITION IS NOT
-// CHANGED DUE TO THIS.
+/// The inverse mass, inertia around the center of mass, and inverse inertia
+/// of a body with its center of mass at `com`. Immovable bodies, and bodies
+/// without mass, have no inverse mass or inertia.
+fn properties_around_center_of_mass(
+    com: FVec,
+    mass: i64,
+    inertia: LMat,
+    immovable: bool,
+) -> (
+    InvMass,
+    InertiaAroundCenterOfMass,
+    InvInertiaAroundCenterOfMass,
+) {
+    // The parallel axis theorem, moving the inertia from the origin to the
+    // center of mass.
+    let iacom = inertia.as_f32() - inertia_of_position(*com.as_array(), mass as f32).into();
+    if immovable || mass == 0 {
+        (
+            InvMass(0.0),
+            InertiaAroundCenterOfMass(iacom),
+            InvInertiaAroundCenterOfMass(FMat::from_scale(0.0)),
+        )
+    } else {
+        (
+            InvMass(1.0 / (mass as f32)),
+            InertiaAroundCenterOfMass(iacom),
+            InvInertiaAroundCenterOfMass(iacom.inversed()),
+        )
+    }
+}
+
+/// Recomputes the center of mass and inertia of every body whose mass has
+/// changed. The body is moved with its center of mass, so that its voxels stay
+/// where they are in the world. Its position is found from its `VoxelAnchor`,
+/// so that rounding does not add up over many edits.
 #[allow(clippy::type_complexity)]
 fn recompute_computed_after_changed(
     pool: Res<ComputeTaskPool>,
@@ -239,92 +483,212 @@ fn recompute_computed_after_changed(
             &mut InvMass,
             &mut InertiaAroundCenterOfMass,
             &mut InvInertiaAroundCenterOfMass,
+            Option<&mut VoxelAnchor>,
+            Option<&Static>,
+            Option<&Kinematic>,
         ),
         Or<(Changed<TotalMassPosition>, Changed<Mass>, Changed<Inertia>)>,
     >,
 ) {
     query.par_iter_mut(128).for_each(
         &pool.0,
-        |(tmp, m, i, r, mut p, mut com, mut im, mut iacom, mut iiacom)| {
-            let old_com = com.0;
-            com.0 = tmp.0.as_f32() / (m.0 as f32);
-            // TODO: Check these parts.
-            let del_com = com.0 - old_com;
-            let rot_del_com = r.0.reversed() * del_com;
-            p.0 += rot_del_com;
-            im.0 = 1.0 / (m.0 as f32);
-            iacom.0 = i.0.as_f32() + inertia_of_position(*com.0.as_array(), m.0 as f32).into();
-            iiacom.0 = iacom.0.inversed();
+        |(tmp, m, i, r, mut p, mut com, mut im, mut iacom, mut iiacom, mut anchor, s, k)| {
+            // A body without mass keeps its center of mass where it was.
+            if m.0 != 0 {
+                let new_com = tmp.0.as_f32() / (m.0 as f32);
+                if new_com != com.0 {
+                    // The anchor is only found again once the body has moved.
+                    let origin = match &anchor {
+                        Some(anchor) if anchor.position == p.0 && anchor.rotation == r.0 => {
+                            anchor.origin
+                        }
+                        _ => p.0 - r.0 * com.0,
+                    };
+                    p.0 = origin + r.0 * new_com;
+                    com.0 = new_com;
+                    if let Some(anchor) = &mut anchor {
+                        **anchor = VoxelAnchor {
+                            origin,
+                            position: p.0,
+                            rotation: r.0,
+                        };
+                    }
+                }
+            }
+            let (new_im, new_iacom, new_iiacom) =
+                properties_around_center_of_mass(com.0, m.0, i.0, s.is_some() || k.is_some());
+            *im = new_im;
+            *iacom = new_iacom;
+            *iiacom = new_iiacom;
         },
     );
 }
 
+/// Damping is applied over the whole substep here, after the first half of the
+/// force.
+#[allow(clippy::type_complexity)]
 fn linear_update_before(
     timestep: Res<Timestep>,
+    substep: Res<Substep>,
+    damping: Res<DampingSettings>,
     pool: Res<ComputeTaskPool>,
-    mut query: Query<(&InvMass, &mut Force, &mut Momentum, &mut Position)>,
+    mut query: Query<
+        (
+            &InvMass,
+            &Force,
+            Option<&LinearDamping>,
+            &mut Momentum,
+            &mut Position,
+        ),
+        (Without<Static>, Without<Kinematic>, Without<Sleeping>),
+    >,
 ) {
-    let timestep = timestep.0;
+    let timestep = substep.length(&timestep);
+    let default_damping = damping.linear;
     query
         .par_iter_mut(128)
-        .for_each(&pool.0, |(im, mut f, mut m, mut p)| {
+        .for_each(&pool.0, |(im, f, ld, mut m, mut p)| {
+            let rate = ld.map_or(default_damping, |ld| ld.0);
+            let (decay, average) = damping::damping_factors(rate, timestep);
             m.0 += 0.5 * f.0 * timestep;
-            p.0 += m.0 * im.0 * timestep;
-            f.0 = FVec::zero();
+            p.0 += m.0 * im.0 * (timestep * average);
+            m.0 *= decay;
             debug_assert!(!f32::is_nan(p.0.x) && !f32::is_nan(p.0.y) && !f32::is_nan(p.0.z));
         });
 }
 
+#[allow(clippy::type_complexity)]
 fn angular_update_before(
     timestep: Res<Timestep>,
+    substep: Res<Substep>,
+    damping: Res<DampingSettings>,
     pool: Res<ComputeTaskPool>,
-    mut query: Query<(
-        &InvInertiaAroundCenterOfMass,
-        &mut Torque,
-        &mut AngularMomentum,
-        &mut Rotation,
-    )>,
+    mut query: Query<
+        (
+            &InvInertiaAroundCenterOfMass,
+            &Torque,
+            Option<&AngularDamping>,
+            &mut AngularMomentum,
+            &mut Rotation,
+        ),
+        (Without<Static>, Without<Kinematic>, Without<Sleeping>),
+    >,
 ) {
-    let timestep = timestep.0;
+    let timestep = substep.length(&timestep);
+    let default_damping = damping.angular;
     query
         .par_iter_mut(128)
-        .for_each(&pool.0, |(iiacom, mut t, mut am, mut r)| {
+        .for_each(&pool.0, |(iiacom, t, ad, mut am, mut r)| {
+            let rate = ad.map_or(default_damping, |ad| ad.0);
+            let (decay, average) = damping::damping_factors(rate, timestep);
             am.0 += 0.5 * t.0 * timestep;
-            let rot_mat = r.0.into_matrix();
-            let w = rot_mat * iiacom.0 * rot_mat.inversed() * am.0 * timestep;
-            if w == FVec::zero() {
-                return;
-            }
-            let theta = w.mag();
-            let half = theta / 2.0;
-            let vec = w.normalized() * half.sin();
-            // TODO: TEST THIS EQUATION AND MAKE SURE IT WORKS
-            r.0 = Rot::new(half.cos(), Bivec3::new(vec.z, vec.y, vec.x)) * r.0;
-            t.0 = FVec::zero();
+            r.0 = rotation_step(r.0, am.0 * average, iiacom, timestep);
+            am.0 *= decay;
             debug_assert!(!f32::is_nan(r.0.s));
         });
 }
 
+/// Rotates a rotation by a world space rotation vector, whose magnitude is the
+/// angle. The rotation is right handed around the vector.
+fn integrate_rotation(rotation: Rot, w: FVec) -> Rot {
+    let theta = w.mag();
+    if theta == 0.0 {
+        return rotation;
+    }
+    let mut rotation =
+        Rot::from_angle_plane(theta, Bivec3::from_normalized_axis(w / theta)) * rotation;
+    rotation.normalize();
+    rotation
+}
+
+/// The rotation vector of a rotation, whose magnitude is the angle.
+/// This undoes `integrate_rotation` from the identity, taking the shorter way
+/// around.
+fn rotation_vector(rotation: Rot) -> FVec {
+    let rotation = if rotation.s < 0.0 {
+        rotation * -1.0
+    } else {
+        rotation
+    };
+    let axis = FVec::new(-rotation.bv.yz, rotation.bv.xz, -rotation.bv.xy);
+    let sin = axis.mag();
+    if sin == 0.0 {
+        return FVec::zero();
+    }
+    axis * (2.0 * sin.atan2(rotation.s) / sin)
+}
+
+/// The rotation of a body after a timestep with a constant angular momentum.
+/// As the angular velocity changes with the rotation, it is taken at the
+/// midpoint of the step.
+fn rotation_step(
+    rotation: Rot,
+    angular_momentum: FVec,
+    iiacom: &InvInertiaAroundCenterOfMass,
+    timestep: f32,
+) -> Rot {
+    let angular_velocity =
+        |rotation: Rot| world_inv_inertia(&Rotation(rotation), iiacom) * angular_momentum;
+    let midpoint = integrate_rotation(rotation, angular_velocity(rotation) * (timestep / 2.0));
+    integrate_rotation(rotation, angular_velocity(midpoint) * timestep)
+}
+
+fn kinematic_update(
+    timestep: Res<Timestep>,
+    substep: Res<Substep>,
+    pool: Res<ComputeTaskPool>,
+    mut query: Query<(&Kinematic, &mut Position, &mut Rotation)>,
+) {
+    let timestep = substep.length(&timestep);
+    query
+        .par_iter_mut(128)
+        .for_each(&pool.0, |(k, mut p, mut r)| {
+            p.0 += k.velocity * timestep;
+            r.0 = integrate_rotation(r.0, k.angular_velocity * timestep);
+        });
+}
+
+/// The forces are cleared here, after being used for both halves of every
+/// substep of the step.
 fn linear_update_after(
     timestep: Res<Timestep>,
+    substep: Res<Substep>,
     pool: Res<ComputeTaskPool>,
-    mut query: Query<(&Force, &mut Momentum)>,
+    mut query: Query<
+        (&mut Force, &mut Momentum),
+        (Without<Static>, Without<Kinematic>, Without<Sleeping>),
+    >,
 ) {
-    let timestep = timestep.0;
-    query.par_iter_mut(128).for_each(&pool.0, |(f, mut m)| {
+    let timestep = substep.length(&timestep);
+    let last = substep.is_last();
+    query.par_iter_mut(128).for_each(&pool.0, |(mut f, mut m)| {
         m.0 += 0.5 * f.0 * timestep;
+        if last {
+            f.0 = FVec::zero();
+        }
     });
 }
 
+/// The torques are cleared here, like the forces.
 fn angular_update_after(
     timestep: Res<Timestep>,
+    substep: Res<Substep>,
     pool: Res<ComputeTaskPool>,
-    mut query: Query<(&Torque, &mut AngularMomentum)>,
+    mut query: Query<
+        (&mut Torque, &mut AngularMomentum),
+        (Without<Static>, Without<Kinematic>, Without<Sleeping>),
+    >,
 ) {
-    let timestep = timestep.0;
-    query.par_iter_mut(128).for_each(&pool.0, |(t, mut am)| {
-        am.0 += 0.5 * t.0 * timestep;
-    });
+    let timestep = substep.length(&timestep);
+    let last = substep.is_last();
+    query
+        .par_iter_mut(128)
+        .for_each(&pool.0, |(mut t, mut am)| {
+            am.0 += 0.5 * t.0 * timestep;
+            if last {
+                t.0 = FVec::zero();
+            }
+        });
 }
 
 pub fn apply_force_bundle(
@@ -338,47 +702,210 @@ pub fn apply_force_bundle(
 pub fn apply_force(force: FVec, position: FVec, ftp: (&mut Force, &mut Torque, &Position)) {
     let delta = position - ftp.2 .0;
     ftp.0 .0 += force;
-    ftp.1 .0 += force.cross(delta);
+    ftp.1 .0 += delta.cross(force);
 }
 
-pub fn apply_collision(
-    a: (&mut Force, &mut Torque, &Position, &Rotation, &Mass),
-    b: (&mut Force, &mut Torque, &Position, &Rotation, &Mass),
-    a_collide_pos: FVec,
-    b_collide_pos: FVec,
-    penetration: FVec,
-) {
-    let min_mass = a.4 .0.min(b.4 .0) as f32;
-    let force = penetration * min_mass * 0.5;
-    apply_force(force, a.2 .0 + a.3 .0 * a_collide_pos, (a.0, a.1, a.2));
-    apply_force(-force, b.2 .0 + b.3 .0 * b_collide_pos, (b.0, b.1, b.2));
+/// The point of a voxel in its object's voxel space.
+/// Each voxel is the unit cube centered on its position, so this is both the
+/// center of its collider and rendered cube and where its mass is counted.
+/// Everything that acts on a single voxel, such as contacts, thrusters and
+/// explosions, acts at this point.
+pub fn voxel_point(voxel: IVec) -> FVec {
+    FVec::new(voxel.x as f32, voxel.y as f32, voxel.z as f32)
+}
+
+/// The world position of the origin of an object's voxel space.
+pub fn voxel_origin(p: &Position, r: &Rotation, com: &CenterOfMass) -> FVec {
+    p.0 - r.0 * com.0
+}
+
+/// Converts a position in an object's voxel space into world space.
+pub fn voxel_to_world(position: FVec, p: &Position, r: &Rotation, com: &CenterOfMass) -> FVec {
+    p.0 + r.0 * (position - com.0)
+}
+
+/// The inverse inertia of an object around its center of mass, in world space.
+pub fn world_inv_inertia(r: &Rotation, iiacom: &InvInertiaAroundCenterOfMass) -> FMat {
+    let rot_mat = r.0.into_matrix();
+    rot_mat * iiacom.0 * rot_mat.transposed()
+}
+
+/// The world space velocity of a point attached to an object.
+pub fn point_velocity(
+    point: FVec,
+    p: &Position,
+    r: &Rotation,
+    m: &Momentum,
+    am: &AngularMomentum,
+    im: &InvMass,
+    iiacom: &InvInertiaAroundCenterOfMass,
+) -> FVec {
+    let angular_velocity = world_inv_inertia(r, iiacom) * am.0;
+    m.0 * im.0 + angular_velocity.cross(point - p.0)
 }
 
 // Inertia computations taken from http://www.kwon3d.com/theory/moi/triten.html
 // Other physics from both         http://www.cs.cmu.edu/~baraff/sigcourse/notesd1.pdf
 // and                             http://developer.nvidia.com/gpugems/gpugems3/part-v-physics-simulation/chapter-29-real-time-rigid-body-simulation-gpus
 
+/// Helpers shared by the tests of the physics modules.
 #[cfg(test)]
-mod tests {
+pub(crate) mod test_util {
+    use super::contact::octree_collide;
+    use super::mass_properties::MassVoxel;
+    use super::structure::StructuralVoxel;
+    use super::thruster::{Thruster, ThrusterVoxel};
     use super::*;
+    use crate::for_each::ForEach;
+    use crate::octree::linear_octree_set::LinearOctreeSet;
+    use crate::octree::octree_set::BBOctreeSet;
+    use crate::octree::OctreeSet;
+    use crate::storage::chunk_map::ChunkStorage;
+    use building_blocks::prelude::IsEmpty;
 
-    #[derive(PartialEq, Copy, Clone, Default, Debug)]
-    struct CubeForce(FVec);
-    // Relative to the center of mass of the cube, but not rotated.
-    #[derive(PartialEq, Copy, Clone, Default, Debug)]
-    struct CubeForcePos(FVec);
+    /// A voxel of a ship.
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    pub enum TestVoxel {
+        Empty,
+        Hull,
+        /// Three times as heavy as hull.
+        Armor,
+        /// A thruster that pushes along x.
+        Engine,
+        /// A weak link.
+        Neck,
+    }
+    impl MassVoxel for TestVoxel {
+        fn mass(&self) -> i64 {
+            match self {
+                TestVoxel::Empty => 0,
+                TestVoxel::Armor => 3,
+                _ => 1,
+            }
+        }
+    }
+    impl IsEmpty for TestVoxel {
+        fn is_empty(&self) -> bool {
+            *self == TestVoxel::Empty
+        }
+    }
+    impl StructuralVoxel for TestVoxel {
+        fn strength(&self) -> f32 {
+            match self {
+                TestVoxel::Empty => 0.0,
+                TestVoxel::Neck => 50.0,
+                _ => 1000.0,
+            }
+        }
+    }
+    impl ThrusterVoxel for TestVoxel {
+        fn thruster(&self) -> Option<Thruster> {
+            match self {
+                TestVoxel::Engine => Some(Thruster {
+                    max_thrust: 1.0,
+                    direction: FVec::unit_x(),
+                }),
+                _ => None,
+            }
+        }
+    }
 
-    fn init_app(timestep: f64) -> AppBuilder {
+    /// An app with the physics plugin, which runs one step of `timestep` every
+    /// update, split into substeps by `substepping`.
+    pub fn init_substepped_app(timestep: f64, substepping: Substepping) -> AppBuilder {
         let mut app = App::build();
         app.add_plugin(bevy::reflect::ReflectPlugin)
             .add_plugin(bevy::core::CorePlugin)
             .add_plugin(PhysicsPlugin {
                 timestep,
-                physics_schedule_name: None,
+                stepping: Stepping::EveryUpdate,
+                substepping,
+                ..Default::default()
             });
         app
     }
 
+    /// An app with the physics plugin, which runs one step of `timestep` every
+    /// update.
+    pub fn init_app(timestep: f64) -> AppBuilder {
+        init_substepped_app(timestep, Substepping::default())
+    }
+
+    /// Collides the bodies with a `Set` collider.
+    pub fn add_octree_collide<Set>(app: &mut AppBuilder)
+    where
+        Set: 'static + OctreeSet + Send + Sync,
+        Set::Node: Send + Sync, {
+        app.stage("physics-schedule", |schedule: &mut Schedule| {
+            schedule.add_system_to_stage("collide", octree_collide::<Set>.system())
+        });
+    }
+
+    /// A body made of voxels with a mass of 1, without a collider.
+    pub fn spawn_body(app: &mut App, position: FVec, velocity: FVec, voxels: &[IVec]) -> Entity {
+        app.world.spawn(PhysicsBundle::new(
+            position,
+            Rot::identity(),
+            velocity,
+            voxels.iter().map(|&v| (v, 1)).collect::<Vec<_>>(),
+        ))
+    }
+
+    /// A body made of voxels with a mass of 1, with a `LinearOctreeSet`
+    /// collider.
+    pub fn spawn_octree_body(
+        app: &mut App,
+        position: FVec,
+        velocity: FVec,
+        voxels: &[IVec],
+    ) -> Entity {
+        let e = spawn_body(app, position, velocity, voxels);
+        app.world
+            .insert_one(e, LinearOctreeSet::from_positions(voxels))
+            .unwrap();
+        e
+    }
+
+    /// A body with the voxels of `storage`, and a `BBOctreeSet` collider.
+    pub fn spawn_storage_body(
+        app: &mut App,
+        position: FVec,
+        rotation: Rot,
+        storage: ChunkStorage<TestVoxel>,
+    ) -> Entity {
+        let physics = PhysicsBundle::new(
+            position,
+            rotation,
+            FVec::zero(),
+            storage.for_each_map(|(position, voxel)| (position, voxel.mass())),
+        );
+        let set = BBOctreeSet::from_chunk_storage(&storage);
+        let e = app.world.spawn(physics);
+        app.world.insert(e, (storage, set)).unwrap();
+        e
+    }
+
+    pub fn assert_close(a: FVec, b: FVec, tolerance: f32) {
+        if (a - b).mag() > tolerance {
+            assert_eq!(a, b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::contact::*;
+    use super::test_util::*;
+    use super::*;
+    use crate::octree::{OctreeNode, OctreeSet};
+    use std::f32::consts::FRAC_PI_2;
+
+    #[derive(PartialEq, Copy, Clone, Default, Debug)]
+    struct CubeForce(FVec);
+    // Relative to the center of mass of the cube, but not rotated.
+    #[derive(PartialEq, Copy, Clone, Default, Debug)]
+    struct CubeForcePos(FVec);
+
     fn apply_force_simple(
         cube_force: Res<CubeForce>,
         cube_force_pos: Res<CubeForcePos>,
@@ -398,12 +925,6 @@ mod tests {
         ));
     }
 
-    fn assert_close(a: FVec, b: FVec) {
-        if (a - b).mag_sq() > 0.001 {
-            assert_eq!(a, b);
-        }
-    }
-
     #[test]
     fn test_simple_movement() {
         let mut app = init_app(1.0);
@@ -415,20 +936,265 @@ mod tests {
         app.update();
         app.update();
         for (pos, momentum) in app.world.query::<(&Position, &Momentum)>() {
-            assert_close(pos.0, FVec::new(2.0, 0.0, 0.0));
-            assert_close(momentum.0, FVec::new(2.0, 0.0, 0.0));
+            assert_close(pos.0, FVec::new(2.0, 0.0, 0.0), 0.03);
+            assert_close(momentum.0, FVec::new(2.0, 0.0, 0.0), 0.03);
         }
         app.resources.get_mut::<CubeForce>().unwrap().0 = FVec::new(-1.0, 0.0, 0.0);
         app.update();
         app.update();
         for (pos, momentum) in app.world.query::<(&Position, &Momentum)>() {
-            assert_close(pos.0, FVec::new(3.0, 0.0, 0.0));
-            assert_close(momentum.0, FVec::new(0.0, 0.0, 0.0));
+            assert_close(pos.0, FVec::new(4.0, 0.0, 0.0), 0.03);
+            assert_close(momentum.0, FVec::new(0.0, 0.0, 0.0), 0.03);
+        }
+    }
+
+    /// An octree containing a single voxel at the origin.
+    struct UnitSet;
+    #[derive(Copy, Clone)]
+    struct UnitNode;
+    impl OctreeNode for UnitNode {
+        fn position(self) -> IVec {
+            IVec::zero()
+        }
+        fn size(self) -> u64 {
+            1
+        }
+        fn is_full(self) -> bool {
+            true
+        }
+    }
+    impl OctreeSet for UnitSet {
+        type Node = UnitNode;
+        type Iter = std::iter::Empty<UnitNode>;
+        fn root(&self) -> Self::Node {
+            UnitNode
+        }
+        fn children(&self, _node: Self::Node) -> Self::Iter {
+            std::iter::empty()
+        }
+        fn generation(&self) -> u64 {
+            0
+        }
+    }
+
+    fn spawn_unit(app: &mut App, position: FVec, velocity: FVec) -> Entity {
+        let e = app.world.spawn(PhysicsBundle::new(
+            position,
+            Rot::identity(),
+            velocity,
+            vec![(IVec::zero(), 1)],
+        ));
+        app.world.insert_one(e, UnitSet).unwrap();
+        e
+    }
+
+    #[test]
+    fn test_collision_events() {
+        let mut app = init_app(0.1);
+        add_octree_collide::<UnitSet>(&mut app);
+        let mut app = app.app;
+        let a = spawn_unit(&mut app, FVec::zero(), FVec::new(1.0, 0.0, 0.0));
+        spawn_unit(&mut app, FVec::new(1.2, 0.0, 0.0), FVec::zero());
+        app.update();
+        let events = app.resources.get::<Events<CollisionEvent>>().unwrap();
+        let collisions = events
+            .get_reader()
+            .iter(&events)
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(collisions.len(), 1);
+        let event = collisions[0];
+        // The normal points towards `a`, and `a` is on the negative side.
+        let sign = if event.a == a { 1.0 } else { -1.0 };
+        assert!(event.normal.x * sign < 0.0);
+        assert!(event.normal_velocity < 0.0);
+        assert!(event.impulse.x * sign < 0.0);
+        assert_eq!(event.a_voxel, IVec::zero());
+        assert_eq!(event.b_voxel, IVec::zero());
+    }
+
+    #[test]
+    fn test_static_body() {
+        let mut app = init_app(0.1);
+        add_octree_collide::<UnitSet>(&mut app);
+        let mut app = app.app;
+        let a = spawn_unit(&mut app, FVec::zero(), FVec::new(1.0, 0.0, 0.0));
+        let b = app.world.spawn(StaticBundle::new(
+            FVec::new(1.2, 0.0, 0.0),
+            Rot::identity(),
+            vec![(IVec::zero(), 1)],
+        ));
+        app.world.insert_one(b, UnitSet).unwrap();
+        for _ in 0..10 {
+            app.update();
+        }
+        assert_eq!(
+            app.world.get::<Position>(b).unwrap().0,
+            FVec::new(1.2, 0.0, 0.0)
+        );
+        assert!(app.world.get::<Momentum>(a).unwrap().0.x < 1.0);
+    }
+
+    #[test]
+    fn test_substepped_movement() {
+        let mut app = init_substepped_app(1.0, Substepping::Fixed(4));
+        app.add_startup_system(init_cube.system())
+            .add_system(apply_force_simple.system())
+            .add_resource(CubeForce(FVec::new(1.0, 0.0, 0.0)))
+            .add_resource(CubeForcePos::default());
+        let mut app = app.app;
+        app.update();
+        app.update();
+        // The force lasts for every substep of each step.
+        for (pos, pp, momentum) in app
+            .world
+            .query::<(&Position, &PreviousPosition, &Momentum)>()
+        {
+            assert_close(pos.0, FVec::new(2.0, 0.0, 0.0), 0.03);
+            assert_close(pp.0, FVec::new(0.5, 0.0, 0.0), 0.03);
+            assert_close(momentum.0, FVec::new(2.0, 0.0, 0.0), 0.03);
+        }
+        let diagnostics = app
+            .resources
+            .get::<diagnostics::PhysicsDiagnostics>()
+            .unwrap();
+        assert_eq!(diagnostics.tick, 2);
+    }
+
+    /// How far a fast body sinks into a static one on the step it hits it.
+    fn impact_position(substepping: Substepping) -> f32 {
+        let mut app = init_substepped_app(0.1, substepping);
+        add_octree_collide::<UnitSet>(&mut app);
+        let mut app = app.app;
+        let a = spawn_unit(&mut app, FVec::zero(), FVec::new(8.0, 0.0, 0.0));
+        let b = app.world.spawn(StaticBundle::new(
+            FVec::new(1.5, 0.0, 0.0),
+            Rot::identity(),
+            vec![(IVec::zero(), 1)],
+        ));
+        app.world.insert_one(b, UnitSet).unwrap();
+        app.update();
+        assert!(app.world.get::<Momentum>(a).unwrap().0.x < 0.0);
+        app.world.get::<Position>(a).unwrap().0.x
+    }
+
+    #[test]
+    fn test_substeps() {
+        let single = impact_position(Substepping::Fixed(1));
+        let fixed = impact_position(Substepping::Fixed(4));
+        let adaptive = impact_position(Substepping::Adaptive {
+            max_substeps: 8,
+            max_travel: 0.25,
+        });
+        // The bodies are near each other before the step, so they are
+        // collided on every substep, and hit before sinking as deep.
+        assert!(fixed < single - 0.1);
+        assert_eq!(adaptive, fixed);
+        assert_eq!(Substepping::Fixed(3).substeps_for(100.0, 0.1), 3);
+        let adaptive = Substepping::Adaptive {
+            max_substeps: 8,
+            max_travel: 0.25,
+        };
+        assert_eq!(adaptive.substeps_for(0.0, 0.1), 1);
+        assert_eq!(adaptive.substeps_for(6.0, 0.1), 3);
+        assert_eq!(adaptive.substeps_for(100.0, 0.1), 8);
+    }
+
+    /// A body resting on the ground under gravity stays at rest while a fast
+    /// body elsewhere splits every step into many substeps.
+    #[test]
+    fn test_resting_contact_with_substeps() {
+        let mut app = init_substepped_app(
+            0.1,
+            Substepping::Adaptive {
+                max_substeps: 8,
+                max_travel: 0.25,
+            },
+        );
+        add_octree_collide::<UnitSet>(&mut app);
+        let mut app = app.app;
+        app.world.spawn((field::ForceField {
+            bounds: field::FieldBounds::Everywhere,
+            kind: field::FieldKind::Acceleration(FVec::new(0.0, -10.0, 0.0)),
+        },));
+        let ground = app.world.spawn(StaticBundle::new(
+            FVec::zero(),
+            Rot::identity(),
+            vec![(IVec::zero(), 1)],
+        ));
+        app.world.insert_one(ground, UnitSet).unwrap();
+        let resting = spawn_unit(&mut app, FVec::new(0.0, 0.98, 0.0), FVec::zero());
+        spawn_unit(
+            &mut app,
+            FVec::new(100.0, 0.0, 0.0),
+            FVec::new(50.0, 0.0, 0.0),
+        );
+        for i in 0..10 {
+            app.update();
+            assert_eq!(app.resources.get::<Substep>().unwrap().count, 8);
+            let y = app.world.get::<Position>(resting).unwrap().0.y;
+            assert!(y > 0.9, "sank to {}", y);
+            let velocity = app.world.get::<Momentum>(resting).unwrap().0;
+            if i >= 3 {
+                assert!(velocity.mag() < 0.3, "moving at {:?}", velocity);
+            }
         }
     }
 
+    fn kinetic_energy(app: &App, e: Entity) -> f32 {
+        let m = app.world.get::<Momentum>(e).unwrap().0;
+        let am = app.world.get::<AngularMomentum>(e).unwrap().0;
+        let im = app.world.get::<InvMass>(e).unwrap().0;
+        let iiacom = world_inv_inertia(
+            &*app.world.get::<Rotation>(e).unwrap(),
+            &*app.world.get::<InvInertiaAroundCenterOfMass>(e).unwrap(),
+        );
+        0.5 * (m.mag_sq() * im + am.dot(iiacom * am))
+    }
+
     #[test]
-    fn test_rotation() {
+    fn test_collisions_do_not_gain_energy() {
+        let mut app = init_app(0.1);
+        add_octree_collide::<UnitSet>(&mut app);
+        let mut app = app.app;
+        let a = spawn_unit(&mut app, FVec::zero(), FVec::new(2.0, 0.3, 0.0));
+        let b = spawn_unit(&mut app, FVec::new(1.5, 0.0, 0.1), FVec::zero());
+        app.update();
+        let initial = kinetic_energy(&app, a) + kinetic_energy(&app, b);
+        for _ in 0..30 {
+            app.update();
+            let energy = kinetic_energy(&app, a) + kinetic_energy(&app, b);
+            assert!(energy <= initial * 1.0001);
+        }
+        // The bodies have bounced apart, losing some energy.
+        let total =
+            app.world.get::<Momentum>(a).unwrap().0 + app.world.get::<Momentum>(b).unwrap().0;
+        assert_close(total, FVec::new(2.0, 0.3, 0.0), 0.03);
+        assert!(app.world.get::<Momentum>(b).unwrap().0.x > 1.0);
+        assert!(kinetic_energy(&app, a) + kinetic_energy(&app, b) < initial * 0.99);
+    }
+
+    #[test]
+    fn test_kinematic_body() {
+        let mut app = init_app(1.0).app;
+        let e = app.world.spawn(KinematicBundle::new(
+            FVec::zero(),
+            Rot::identity(),
+            FVec::new(0.0, 2.0, 0.0),
+            FVec::zero(),
+            vec![(IVec::zero(), 1)],
+        ));
+        app.update();
+        app.update();
+        assert_close(
+            app.world.get::<Position>(e).unwrap().0,
+            FVec::new(0.0, 4.0, 0.0),
+            0.03,
+        );
+    }
+
+    #[test]
+    fn test_torque_direction() {
         let mut app = init_app(1.0);
         app.add_startup_system(init_cube.system())
             .add_system(apply_force_simple.system())
@@ -437,9 +1203,152 @@ mod tests {
         let mut app = app.app;
         app.update();
         app.update();
-        for rot in app.world.query::<&Rotation>() {
-            println!("{:?}", rot);
+        for (rot, am) in app.world.query::<(&Rotation, &AngularMomentum)>() {
+            // Pushing the top of the cube along x turns it clockwise around z.
+            assert!(am.0.z < 0.0);
+            assert!((rot.0 * FVec::unit_x()).y < 0.0);
+        }
+    }
+
+    fn assert_close_rot(a: Rot, b: Rot) {
+        for &v in [FVec::unit_x(), FVec::unit_y(), FVec::unit_z()].iter() {
+            assert_close(a * v, b * v, 0.03);
+        }
+    }
+
+    /// The inverse inertia of a body with the given principal moments.
+    fn inv_inertia(moments: FVec) -> InvInertiaAroundCenterOfMass {
+        InvInertiaAroundCenterOfMass(FMat::from_nonuniform_scale(moments).inversed())
+    }
+
+    fn energy(r: Rot, am: FVec, iiacom: &InvInertiaAroundCenterOfMass) -> f32 {
+        0.5 * am.dot(world_inv_inertia(&Rotation(r), iiacom) * am)
+    }
+
+    #[test]
+    fn test_integrate_rotation() {
+        let r = integrate_rotation(Rot::identity(), FVec::new(0.0, 0.0, 0.3));
+        assert_close(
+            r * FVec::unit_x(),
+            FVec::new(0.3f32.cos(), 0.3f32.sin(), 0.0),
+            0.03,
+        );
+        let r = integrate_rotation(Rot::identity(), FVec::new(0.3, 0.0, 0.0));
+        assert_close(
+            r * FVec::unit_y(),
+            FVec::new(0.0, 0.3f32.cos(), 0.3f32.sin()),
+            0.03,
+        );
+        let r = integrate_rotation(Rot::identity(), FVec::new(0.0, 0.3, 0.0));
+        assert_close(
+            r * FVec::unit_z(),
+            FVec::new(0.3f32.sin(), 0.0, 0.3f32.cos()),
+            0.03,
+        );
+        // Rotations are applied in world space, after the existing rotation.
+        let r = integrate_rotation(
+            integrate_rotation(Rot::identity(), FVec::new(0.0, 0.0, FRAC_PI_2)),
+            FVec::new(FRAC_PI_2, 0.0, 0.0),
+        );
+        assert_close(r * FVec::unit_x(), FVec::unit_z(), 0.03);
+        for &w in [FVec::new(0.3, -0.2, 0.5), FVec::new(-1.0, 1.0, 2.5)].iter() {
+            assert_close(
+                rotation_vector(integrate_rotation(Rot::identity(), w)),
+                w,
+                0.03,
+            );
+        }
+    }
+
+    #[test]
+    fn test_principal_axis_spin() {
+        let moments = FVec::new(1.0, 2.0, 3.0);
+        let iiacom = inv_inertia(moments);
+        for &axis in [FVec::unit_x(), FVec::unit_y(), FVec::unit_z()].iter() {
+            let angular_velocity = axis * 1.5;
+            let am = angular_velocity * moments.dot(axis);
+            let mut r = Rot::identity();
+            for i in 1..=100 {
+                r = rotation_step(r, am, &iiacom, 0.05);
+                let expected =
+                    integrate_rotation(Rot::identity(), angular_velocity * (i as f32 * 0.05));
+                assert_close_rot(r, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_symmetric_precession() {
+        // The symmetry axis of a torque-free symmetric body precesses around the
+        // angular momentum at a rate of |L| / I, where I is the moment of
+        // inertia around the other axes.
+        let iiacom = inv_inertia(FVec::new(2.0, 2.0, 1.0));
+        let am = FVec::new(1.0, 0.0, 2.0);
+        let mut r = Rot::identity();
+        for i in 1..=500 {
+            r = rotation_step(r, am, &iiacom, 0.01);
+            let t = i as f32 * 0.01;
+            let precession =
+                integrate_rotation(Rot::identity(), am.normalized() * (am.mag() / 2.0 * t));
+            assert_close(r * FVec::unit_z(), precession * FVec::unit_z(), 0.03);
+        }
+    }
+
+    #[test]
+    fn test_asymmetric_precession() {
+        // Spinning close to the intermediate axis is unstable, so the body flips
+        // over while conserving its rotational energy.
+        let iiacom = inv_inertia(FVec::new(1.0, 2.0, 3.0));
+        let am = FVec::new(0.01, 2.0, 0.01);
+        let mut r = Rot::identity();
+        let initial_energy = energy(r, am, &iiacom);
+        let mut flipped = false;
+        for _ in 0..3000 {
+            r = rotation_step(r, am, &iiacom, 0.01);
+            assert!((energy(r, am, &iiacom) - initial_energy).abs() < 1e-4 * initial_energy);
+            flipped |= (r * FVec::unit_y()).dot(am) < 0.0;
+        }
+        assert!(flipped);
+        // Spinning close to the major axis is stable.
+        let am = FVec::new(0.01, 0.01, 3.0);
+        let mut r = Rot::identity();
+        for _ in 0..3000 {
+            r = rotation_step(r, am, &iiacom, 0.01);
+            assert!((r * FVec::unit_z()).dot(am.normalized()) > 0.99);
+        }
+    }
+
+    #[test]
+    fn test_angular_conservation() {
+        let mut app = init_app(0.02).app;
+        let mut masses = vec![];
+        for x in 0..3 {
+            for y in 0..2 {
+                masses.push((IVec::new(x, y, 0), 1));
+            }
+        }
+        masses.push((IVec::new(0, 0, 1), 1));
+        let e = app.world.spawn(PhysicsBundle::new(
+            FVec::zero(),
+            Rot::identity(),
+            FVec::zero(),
+            masses,
+        ));
+        let am = FVec::new(0.5, -1.0, 2.0);
+        app.world.get_mut::<AngularMomentum>(e).unwrap().0 = am;
+        app.update();
+        let state = |app: &App| {
+            let r = app.world.get::<Rotation>(e).unwrap().0;
+            let iiacom = *app.world.get::<InvInertiaAroundCenterOfMass>(e).unwrap();
+            (r, energy(r, am, &iiacom))
+        };
+        let (initial_rotation, initial_energy) = state(&app);
+        for _ in 0..500 {
+            app.update();
         }
-        panic!();
+        let (final_rotation, final_energy) = state(&app);
+        assert_eq!(app.world.get::<AngularMomentum>(e).unwrap().0, am);
+        assert!((final_energy - initial_energy).abs() < 1e-3 * initial_energy);
+        assert!((final_rotation * FVec::unit_x() - initial_rotation * FVec::unit_x()).mag() > 0.1);
     }
 }
@@ -0,0 +1,248 @@
+use super::island::solve_islands;
+use super::snapshot::RestoreCount;
+use super::solver::*;
+use super::*;
+use crate::collision::octree::CollisionCache;
+use crate::collision::octree::OctreeCollisionResolver;
+use crate::collision::Positioned;
+use crate::octree::OctreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// A single voxel contact between two bodies.
+/// One of these is sent by `octree_collide` for every contact that it resolves.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct CollisionEvent {
+    pub a: Entity,
+    pub b: Entity,
+    /// The voxel of `a` that was hit.
+    pub a_voxel: IVec,
+    /// The voxel of `b` that was hit.
+    pub b_voxel: IVec,
+    /// The contact normal, pointing from `b` towards `a`.
+    pub normal: FVec,
+    /// The velocity of `a` relative to `b` along the normal, at the contact.
+    /// This is negative when the bodies are moving towards each other.
+    pub normal_velocity: f32,
+    /// The impulse applied to `a`. `b` receives the opposite impulse.
+    pub impulse: FVec,
+}
+
+/// Collides every pair of bodies that have a `Set` collider, resolving the
+/// contacts with impulses and sending a `CollisionEvent` for every contact.
+/// The overlapping nodes of each pair are cached between ticks, so that bodies
+/// resting against each other are cheap to collide. The caches are dropped
+/// whenever a snapshot is restored.
+/// Bodies without a `Momentum` or `AngularMomentum` are treated as infinitely
+/// heavy. Pairs of such bodies are never collided.
+/// Sleeping bodies are only collided with awake ones, and any contact wakes
+/// them. A sleeping body is also woken when a body it was touching stops
+/// touching it, is despawned, changes the generation of its collider, or is a
+/// kinematic body that moves, so that it does not float once its support is
+/// gone.
+/// The contacts of independent groups of bodies are solved in parallel.
+/// New contacts are only searched for on the first substep of a step. The
+/// pairs found to be in contact, or within `CACHE_MARGIN` of it, then are the
+/// only ones collided on the later substeps, so that substepping does not
+/// repeat the search over every pair.
+/// This should be added to the "collide" stage of the physics schedule.
+#[allow(clippy::type_complexity)]
+pub fn octree_collide<Set: 'static + OctreeSet + Send + Sync>(
+    commands: &mut Commands,
+    pool: Res<ComputeTaskPool>,
+    timestep: Res<Timestep>,
+    substep: Res<Substep>,
+    settings: Res<ContactSettings>,
+    mut events: ResMut<Events<CollisionEvent>>,
+    mut caches: Local<HashMap<(Entity, Entity), CollisionCache<Set::Node>>>,
+    // The pairs in contact or close to it at the start of this step.
+    mut touching: Local<HashSet<(Entity, Entity)>>,
+    // The pairs that were in contact the last time they were collided, with
+    // the generations of their colliders then.
+    mut resting: Local<HashMap<(Entity, Entity), (u64, u64)>>,
+    restores: Res<RestoreCount>,
+    mut seen_restores: Local<RestoreCount>,
+    mut query: Query<(
+        Entity,
+        &Set,
+        &mut Position,
+        &mut Rotation,
+        &CenterOfMass,
+        &InvMass,
+        &InvInertiaAroundCenterOfMass,
+        Option<&Kinematic>,
+        Option<&mut Momentum>,
+        Option<&mut AngularMomentum>,
+        Option<&Sleeping>,
+    )>,
+) where
+    Set::Node: Send + Sync, {
+    if *seen_restores != *restores {
+        caches.clear();
+        touching.clear();
+        resting.clear();
+        *seen_restores = *restores;
+    }
+    // Caches of pairs that are not collided in the first substep of a step are
+    // dropped.
+    let mut old_caches = if substep.is_first() {
+        touching.clear();
+        std::mem::take(&mut *caches)
+    } else {
+        HashMap::new()
+    };
+    let mut bodies = query.iter_mut().collect::<Vec<_>>();
+    let mut solver_bodies = bodies
+        .iter()
+        .map(|body| {
+            let (velocity, angular_velocity) = match (body.7, &body.8, &body.9) {
+                (Some(k), _, _) => (k.velocity, k.angular_velocity),
+                (None, Some(m), Some(am)) => {
+                    (m.0 * body.5 .0, world_inv_inertia(&body.3, body.6) * am.0)
+                }
+                _ => (FVec::zero(), FVec::zero()),
+            };
+            let dynamic = body.8.is_some() && body.9.is_some() && body.5 .0 != 0.0;
+            SolverBody::new(
+                body.2 .0,
+                velocity,
+                angular_velocity,
+                if dynamic { body.5 .0 } else { 0.0 },
+                if dynamic {
+                    world_inv_inertia(&body.3, body.6)
+                } else {
+                    FMat::from_scale(0.0)
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut asleep = bodies
+        .iter()
+        .map(|body| body.10.is_some())
+        .collect::<Vec<_>>();
+    let awake = |i: usize| solver_bodies[i].inv_mass != 0.0 && !asleep[i];
+    let moving = |i: usize| {
+        bodies[i].7.map_or(false, |k| {
+            k.velocity != FVec::zero() || k.angular_velocity != FVec::zero()
+        })
+    };
+    // The bodies to wake, as their supports have changed.
+    let mut woken = vec![];
+    if substep.is_first() {
+        let indices = bodies
+            .iter()
+            .enumerate()
+            .map(|(i, body)| (body.0, i))
+            .collect::<HashMap<_, _>>();
+        // Wake the bodies whose partner was despawned or lost its collider.
+        resting.retain(|(a, b), _| match (indices.get(a), indices.get(b)) {
+            (Some(_), Some(_)) => true,
+            (i, j) => {
+                woken.extend(i.into_iter().chain(j).copied());
+                false
+            }
+        });
+    }
+    let mut contacts = vec![];
+    // The voxels of each contact, in the same order.
+    let mut voxels = vec![];
+    for j in 1..bodies.len() {
+        for i in 0..j {
+            let (a, b) = (&bodies[i], &bodies[j]);
+            let generations = (a.1.generation(), b.1.generation());
+            if !awake(i) && !awake(j) {
+                // Neither body can push the other, but they may no longer be
+                // touching.
+                if let Some(&rested) = resting.get(&(a.0, b.0)) {
+                    if rested != generations || moving(i) || moving(j) {
+                        resting.remove(&(a.0, b.0));
+                        woken.extend([i, j].iter().copied());
+                    }
+                }
+                continue;
+            }
+            if !substep.is_first() && !touching.contains(&(a.0, b.0)) {
+                continue;
+            }
+            let mut cache = old_caches
+                .remove(&(a.0, b.0))
+                .or_else(|| caches.remove(&(a.0, b.0)))
+                .unwrap_or_default();
+            let collisions = OctreeCollisionResolver::<Set>::collide_cached(
+                Positioned::new(a.1, voxel_origin(&a.2, &a.3, a.4), a.3 .0),
+                Positioned::new(b.1, voxel_origin(&b.2, &b.3, b.4), b.3 .0),
+                &mut cache,
+            );
+            if substep.is_first() && !cache.is_empty() {
+                touching.insert((a.0, b.0));
+            }
+            caches.insert((a.0, b.0), cache);
+            let first_contact = contacts.len();
+            for (a_voxel, b_voxel, penetration) in collisions {
+                let depth = penetration.mag();
+                if depth == 0.0 {
+                    continue;
+                }
+                let a_point = voxel_to_world(voxel_point(a_voxel), &a.2, &a.3, a.4);
+                let b_point = voxel_to_world(voxel_point(b_voxel), &b.2, &b.3, b.4);
+                contacts.push(Contact::new(
+                    i,
+                    j,
+                    (a_point + b_point) * 0.5,
+                    penetration / depth,
+                    depth,
+                ));
+                voxels.push((a_voxel, b_voxel));
+            }
+            if contacts.len() > first_contact {
+                resting.insert((a.0, b.0), generations);
+            } else if resting.remove(&(a.0, b.0)).is_some() {
+                woken.extend([i, j].iter().copied());
+            }
+        }
+    }
+    for contact in contacts.iter() {
+        woken.push(contact.a);
+        woken.push(contact.b);
+    }
+    for i in woken {
+        if asleep[i] {
+            asleep[i] = false;
+            commands.remove_one::<Sleeping>(bodies[i].0);
+        }
+    }
+    let (settings, timestep) = (*settings, substep.length(&timestep));
+    solve_islands(
+        &pool.0,
+        &mut solver_bodies,
+        &mut contacts,
+        |bodies, contacts| solve(bodies, contacts, &settings, timestep),
+    );
+    for ((body, solved), &sleeping) in bodies
+        .iter_mut()
+        .zip(solver_bodies.iter())
+        .zip(asleep.iter())
+    {
+        if solved.inv_mass == 0.0 || sleeping {
+            continue;
+        }
+        if let (Some(m), Some(am)) = (&mut body.8, &mut body.9) {
+            m.0 += solved.impulse;
+            am.0 += solved.angular_impulse;
+        }
+        // Penetration is removed by moving the bodies directly.
+        body.2 .0 += solved.bias_velocity * timestep;
+        body.3 .0 = integrate_rotation(body.3 .0, solved.bias_angular_velocity * timestep);
+    }
+    for (contact, (a_voxel, b_voxel)) in contacts.into_iter().zip(voxels.into_iter()) {
+        events.send(CollisionEvent {
+            a: bodies[contact.a].0,
+            b: bodies[contact.b].0,
+            a_voxel,
+            b_voxel,
+            normal: contact.normal,
+            normal_velocity: contact.normal_velocity,
+            impulse: contact.impulse,
+        });
+    }
+}
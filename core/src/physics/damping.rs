@@ -0,0 +1,93 @@
+//! Damping, which slowly takes the momentum out of bodies, so that debris
+//! comes to rest and numerical drift does not pile up.
+use super::*;
+
+/// The damping of bodies without a `LinearDamping` or `AngularDamping` of
+/// their own. There is none by default.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct DampingSettings {
+    pub linear: f32,
+    pub angular: f32,
+}
+
+/// The rate at which a body loses its momentum: after `t` seconds,
+/// `exp(-rate * t)` of it is left.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct LinearDamping(pub f32);
+/// The rate at which a body loses its angular momentum, as in `LinearDamping`.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct AngularDamping(pub f32);
+
+/// The fraction of the momentum that is left after damping for `time`, and
+/// the average of that fraction over the time.
+/// Scaling the motion of a step by the average and the momentum by the
+/// fraction follows the damped motion exactly, whatever the timestep.
+pub(super) fn damping_factors(rate: f32, time: f32) -> (f32, f32) {
+    let x = rate * time;
+    if x == 0.0 {
+        return (1.0, 1.0);
+    }
+    ((-x).exp(), -(-x).exp_m1() / x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::test_util::*;
+
+    #[test]
+    fn test_exact_damping() {
+        // The same second of damped motion, in steps of different lengths.
+        for &(timestep, steps) in [(0.1, 10), (0.25, 4), (1.0, 1)].iter() {
+            let mut app = init_app(timestep).app;
+            let e = spawn_body(&mut app, FVec::zero(), FVec::unit_x(), &[IVec::zero()]);
+            app.world
+                .insert(e, (LinearDamping(2.0), AngularDamping(0.5)))
+                .unwrap();
+            app.world.get_mut::<AngularMomentum>(e).unwrap().0 = FVec::unit_z();
+            for _ in 0..steps {
+                app.update();
+            }
+            let decay = (-2.0f32).exp();
+            assert_close(
+                app.world.get::<Momentum>(e).unwrap().0,
+                FVec::unit_x() * decay,
+                0.0001,
+            );
+            assert_close(
+                app.world.get::<Position>(e).unwrap().0,
+                FVec::unit_x() * (1.0 - decay) / 2.0,
+                0.0001,
+            );
+            assert_close(
+                app.world.get::<AngularMomentum>(e).unwrap().0,
+                FVec::unit_z() * (-0.5f32).exp(),
+                0.0001,
+            );
+        }
+    }
+
+    #[test]
+    fn test_default_damping() {
+        let mut app = init_app(0.1).app;
+        app.resources.insert(DampingSettings {
+            linear: 1.0,
+            angular: 0.0,
+        });
+        let damped = spawn_body(&mut app, FVec::zero(), FVec::unit_x(), &[IVec::zero()]);
+        let undamped = spawn_body(&mut app, FVec::zero(), FVec::unit_x(), &[IVec::zero()]);
+        app.world.insert_one(undamped, LinearDamping(0.0)).unwrap();
+        for _ in 0..10 {
+            app.update();
+        }
+        assert_close(
+            app.world.get::<Momentum>(damped).unwrap().0,
+            FVec::unit_x() * (-1.0f32).exp(),
+            0.0001,
+        );
+        assert_eq!(
+            app.world.get::<Momentum>(undamped).unwrap().0,
+            FVec::unit_x()
+        );
+    }
+}
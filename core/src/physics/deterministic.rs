@@ -0,0 +1,999 @@
+//! An optional deterministic mode, where bodies keep all of their state in
+//! fixed point and are stepped in a stable order, so that every machine fed
+//! the same inputs ends up with bit-identical state. This is what lockstep
+//! multiplayer needs.
+//!
+//! Deterministic bodies are spawned with a `DeterministicBundle`. Their float
+//! `Position`, `Rotation` and `CenterOfMass` are only copies of the fixed point
+//! state, kept for rendering, and writing to them has no effect. Deterministic
+//! bodies only collide with each other, through `fixed_octree_collide`, and
+//! joints do not act on them. Force fields and gravity wells act on them
+//! through `apply_fixed_force_fields`, but damping, thrusters and sleeping do
+//! not; any other force must be applied through `FixedForce` and
+//! `FixedTorque`.
+//!
+//! Everything is done in fixed point or with integers, including the inverse
+//! inertia and collision detection, so nothing depends on how a machine
+//! rounds floats. Floats only come in through settings, such as the timestep
+//! and the fields, which are converted to fixed point the same way everywhere.
+use super::contact::CollisionEvent;
+use super::field::{FieldBounds, FieldKind, FieldSettings, ForceField, GravityWell};
+use super::solver::ContactSettings;
+use super::stepping::{PreviousPosition, PreviousRotation};
+use super::*;
+use crate::octree::{OctreeNode, OctreeSet};
+
+/// Marks a deterministic body, with an id that must be the same on every
+/// machine. Bodies are always collided in the order of their ids.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct DeterministicBody(pub u64);
+
+/// The position of the body's center of mass.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct FixedPosition(pub FixedVec);
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct FixedRotation(pub FixedRot);
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct FixedMomentum(pub FixedVec);
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct FixedAngularMomentum(pub FixedVec);
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct FixedForce(pub FixedVec);
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct FixedTorque(pub FixedVec);
+// == Computed properties == //
+/// The center of mass relative to the body's origin.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct FixedCenterOfMass(pub FixedVec);
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct FixedInvMass(pub Fixed);
+/// The inverse inertia around the center of mass, in the body's frame.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct FixedInvInertia(pub FixedMat);
+
+#[derive(Bundle)]
+/// A bundle for deterministic bodies.
+/// The same caveats as `PhysicsBundle` apply to when this can be added.
+pub struct DeterministicBundle {
+    id: DeterministicBody,
+    fixed_position: FixedPosition,
+    fixed_rotation: FixedRotation,
+    momentum: FixedMomentum,
+    angular_momentum: FixedAngularMomentum,
+    force: FixedForce,
+    torque: FixedTorque,
+    total_mass_position: TotalMassPosition,
+    mass: Mass,
+    inertia: Inertia,
+    changed_bodies: ChangedBodies,
+    // Computed fields
+    fixed_center_of_mass: FixedCenterOfMass,
+    inv_mass: FixedInvMass,
+    inv_inertia: FixedInvInertia,
+    // Float copies of the fixed point state
+    position: Position,
+    rotation: Rotation,
+    center_of_mass: CenterOfMass,
+    previous_position: PreviousPosition,
+    previous_rotation: PreviousRotation,
+}
+
+impl DeterministicBundle {
+    /// `position` is the world position of the origin of the voxels.
+    pub fn new(
+        id: u64,
+        position: FixedVec,
+        rotation: FixedRot,
+        velocity: FixedVec,
+        properties: MassProperties,
+    ) -> Self {
+        let (com, inv_mass, inv_inertia) = fixed_mass_properties(
+            properties.total_mass_position,
+            properties.mass,
+            properties.inertia,
+        );
+        let position = position + rotation * com.0;
+        DeterministicBundle {
+            id: DeterministicBody(id),
+            fixed_position: FixedPosition(position),
+            fixed_rotation: FixedRotation(rotation),
+            momentum: FixedMomentum(velocity * Fixed::from_int(properties.mass)),
+            angular_momentum: FixedAngularMomentum(FixedVec::zero()),
+            force: FixedForce(FixedVec::zero()),
+            torque: FixedTorque(FixedVec::zero()),
+            total_mass_position: TotalMassPosition(properties.total_mass_position),
+            mass: Mass(properties.mass),
+            inertia: Inertia(properties.inertia),
+            changed_bodies: ChangedBodies(vec![]),
+            fixed_center_of_mass: com,
+            inv_mass,
+            inv_inertia,
+            position: Position(position.to_f32()),
+            rotation: Rotation(rotation.to_f32()),
+            center_of_mass: CenterOfMass(com.0.to_f32()),
+            previous_position: PreviousPosition(position.to_f32()),
+            previous_rotation: PreviousRotation(rotation.to_f32()),
+        }
+    }
+}
+
+/// The mass times the inertia around the center of mass of a body, which,
+/// unlike the inertia itself, is a matrix of integers.
+fn scaled_inertia(total_mass_position: LVec, mass: i64, inertia: LMat) -> [[i128; 3]; 3] {
+    // The parallel axis theorem subtracts `inertia_of_position(com, mass)`,
+    // which is `inertia_of_position(total_mass_position, 1) / mass`.
+    let t = total_mass_position;
+    let parallel_axis = inertia_of_position([t.0 as i128, t.1 as i128, t.2 as i128], 1);
+    let column = |c: LVec| [c.0 as i128, c.1 as i128, c.2 as i128];
+    let columns = [column(inertia.0), column(inertia.1), column(inertia.2)];
+    let mut i = [[0; 3]; 3];
+    for (r, row) in i.iter_mut().enumerate() {
+        for (c, x) in row.iter_mut().enumerate() {
+            *x = mass as i128 * columns[c][r] - parallel_axis[r][c];
+        }
+    }
+    i
+}
+
+/// `numerator * 2^shift / denominator`, rounded towards zero. The bits below
+/// the point are found one at a time, so that nothing overflows.
+fn shifted_ratio(numerator: i128, denominator: i128, shift: i32) -> i64 {
+    let negative = (numerator < 0) != (denominator < 0);
+    let (mut n, d) = (numerator.unsigned_abs(), denominator.unsigned_abs());
+    if shift < 0 {
+        n >>= -shift;
+    }
+    let (mut quotient, mut remainder) = (n / d, n % d);
+    for _ in 0..shift.max(0) {
+        quotient <<= 1;
+        remainder <<= 1;
+        if remainder >= d {
+            quotient += 1;
+            remainder -= d;
+        }
+    }
+    let quotient = quotient as i64;
+    if negative {
+        -quotient
+    } else {
+        quotient
+    }
+}
+
+/// The number of bits kept of the numbers that `fixed_mass_properties`
+/// multiplies together, so that their products fit in an `i128`.
+const INERTIA_BITS: u32 = 40;
+
+/// The bits to drop from `x` to keep `INERTIA_BITS` of it.
+fn excess_bits(x: u128) -> u32 {
+    (128 - x.leading_zeros()).saturating_sub(INERTIA_BITS)
+}
+
+/// The center of mass, inverse mass and inverse inertia of a body.
+/// Everything is found with integers, so it is the same on every machine.
+fn fixed_mass_properties(
+    total_mass_position: LVec,
+    mass: i64,
+    inertia: LMat,
+) -> (FixedCenterOfMass, FixedInvMass, FixedInvInertia) {
+    if mass == 0 {
+        return Default::default();
+    }
+    let com = FixedVec::new(
+        Fixed::from_ratio(total_mass_position.0, mass),
+        Fixed::from_ratio(total_mass_position.1, mass),
+        Fixed::from_ratio(total_mass_position.2, mass),
+    );
+    // The inverse of the inertia around the center of mass is `mass` times
+    // the inverse of `i`. Only the top bits of `i` are kept, so that its
+    // cofactors and determinant fit in an `i128`, which scales the inverse up
+    // by `2^shift`.
+    let mut i = scaled_inertia(total_mass_position, mass, inertia);
+    let max = i.iter().flatten().map(|x| x.unsigned_abs()).max().unwrap();
+    let shift = excess_bits(max);
+    for x in i.iter_mut().flatten() {
+        *x >>= shift;
+    }
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1) = ((r + 1) % 3, (r + 2) % 3);
+        let (c0, c1) = ((c + 1) % 3, (c + 2) % 3);
+        i[r0][c0] * i[r1][c1] - i[r0][c1] * i[r1][c0]
+    };
+    let det = i[0][0] * cofactor(0, 0) + i[0][1] * cofactor(0, 1) + i[0][2] * cofactor(0, 2);
+    let mass_shift = excess_bits(mass as u128);
+    let scaled_mass = mass as i128 >> mass_shift;
+    let point_shift = Fixed::FRACTION_BITS as i32 + mass_shift as i32 - shift as i32;
+    // The inverse is the transposed cofactors over the determinant, so its
+    // columns are the rows of cofactors.
+    let inverse = |c: usize| {
+        let x = |r: usize| {
+            Fixed(shifted_ratio(
+                scaled_mass * cofactor(c, r),
+                det,
+                point_shift,
+            ))
+        };
+        FixedVec::new(x(0), x(1), x(2))
+    };
+    (
+        FixedCenterOfMass(com),
+        FixedInvMass(Fixed::from_ratio(1, mass)),
+        FixedInvInertia(FixedMat(inverse(0), inverse(1), inverse(2))),
+    )
+}
+
+/// Applies a force at a world position to a deterministic body.
+pub fn apply_fixed_force(
+    force: FixedVec,
+    position: FixedVec,
+    ftp: (&mut FixedForce, &mut FixedTorque, &FixedPosition),
+) {
+    let delta = position - ftp.2 .0;
+    ftp.0 .0 += force;
+    ftp.1 .0 += delta.cross(force);
+}
+
+fn world_inv_inertia_fixed(rotation: FixedRot, inv_inertia: &FixedInvInertia) -> FixedMat {
+    let rot_mat = rotation.into_matrix();
+    rot_mat * inv_inertia.0 * rot_mat.transposed()
+}
+
+fn integrate_rotation_fixed(rotation: FixedRot, w: FixedVec) -> FixedRot {
+    (FixedRot::from_rotation_vector(w) * rotation).normalized()
+}
+
+/// The same as `rotation_step`, in fixed point.
+fn rotation_step_fixed(
+    rotation: FixedRot,
+    angular_momentum: FixedVec,
+    inv_inertia: &FixedInvInertia,
+    timestep: Fixed,
+) -> FixedRot {
+    let angular_velocity =
+        |rotation: FixedRot| world_inv_inertia_fixed(rotation, inv_inertia) * angular_momentum;
+    let midpoint = integrate_rotation_fixed(rotation, angular_velocity(rotation) * (timestep / 2));
+    integrate_rotation_fixed(rotation, angular_velocity(midpoint) * timestep)
+}
+
+/// Recomputes the mass properties of deterministic bodies whose voxels have
+/// changed, keeping the voxels where they are.
+/// This is added to the "pre-physics" stage, after the voxel edits are applied.
+#[allow(clippy::type_complexity)]
+pub fn recompute_fixed_after_changed(
+    mut query: Query<
+        (
+            &TotalMassPosition,
+            &Mass,
+            &Inertia,
+            &FixedRotation,
+            &mut FixedPosition,
+            &mut FixedCenterOfMass,
+            &mut FixedInvMass,
+            &mut FixedInvInertia,
+            &mut CenterOfMass,
+        ),
+        Or<(Changed<TotalMassPosition>, Changed<Mass>, Changed<Inertia>)>,
+    >,
+) {
+    for (tmp, m, i, r, mut p, mut com, mut im, mut ii, mut float_com) in query.iter_mut() {
+        let (new_com, new_im, new_ii) = fixed_mass_properties(tmp.0, m.0, i.0);
+        p.0 += r.0 * (new_com.0 - com.0);
+        *com = new_com;
+        *im = new_im;
+        *ii = new_ii;
+        float_com.0 = com.0.to_f32();
+    }
+}
+
+/// Whether a field acts on a point, found in fixed point.
+fn fixed_contains(bounds: FieldBounds, point: FixedVec) -> bool {
+    match bounds {
+        FieldBounds::Everywhere => true,
+        FieldBounds::Box { min, max } => {
+            let (min, max) = (FixedVec::from_f32(min), FixedVec::from_f32(max));
+            point.x >= min.x
+                && point.y >= min.y
+                && point.z >= min.z
+                && point.x <= max.x
+                && point.y <= max.y
+                && point.z <= max.z
+        }
+        FieldBounds::Sphere { center, radius } => {
+            let radius = Fixed::from_f32(radius);
+            (point - FixedVec::from_f32(center)).mag_sq() <= radius * radius
+        }
+    }
+}
+
+/// The same as `apply_force_fields`, for deterministic bodies, adding to
+/// their `FixedForce` and `FixedTorque`. The fields are converted to fixed
+/// point with `Fixed::from_f32`, and wells that are deterministic bodies pull
+/// from their `FixedPosition`.
+/// This is added to the end of the "pre-physics" stage, and only runs on the
+/// first substep of each step, so the forces are there for the deterministic
+/// step on the last one.
+#[allow(clippy::type_complexity)]
+pub fn apply_fixed_force_fields(
+    substep: Res<Substep>,
+    settings: Res<FieldSettings>,
+    pool: Res<ComputeTaskPool>,
+    fields: Query<&ForceField>,
+    wells: Query<(Entity, &GravityWell, &Position, Option<&FixedPosition>)>,
+    mut bodies: Query<(
+        Entity,
+        &FixedPosition,
+        &FixedRotation,
+        &TotalMassPosition,
+        &Mass,
+        &Inertia,
+        &FixedMomentum,
+        &FixedAngularMomentum,
+        &mut FixedForce,
+        &mut FixedTorque,
+    )>,
+) {
+    if !substep.is_first() {
+        return;
+    }
+    let fields = fields.iter().copied().collect::<Vec<_>>();
+    let wells = wells
+        .iter()
+        .map(|(e, well, p, fixed_p)| {
+            let position = fixed_p.map_or_else(|| FixedVec::from_f32(p.0), |p| p.0);
+            let mass = Fixed::from_f32(well.mass);
+            (e, mass, Fixed::from_f32(well.softening), position)
+        })
+        .collect::<Vec<_>>();
+    if fields.is_empty() && wells.is_empty() {
+        return;
+    }
+    let gravitational_constant = Fixed::from_f32(settings.gravitational_constant);
+    bodies.par_iter_mut(64).for_each(
+        &pool.0,
+        |(e, p, r, tmp, m, i, momentum, am, mut f, mut t)| {
+            for field in fields.iter() {
+                if !fixed_contains(field.bounds, p.0) {
+                    continue;
+                }
+                match field.kind {
+                    FieldKind::Acceleration(acceleration) => {
+                        f.0 += FixedVec::from_f32(acceleration) * Fixed::from_int(m.0);
+                    }
+                    FieldKind::Drag { flow, coefficient } => {
+                        let coefficient = Fixed::from_f32(coefficient);
+                        let flow = FixedVec::from_f32(flow) * Fixed::from_int(m.0);
+                        f.0 += (flow - momentum.0) * coefficient;
+                        t.0 -= am.0 * coefficient;
+                    }
+                }
+            }
+            if wells.is_empty() || m.0 == 0 {
+                return;
+            }
+            let mass = Fixed::from_int(m.0);
+            let scaled = scaled_inertia(tmp.0, m.0, i.0);
+            let column = |c: usize| {
+                let x =
+                    |r: usize| Fixed(((scaled[r][c] << Fixed::FRACTION_BITS) / m.0 as i128) as i64);
+                FixedVec::new(x(0), x(1), x(2))
+            };
+            let rot_mat = r.0.into_matrix();
+            let inertia =
+                rot_mat * FixedMat(column(0), column(1), column(2)) * rot_mat.transposed();
+            for &(well_entity, well_mass, softening, well_position) in wells.iter() {
+                if well_entity == e {
+                    continue;
+                }
+                let delta = p.0 - well_position;
+                let distance_sq = delta.mag_sq() + softening * softening;
+                if distance_sq == Fixed::ZERO {
+                    continue;
+                }
+                // Dividing one at a time keeps this within the range of `Fixed`.
+                let distance = distance_sq.sqrt();
+                let strength = gravitational_constant * well_mass / distance_sq / distance;
+                f.0 -= delta * (strength * mass);
+                // The gravity gradient torque, from the difference in pull
+                // across the body.
+                let direction = delta / distance;
+                t.0 += direction.cross(inertia * direction) * (strength * 3);
+            }
+        },
+    );
+}
+
+/// The first half of a step of every deterministic body, like
+/// `linear_update_before` and `angular_update_before`.
+/// Each body is stepped on its own, so stepping them in parallel does not
+/// change the result.
+/// Deterministic bodies are stepped once per step, on its last substep, so
+/// that they do not depend on the `Substepping`.
+#[allow(clippy::type_complexity)]
+pub fn fixed_update_before(
+    timestep: Res<Timestep>,
+    substep: Res<Substep>,
+    pool: Res<ComputeTaskPool>,
+    mut query: Query<(
+        &FixedInvMass,
+        &FixedInvInertia,
+        &FixedForce,
+        &FixedTorque,
+        &mut FixedMomentum,
+        &mut FixedAngularMomentum,
+        &mut FixedPosition,
+        &mut FixedRotation,
+        &mut Position,
+        &mut Rotation,
+    )>,
+) {
+    if !substep.is_last() {
+        return;
+    }
+    let timestep = Fixed::from_f32(timestep.0);
+    query.par_iter_mut(128).for_each(
+        &pool.0,
+        |(im, ii, f, t, mut m, mut am, mut p, mut r, mut float_p, mut float_r)| {
+            m.0 += f.0 * (timestep / 2);
+            am.0 += t.0 * (timestep / 2);
+            p.0 += m.0 * im.0 * timestep;
+            r.0 = rotation_step_fixed(r.0, am.0, ii, timestep);
+            float_p.0 = p.0.to_f32();
+            float_r.0 = r.0.to_f32();
+        },
+    );
+}
+
+/// The second half of a step of every deterministic body, like
+/// `linear_update_after` and `angular_update_after`. This also clears the
+/// forces and torques.
+#[allow(clippy::type_complexity)]
+pub fn fixed_update_after(
+    timestep: Res<Timestep>,
+    substep: Res<Substep>,
+    pool: Res<ComputeTaskPool>,
+    mut query: Query<(
+        &mut FixedForce,
+        &mut FixedTorque,
+        &mut FixedMomentum,
+        &mut FixedAngularMomentum,
+        &FixedPosition,
+        &mut Position,
+    )>,
+) {
+    if !substep.is_last() {
+        return;
+    }
+    let timestep = Fixed::from_f32(timestep.0);
+    query
+        .par_iter_mut(128)
+        .for_each(&pool.0, |(mut f, mut t, mut m, mut am, p, mut float_p)| {
+            m.0 += f.0 * (timestep / 2);
+            am.0 += t.0 * (timestep / 2);
+            f.0 = FixedVec::zero();
+            t.0 = FixedVec::zero();
+            // Collisions may have moved the body.
+            float_p.0 = p.0.to_f32();
+        });
+}
+
+struct FixedSolverBody {
+    velocity: FixedVec,
+    angular_velocity: FixedVec,
+    inv_mass: Fixed,
+    /// The inverse inertia in world space.
+    inv_inertia: FixedMat,
+    impulse: FixedVec,
+    angular_impulse: FixedVec,
+}
+
+impl FixedSolverBody {
+    fn velocity_at(&self, offset: FixedVec) -> FixedVec {
+        self.velocity + self.angular_velocity.cross(offset)
+    }
+
+    fn inv_effective_mass(&self, offset: FixedVec, direction: FixedVec) -> Fixed {
+        let arm = offset.cross(direction);
+        self.inv_mass + arm.dot(self.inv_inertia * arm)
+    }
+
+    fn apply_impulse(&mut self, offset: FixedVec, impulse: FixedVec) {
+        let angular_impulse = offset.cross(impulse);
+        self.velocity += impulse * self.inv_mass;
+        self.angular_velocity += self.inv_inertia * angular_impulse;
+        self.impulse += impulse;
+        self.angular_impulse += angular_impulse;
+    }
+}
+
+struct FixedContact {
+    a: usize,
+    b: usize,
+    a_offset: FixedVec,
+    b_offset: FixedVec,
+    /// Points from `b` towards `a`.
+    normal: FixedVec,
+    tangents: [FixedVec; 2],
+    normal_velocity: Fixed,
+    target_velocity: Fixed,
+    normal_impulse: Fixed,
+    tangent_impulses: [Fixed; 2],
+}
+
+impl FixedContact {
+    fn relative_velocity(&self, bodies: &[FixedSolverBody]) -> FixedVec {
+        bodies[self.a].velocity_at(self.a_offset) - bodies[self.b].velocity_at(self.b_offset)
+    }
+
+    fn apply(&self, bodies: &mut [FixedSolverBody], impulse: FixedVec) {
+        bodies[self.a].apply_impulse(self.a_offset, impulse);
+        bodies[self.b].apply_impulse(self.b_offset, -impulse);
+    }
+
+    fn inv_effective_mass(&self, bodies: &[FixedSolverBody], direction: FixedVec) -> Fixed {
+        bodies[self.a].inv_effective_mass(self.a_offset, direction)
+            + bodies[self.b].inv_effective_mass(self.b_offset, direction)
+    }
+
+    fn solve(&mut self, bodies: &mut [FixedSolverBody], friction: Fixed) {
+        // Friction is limited by the normal impulse of the last iteration.
+        let velocity = self.relative_velocity(bodies);
+        let old = self.tangent_impulses;
+        let mut new = old;
+        for (impulse, &tangent) in new.iter_mut().zip(self.tangents.iter()) {
+            *impulse -= velocity.dot(tangent) / self.inv_effective_mass(bodies, tangent);
+        }
+        let limit = friction * self.normal_impulse;
+        let magnitude = (new[0] * new[0] + new[1] * new[1]).sqrt();
+        if magnitude > limit {
+            for x in new.iter_mut() {
+                *x = *x * limit / magnitude;
+            }
+        }
+        self.tangent_impulses = new;
+        let impulse = self.tangents[0] * (new[0] - old[0]) + self.tangents[1] * (new[1] - old[1]);
+        self.apply(bodies, impulse);
+
+        let velocity = self.relative_velocity(bodies).dot(self.normal);
+        let mass = self.inv_effective_mass(bodies, self.normal);
+        let old = self.normal_impulse;
+        let new = (old - (velocity - self.target_velocity) / mass).max(Fixed::ZERO);
+        self.normal_impulse = new;
+        self.apply(bodies, self.normal * (new - old));
+    }
+}
+
+/// Two directions perpendicular to a unit normal and to each other.
+fn fixed_tangents(normal: FixedVec) -> [FixedVec; 2] {
+    let first = if normal.x.abs() > Fixed::from_ratio(57, 100) {
+        FixedVec::new(normal.y, -normal.x, Fixed::ZERO)
+    } else {
+        FixedVec::new(Fixed::ZERO, normal.z, -normal.y)
+    }
+    .normalized();
+    [first, normal.cross(first)]
+}
+
+/// The `voxel_point` of a voxel, in fixed point.
+fn fixed_voxel_point(voxel: IVec) -> FixedVec {
+    FixedVec::new(
+        Fixed::from_int(voxel.x as i64),
+        Fixed::from_int(voxel.y as i64),
+        Fixed::from_int(voxel.z as i64),
+    )
+}
+
+/// The center and half of the size of the cube of a node of an octree whose
+/// voxels have their origin at `origin`.
+fn fixed_cube<Node: OctreeNode>(
+    node: Node,
+    origin: FixedVec,
+    rotation: FixedRot,
+) -> (FixedVec, Fixed) {
+    let half_size = Fixed::from_int(node.size() as i64) / 2;
+    // Each voxel is the unit cube centered on its position.
+    let offset = half_size - Fixed::HALF;
+    let center = fixed_voxel_point(node.position()) + FixedVec::new(offset, offset, offset);
+    (origin + rotation * center, half_size)
+}
+
+/// Finds the colliding voxels of two octrees, as
+/// `OctreeCollisionResolver::collide` does, in fixed point.
+fn fixed_collide<Set: OctreeSet>(
+    a: (&Set, FixedVec, FixedRot),
+    b: (&Set, FixedVec, FixedRot),
+) -> Vec<(IVec, IVec, FixedVec)> {
+    let sqrt_3 = Fixed::from_int(3).sqrt();
+    // The same as `CUBE_SIZE_FACTOR`.
+    let shrink = Fixed::from_ratio(9, 10);
+    let mut collisions = vec![];
+    let mut pairs = vec![(a.0.root(), b.0.root())];
+    while let Some((x, y)) = pairs.pop() {
+        let (x_center, x_half_size) = fixed_cube(x, a.1, a.2);
+        let (y_center, y_half_size) = fixed_cube(y, b.1, b.2);
+        // Nodes whose bounding spheres do not overlap cannot collide.
+        let delta = x_center - y_center;
+        let max_dist = sqrt_3 * (x_half_size + y_half_size);
+        if delta.mag_sq() >= max_dist * max_dist {
+            continue;
+        }
+        if x.is_unit() && y.is_unit() {
+            // An empty root may be a unit node.
+            if !x.is_full() || !y.is_full() {
+                continue;
+            }
+            let (dist, max_dist) = (delta.mag(), max_dist * shrink);
+            if dist < max_dist {
+                let penetration = delta.normalized() * (max_dist - dist);
+                collisions.push((x.position(), y.position(), penetration));
+            }
+        } else if x.size() >= y.size() {
+            pairs.extend(a.0.children(x).map(|child| (child, y)));
+        } else {
+            pairs.extend(b.0.children(y).map(|child| (x, child)));
+        }
+    }
+    collisions
+}
+
+/// Collides every pair of deterministic bodies that have a `Set` collider, in
+/// the order of their ids, resolving the contacts with fixed point impulses
+/// and sending a `CollisionEvent` for every contact.
+/// The overlapping voxels are found from the fixed point poses as well, by
+/// `fixed_collide`. The node pairs are not cached, as `octree_collide` does,
+/// since reusing a cache depends on trigonometry.
+/// Penetration is removed by moving each pair of bodies apart along their
+/// deepest contact.
+/// Like the rest of the deterministic step, this only runs on the last substep.
+/// This should be added to the "collide" stage of the physics schedule.
+#[allow(clippy::type_complexity)]
+pub fn fixed_octree_collide<Set: 'static + OctreeSet + Send + Sync>(
+    substep: Res<Substep>,
+    settings: Res<ContactSettings>,
+    mut events: ResMut<Events<CollisionEvent>>,
+    mut query: Query<(
+        Entity,
+        &DeterministicBody,
+        &Set,
+        &FixedRotation,
+        &FixedCenterOfMass,
+        &FixedInvMass,
+        &FixedInvInertia,
+        &mut FixedPosition,
+        &mut FixedMomentum,
+        &mut FixedAngularMomentum,
+        &mut Position,
+    )>,
+) where
+    Set::Node: Send + Sync, {
+    if !substep.is_last() {
+        return;
+    }
+    let restitution = Fixed::from_f32(settings.restitution);
+    let restitution_threshold = Fixed::from_f32(settings.restitution_threshold);
+    let friction = Fixed::from_f32(settings.friction);
+    let slop = Fixed::from_f32(settings.slop);
+    let position_correction = Fixed::from_f32(settings.position_correction);
+
+    let mut bodies = query.iter_mut().collect::<Vec<_>>();
+    bodies.sort_by_key(|body| *body.1);
+    let mut solver_bodies = bodies
+        .iter()
+        .map(|body| {
+            let inv_inertia = world_inv_inertia_fixed(body.3 .0, body.6);
+            FixedSolverBody {
+                velocity: body.8 .0 * body.5 .0,
+                angular_velocity: inv_inertia * body.9 .0,
+                inv_mass: body.5 .0,
+                inv_inertia,
+                impulse: FixedVec::zero(),
+                angular_impulse: FixedVec::zero(),
+            }
+        })
+        .collect::<Vec<_>>();
+    let voxel_origin = |body: usize| {
+        let (position, rotation, com) = (&bodies[body].7, bodies[body].3, bodies[body].4);
+        position.0 - rotation.0 * com.0
+    };
+    let world_point = |body: usize, voxel: IVec| {
+        let (position, rotation, com) = (&bodies[body].7, bodies[body].3, bodies[body].4);
+        position.0 + rotation.0 * (fixed_voxel_point(voxel) - com.0)
+    };
+    let mut contacts = vec![];
+    // The voxels of each contact, in the same order.
+    let mut voxels = vec![];
+    // The deepest contact of each pair, as `(a, b, normal, depth)`.
+    let mut deepest = vec![];
+    for i in 0..bodies.len() {
+        for j in i + 1..bodies.len() {
+            if solver_bodies[i].inv_mass == Fixed::ZERO && solver_bodies[j].inv_mass == Fixed::ZERO
+            {
+                continue;
+            }
+            let (a, b) = (&bodies[i], &bodies[j]);
+            let collisions = fixed_collide(
+                (a.2, voxel_origin(i), a.3 .0),
+                (b.2, voxel_origin(j), b.3 .0),
+            );
+            let mut pair_deepest: Option<(FixedVec, Fixed)> = None;
+            for (a_voxel, b_voxel, penetration) in collisions {
+                let depth = penetration.mag();
+                if depth == Fixed::ZERO {
+                    continue;
+                }
+                let normal = penetration / depth;
+                let point =
+                    (world_point(i, a_voxel) + world_point(j, b_voxel)) / Fixed::from_int(2);
+                let a_offset = point - a.7 .0;
+                let b_offset = point - b.7 .0;
+                let normal_velocity = (solver_bodies[i].velocity_at(a_offset)
+                    - solver_bodies[j].velocity_at(b_offset))
+                .dot(normal);
+                contacts.push(FixedContact {
+                    a: i,
+                    b: j,
+                    a_offset,
+                    b_offset,
+                    normal,
+                    tangents: fixed_tangents(normal),
+                    normal_velocity,
+                    target_velocity: if normal_velocity < -restitution_threshold {
+                        -restitution * normal_velocity
+                    } else {
+                        Fixed::ZERO
+                    },
+                    normal_impulse: Fixed::ZERO,
+                    tangent_impulses: [Fixed::ZERO; 2],
+                });
+                voxels.push((a_voxel, b_voxel));
+                if pair_deepest.map_or(true, |(_, d)| depth > d) {
+                    pair_deepest = Some((normal, depth));
+                }
+            }
+            if let Some((normal, depth)) = pair_deepest {
+                deepest.push((i, j, normal, depth));
+            }
+        }
+    }
+    for _ in 0..settings.iterations {
+        for contact in contacts.iter_mut() {
+            contact.solve(&mut solver_bodies, friction);
+        }
+    }
+    for (i, j, normal, depth) in deepest {
+        let total = solver_bodies[i].inv_mass + solver_bodies[j].inv_mass;
+        if depth <= slop || total == Fixed::ZERO {
+            continue;
+        }
+        let correction = normal * ((depth - slop) * position_correction / total);
+        bodies[i].7 .0 += correction * solver_bodies[i].inv_mass;
+        bodies[j].7 .0 -= correction * solver_bodies[j].inv_mass;
+    }
+    for (body, solved) in bodies.iter_mut().zip(solver_bodies.iter()) {
+        body.8 .0 += solved.impulse;
+        body.9 .0 += solved.angular_impulse;
+        body.10 .0 = body.7 .0.to_f32();
+    }
+    for (contact, (a_voxel, b_voxel)) in contacts.into_iter().zip(voxels.into_iter()) {
+        let impulse = contact.normal * contact.normal_impulse
+            + contact.tangents[0] * contact.tangent_impulses[0]
+            + contact.tangents[1] * contact.tangent_impulses[1];
+        events.send(CollisionEvent {
+            a: bodies[contact.a].0,
+            b: bodies[contact.b].0,
+            a_voxel,
+            b_voxel,
+            normal: contact.normal.to_f32(),
+            normal_velocity: contact.normal_velocity.to_f32(),
+            impulse: impulse.to_f32(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::linear_octree_set::LinearOctreeSet;
+    use crate::physics::test_util;
+
+    struct Marker;
+
+    fn init_app() -> App {
+        let mut app = test_util::init_app(0.1);
+        app.stage("physics-schedule", |schedule: &mut Schedule| {
+            schedule
+                .add_system_to_stage("collide", fixed_octree_collide::<LinearOctreeSet>.system())
+        });
+        app.app
+    }
+
+    fn fixed(x: f32, y: f32, z: f32) -> FixedVec {
+        FixedVec::from_f32(FVec::new(x, y, z))
+    }
+
+    /// An L shaped body, so that it has an interesting inertia.
+    fn spawn_body(app: &mut App, id: u64, position: FixedVec, velocity: FixedVec) -> Entity {
+        let voxels = [
+            IVec::zero(),
+            IVec::unit_x(),
+            IVec::unit_y() * 2,
+            IVec::unit_y(),
+        ];
+        let rotation = FixedRot::from_rotation_vector(fixed(0.1, id as f32 * 0.3, -0.2));
+        let e = app.world.spawn(DeterministicBundle::new(
+            id,
+            position,
+            rotation,
+            velocity,
+            MassProperties::from_voxels(voxels.iter().map(|&v| (v, 1)).collect::<Vec<_>>()),
+        ));
+        app.world
+            .insert_one(e, LinearOctreeSet::from_positions(&voxels))
+            .unwrap();
+        e
+    }
+
+    #[test]
+    fn test_exact_forces() {
+        let mut app = init_app();
+        let voxels = vec![(IVec::zero(), 1)];
+        let e = app.world.spawn(DeterministicBundle::new(
+            0,
+            FixedVec::zero(),
+            FixedRot::identity(),
+            FixedVec::zero(),
+            MassProperties::from_voxels(voxels),
+        ));
+        for _ in 0..10 {
+            app.world.get_mut::<FixedForce>(e).unwrap().0 = fixed(1.0, 0.0, 0.0);
+            app.update();
+        }
+        // Every half step adds exactly the same momentum.
+        let half_step = Fixed::from_f32(0.1) / 2;
+        let momentum = app.world.get::<FixedMomentum>(e).unwrap().0;
+        assert_eq!(
+            momentum,
+            FixedVec::new(half_step * 20, Fixed::ZERO, Fixed::ZERO)
+        );
+        let position = app.world.get::<Position>(e).unwrap().0;
+        assert!((position - FVec::new(0.5, 0.0, 0.0)).mag() < 1e-5);
+    }
+
+    #[test]
+    fn test_mass_properties() {
+        let l = [
+            IVec::zero(),
+            IVec::unit_x(),
+            IVec::unit_y() * 2,
+            IVec::unit_y(),
+        ];
+        let properties = MassProperties::from_voxels(l.iter().map(|&v| (v, 2)).collect::<Vec<_>>());
+        let (_, _, inv_inertia) = fixed_mass_properties(
+            properties.total_mass_position,
+            properties.mass,
+            properties.inertia,
+        );
+        let (_, _, expected) = properties_around_center_of_mass(
+            properties.center_of_mass(),
+            properties.mass,
+            properties.inertia,
+            false,
+        );
+        let found = inv_inertia.0.to_f32();
+        for (&found, &expected) in found.cols.iter().zip(expected.0.cols.iter()) {
+            test_util::assert_close(found, expected, 1e-6);
+        }
+
+        // This is far enough from the origin that only the top bits of its
+        // inertia are kept. Each voxel has an inertia of its own of one, so
+        // the inertia of the cube is `mass * (1 + (size^2 - 1) / 6)` around
+        // every axis.
+        let mut properties = MassProperties::default();
+        properties.add_cube(IVec::new(100_000, -20_000, 5000), 16, 1);
+        let (com, _, inv_inertia) = fixed_mass_properties(
+            properties.total_mass_position,
+            properties.mass,
+            properties.inertia,
+        );
+        assert_eq!(com.0, fixed(100_007.5, -19_992.5, 5007.5));
+        let expected = 1.0 / (4096.0 * (1.0 + 255.0 / 6.0));
+        let found = inv_inertia.0.to_f32();
+        for (c, column) in found.cols.iter().enumerate() {
+            let axis = [FVec::unit_x(), FVec::unit_y(), FVec::unit_z()][c];
+            test_util::assert_close(*column, axis * expected, expected * 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_force_fields() {
+        let mut app = init_app();
+        app.world.spawn((ForceField {
+            bounds: FieldBounds::Sphere {
+                center: FVec::zero(),
+                radius: 5.0,
+            },
+            kind: FieldKind::Acceleration(FVec::new(0.0, -1.0, 0.0)),
+        },));
+        app.world.spawn((
+            GravityWell {
+                mass: 100.0,
+                softening: 0.0,
+            },
+            Position(FVec::new(20.0, 0.0, 0.0)),
+        ));
+        let voxels = || MassProperties::from_voxels(vec![(IVec::zero(), 2)]);
+        let spawn = |app: &mut App, id: u64, position: FixedVec| {
+            let rotation = FixedRot::identity();
+            let bundle =
+                DeterministicBundle::new(id, position, rotation, FixedVec::zero(), voxels());
+            app.world.spawn(bundle)
+        };
+        let inside = spawn(&mut app, 0, FixedVec::zero());
+        let outside = spawn(&mut app, 1, fixed(0.0, 0.0, -10.0));
+        for _ in 0..10 {
+            app.update();
+        }
+        let momentum = |e: Entity| app.world.get::<FixedMomentum>(e).unwrap().0;
+        // Every half step adds exactly the same momentum.
+        let half_step = Fixed::from_f32(0.1) / 2;
+        assert_eq!(momentum(inside).y, Fixed::from_int(-2) * half_step * 20);
+        assert_eq!(momentum(outside).y, Fixed::ZERO);
+        // The well pulls both.
+        assert!(momentum(inside).x > Fixed::ZERO);
+        assert!(momentum(outside).x > Fixed::ZERO);
+        assert!(momentum(outside).z > Fixed::ZERO);
+    }
+
+    #[test]
+    fn test_order_independence() {
+        let bodies = [
+            (1, fixed(0.0, 0.0, 0.0), fixed(1.0, 0.1, 0.0)),
+            (2, fixed(3.0, 0.2, 0.1), fixed(-1.0, 0.0, 0.05)),
+            (3, fixed(1.5, 3.0, 0.0), fixed(0.0, -1.5, 0.0)),
+            (4, fixed(-4.0, 0.5, 0.3), fixed(0.5, 0.0, 0.0)),
+        ];
+        let mut a = init_app();
+        for &(id, position, velocity) in bodies.iter() {
+            spawn_body(&mut a, id, position, velocity);
+        }
+        let mut b = init_app();
+        for &(id, position, velocity) in bodies.iter().rev() {
+            let e = spawn_body(&mut b, id, position, velocity);
+            // Different archetypes are iterated in a different order.
+            if id % 2 == 0 {
+                b.world.insert_one(e, Marker).unwrap();
+            }
+        }
+        let state = |app: &App| {
+            let mut state = app
+                .world
+                .query::<(
+                    &DeterministicBody,
+                    &FixedPosition,
+                    &FixedRotation,
+                    &FixedMomentum,
+                    &FixedAngularMomentum,
+                )>()
+                .map(|(id, p, r, m, am)| (*id, *p, *r, *m, *am))
+                .collect::<Vec<_>>();
+            state.sort_by_key(|x| x.0);
+            state
+        };
+        let total_momentum = |app: &App| {
+            state(app)
+                .iter()
+                .fold(FixedVec::zero(), |total, body| total + body.3 .0)
+        };
+        let initial_momentum = total_momentum(&a);
+        let mut collided = false;
+        for _ in 0..40 {
+            a.update();
+            b.update();
+            assert_eq!(state(&a), state(&b));
+            let events = a.resources.get::<Events<CollisionEvent>>().unwrap();
+            collided |= events.get_reader().iter(&events).next().is_some();
+        }
+        assert!(collided);
+        // Every impulse is applied exactly, equal and opposite.
+        assert_eq!(total_momentum(&a), initial_momentum);
+    }
+}
@@ -0,0 +1,253 @@
+//! Totals that the physics should conserve, recorded every tick, so that
+//! changes to the physics can be checked against them.
+use super::*;
+use std::collections::VecDeque;
+
+/// The totals over every dynamic body at a tick.
+/// Deterministic bodies are not included, as they keep their momentum in
+/// fixed point.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct PhysicsTotals {
+    pub linear_momentum: FVec,
+    /// The angular momentum around the world origin.
+    pub angular_momentum: FVec,
+    /// The kinetic energy of the motion of the centers of mass.
+    pub linear_energy: f32,
+    /// The kinetic energy of the rotation around the centers of mass.
+    pub angular_energy: f32,
+}
+
+impl PhysicsTotals {
+    pub fn kinetic_energy(&self) -> f32 {
+        self.linear_energy + self.angular_energy
+    }
+}
+
+/// Something wrong that the diagnostics found.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Violation {
+    /// A body has a position, rotation or momentum that is not finite.
+    NotFinite(Entity),
+    EnergyDrift {
+        reference: f32,
+        found: f32,
+    },
+    MomentumDrift {
+        reference: FVec,
+        found: FVec,
+    },
+    AngularMomentumDrift {
+        reference: FVec,
+        found: FVec,
+    },
+}
+
+/// The conservation diagnostics of the physics world.
+/// Drift is measured from `reference`, which is set to the totals of the first
+/// tick, and can be reset by setting it to `None`. The momenta are compared
+/// relative to the sum of the magnitudes of the momenta of the bodies, so that
+/// bodies moving in opposite directions still have a sensible tolerance.
+#[derive(Clone, PartialEq, Debug)]
+pub struct PhysicsDiagnostics {
+    /// The most ticks kept in `history`.
+    pub history_length: usize,
+    /// Whether to flag bodies whose state is not finite.
+    pub check_finite: bool,
+    /// The relative drift in kinetic energy above which it is flagged.
+    pub energy_tolerance: Option<f32>,
+    /// The relative drift in linear and angular momentum above which they are
+    /// flagged.
+    pub momentum_tolerance: Option<f32>,
+    pub reference: Option<PhysicsTotals>,
+    /// The totals of the latest ticks, oldest first.
+    pub history: VecDeque<PhysicsTotals>,
+    /// Everything that has been flagged, with the tick it was flagged at.
+    pub violations: Vec<(u64, Violation)>,
+    /// The ticks recorded so far.
+    pub tick: u64,
+}
+impl Default for PhysicsDiagnostics {
+    fn default() -> Self {
+        PhysicsDiagnostics {
+            history_length: 600,
+            check_finite: true,
+            energy_tolerance: None,
+            momentum_tolerance: None,
+            reference: None,
+            history: VecDeque::new(),
+            violations: vec![],
+            tick: 0,
+        }
+    }
+}
+
+impl PhysicsDiagnostics {
+    /// The totals of the latest tick.
+    pub fn latest(&self) -> Option<PhysicsTotals> {
+        self.history.back().copied()
+    }
+}
+
+fn is_finite(v: FVec) -> bool {
+    v.x.is_finite() && v.y.is_finite() && v.z.is_finite()
+}
+
+fn is_finite_rotor(r: Rot) -> bool {
+    r.s.is_finite() && r.bv.xy.is_finite() && r.bv.xz.is_finite() && r.bv.yz.is_finite()
+}
+
+fn drifted(reference: FVec, found: FVec, scale: f32, tolerance: f32) -> bool {
+    (found - reference).mag() > tolerance * scale
+}
+
+/// Records the totals of the tick, and flags anything wrong with them.
+/// This is added to the "diagnostics" stage, at the end of the physics
+/// schedule, and only runs on the last substep of each tick.
+pub fn record_diagnostics(
+    substep: Res<Substep>,
+    mut diagnostics: ResMut<PhysicsDiagnostics>,
+    query: Query<(
+        Entity,
+        &Position,
+        &Rotation,
+        &Momentum,
+        &AngularMomentum,
+        &InvMass,
+        &InvInertiaAroundCenterOfMass,
+    )>,
+) {
+    if !substep.is_last() {
+        return;
+    }
+    let tick = diagnostics.tick;
+    diagnostics.tick += 1;
+    let mut totals = PhysicsTotals::default();
+    // The sums of the magnitudes of the momenta.
+    let mut linear_scale = 0.0;
+    let mut angular_scale = 0.0;
+    for (e, p, r, m, am, im, iiacom) in query.iter() {
+        if diagnostics.check_finite
+            && !(is_finite(p.0) && is_finite(m.0) && is_finite(am.0) && is_finite_rotor(r.0))
+        {
+            diagnostics.violations.push((tick, Violation::NotFinite(e)));
+            continue;
+        }
+        let orbital = p.0.cross(m.0);
+        totals.linear_momentum += m.0;
+        totals.angular_momentum += orbital + am.0;
+        totals.linear_energy += 0.5 * m.0.mag_sq() * im.0;
+        totals.angular_energy += 0.5 * am.0.dot(world_inv_inertia(r, iiacom) * am.0);
+        linear_scale += m.0.mag();
+        angular_scale += orbital.mag() + am.0.mag();
+    }
+    let reference = *diagnostics.reference.get_or_insert(totals);
+    if let Some(tolerance) = diagnostics.energy_tolerance {
+        let (reference, found) = (reference.kinetic_energy(), totals.kinetic_energy());
+        if (found - reference).abs() > tolerance * reference {
+            let violation = Violation::EnergyDrift { reference, found };
+            diagnostics.violations.push((tick, violation));
+        }
+    }
+    if let Some(tolerance) = diagnostics.momentum_tolerance {
+        let (reference, found) = (reference.linear_momentum, totals.linear_momentum);
+        if drifted(reference, found, linear_scale, tolerance) {
+            let violation = Violation::MomentumDrift { reference, found };
+            diagnostics.violations.push((tick, violation));
+        }
+        let (reference, found) = (reference.angular_momentum, totals.angular_momentum);
+        if drifted(reference, found, angular_scale, tolerance) {
+            let violation = Violation::AngularMomentumDrift { reference, found };
+            diagnostics.violations.push((tick, violation));
+        }
+    }
+    diagnostics.history.push_back(totals);
+    while diagnostics.history.len() > diagnostics.history_length {
+        diagnostics.history.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::linear_octree_set::LinearOctreeSet;
+    use crate::physics::test_util::{self, add_octree_collide, spawn_octree_body};
+
+    fn init_app(diagnostics: PhysicsDiagnostics) -> App {
+        let mut app = test_util::init_app(0.1);
+        app.add_resource(diagnostics);
+        add_octree_collide::<LinearOctreeSet>(&mut app);
+        app.app
+    }
+
+    #[test]
+    fn test_totals() {
+        let mut app = init_app(PhysicsDiagnostics::default());
+        let e = spawn_octree_body(
+            &mut app,
+            FVec::new(0.0, 1.0, 0.0),
+            FVec::new(2.0, 0.0, 0.0),
+            &[IVec::zero(), IVec::unit_x()],
+        );
+        app.world.get_mut::<AngularMomentum>(e).unwrap().0 = FVec::unit_z();
+        app.update();
+        let diagnostics = app.resources.get::<PhysicsDiagnostics>().unwrap();
+        let totals = diagnostics.latest().unwrap();
+        let position = app.world.get::<Position>(e).unwrap().0;
+        assert_eq!(totals.linear_momentum, FVec::new(4.0, 0.0, 0.0));
+        assert_eq!(
+            totals.angular_momentum,
+            position.cross(FVec::new(4.0, 0.0, 0.0)) + FVec::unit_z()
+        );
+        assert!((totals.linear_energy - 4.0).abs() < 0.0001);
+        assert!(totals.angular_energy > 0.0);
+        assert!(diagnostics.violations.is_empty());
+    }
+
+    #[test]
+    fn test_collision_drift() {
+        let mut app = init_app(PhysicsDiagnostics {
+            history_length: 10,
+            energy_tolerance: Some(0.01),
+            momentum_tolerance: Some(0.001),
+            ..Default::default()
+        });
+        let l = [IVec::zero(), IVec::unit_x(), IVec::unit_y()];
+        spawn_octree_body(&mut app, FVec::zero(), FVec::unit_x(), &l);
+        spawn_octree_body(&mut app, FVec::new(3.0, 0.5, 0.0), -FVec::unit_x(), &l);
+        for _ in 0..30 {
+            app.update();
+        }
+        let diagnostics = app.resources.get::<PhysicsDiagnostics>().unwrap();
+        assert_eq!(diagnostics.tick, 30);
+        assert_eq!(diagnostics.history.len(), 10);
+        // Contacts lose energy, but conserve momentum.
+        assert!(diagnostics
+            .violations
+            .iter()
+            .any(|(_, v)| matches!(v, Violation::EnergyDrift { .. })));
+        assert!(diagnostics
+            .violations
+            .iter()
+            .all(|(_, v)| matches!(v, Violation::EnergyDrift { .. })));
+    }
+
+    #[test]
+    fn test_not_finite() {
+        let mut app = init_app(PhysicsDiagnostics::default());
+        let e = spawn_octree_body(&mut app, FVec::zero(), FVec::zero(), &[IVec::zero()]);
+        app.world.get_mut::<Momentum>(e).unwrap().0 = FVec::new(f32::INFINITY, 0.0, 0.0);
+        app.update();
+        let diagnostics = app.resources.get::<PhysicsDiagnostics>().unwrap();
+        assert_eq!(diagnostics.violations, vec![(0, Violation::NotFinite(e))]);
+    }
+
+    #[test]
+    fn test_not_finite_rotation() {
+        let mut app = init_app(PhysicsDiagnostics::default());
+        let e = spawn_octree_body(&mut app, FVec::zero(), FVec::zero(), &[IVec::zero()]);
+        app.world.get_mut::<Rotation>(e).unwrap().0.bv.xz = f32::NAN;
+        app.update();
+        let diagnostics = app.resources.get::<PhysicsDiagnostics>().unwrap();
+        assert_eq!(diagnostics.violations, vec![(0, Violation::NotFinite(e))]);
+    }
+}
@@ -0,0 +1,225 @@
+//! Editing the voxels of bodies, keeping their storage, collider and mass
+//! properties in sync.
+//!
+//! Every voxel of a body is kept in three places: its storage, its collider,
+//! and its mass properties. Edits should go through `edit_voxels`, or be
+//! queued in `VoxelEdits`, so that all three see them. Changing the mass of a
+//! body moves its center of mass, but the body is moved along with it, so its
+//! voxels stay exactly where they are in the world.
+use super::mass_properties::MassVoxel;
+use super::*;
+use crate::octree::EditableOctreeSet;
+use crate::storage::{VoxelStorage, Writer};
+
+/// Voxel edits to a body, applied together by `apply_voxel_edits` before the
+/// next tick. Later edits to the same voxel win.
+#[derive(Clone, PartialEq, Debug)]
+pub struct VoxelEdits<T>(pub Vec<(IVec, T)>);
+impl<T> Default for VoxelEdits<T> {
+    fn default() -> Self {
+        VoxelEdits(vec![])
+    }
+}
+
+/// Sets voxels of a body, updating its storage, its collider, and the mass
+/// changes in `changed`.
+/// The collider contains exactly the voxels with mass. Bodies that do not
+/// keep track of their mass, such as static bodies, have no `changed`.
+pub fn edit_voxels<Storage, Set>(
+    storage: &mut Storage,
+    collider: &mut Set,
+    mut changed: Option<&mut ChangedBodies>,
+    edits: impl IntoIterator<Item = (IVec, Storage::T)>,
+) where
+    Storage: VoxelStorage<Position = IVec>,
+    Storage::T: MassVoxel,
+    Set: EditableOctreeSet, {
+    for (position, voxel) in edits {
+        let old = storage.get(position).mass();
+        let new = voxel.mass();
+        *storage.get_mut(position).get_mut() = voxel;
+        if new > 0 {
+            collider.set(position);
+        } else {
+            collider.clear(position);
+        }
+        if let Some(changed) = &mut changed {
+            if new != old {
+                changed.0.push((position, new - old));
+            }
+        }
+    }
+}
+
+/// Applies the queued `VoxelEdits` of every body, including edits inserted
+/// together with the body.
+/// This should be added to a stage before the one that the other parts of the
+/// bodies are updated from their storage in, such as `stage::PRE_UPDATE`, for
+/// the storage and collider types of the bodies.
+#[allow(clippy::type_complexity)]
+pub fn apply_voxel_edits<Storage, Set>(
+    mut query: Query<(
+        &mut VoxelEdits<Storage::T>,
+        &mut Storage,
+        &mut Set,
+        Option<&mut ChangedBodies>,
+    )>,
+) where
+    Storage: 'static + VoxelStorage<Position = IVec> + Send + Sync,
+    Storage::T: 'static + MassVoxel + Send + Sync,
+    Set: 'static + EditableOctreeSet + Send + Sync, {
+    for (mut edits, mut storage, mut collider, mut changed) in query.iter_mut() {
+        if edits.0.is_empty() {
+            continue;
+        }
+        edit_voxels(
+            &mut *storage,
+            &mut *collider,
+            changed.as_deref_mut(),
+            std::mem::take(&mut edits.0),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::for_each::ForEach;
+    use crate::octree::octree_set::BBOctreeSet;
+    use crate::octree::{OctreeNode, OctreeSet};
+    use crate::physics::test_util::{self, spawn_storage_body, TestVoxel};
+    use crate::storage::chunk_map::ChunkStorage;
+
+    fn init_app() -> App {
+        let mut app = test_util::init_app(0.1);
+        app.add_system_to_stage(
+            stage::PRE_UPDATE,
+            apply_voxel_edits::<ChunkStorage<TestVoxel>, BBOctreeSet>.system(),
+        );
+        app.app
+    }
+
+    /// Checks that the voxels of a body are exactly where they were spawned,
+    /// with their origin at `origin`.
+    fn assert_anchored(app: &App, e: Entity, origin: FVec, rotation: Rot) {
+        assert_eq!(app.world.get::<VoxelAnchor>(e).unwrap().origin, origin);
+        assert_eq!(app.world.get::<Rotation>(e).unwrap().0, rotation);
+        let com = app.world.get::<CenterOfMass>(e).unwrap().0;
+        assert_eq!(
+            app.world.get::<Position>(e).unwrap().0,
+            origin + rotation * com
+        );
+    }
+
+    fn voxels(set: &BBOctreeSet) -> Vec<IVec> {
+        let mut voxels = vec![];
+        set.for_each_full(|node| {
+            assert_eq!(node.size(), 1);
+            voxels.push(node.position());
+        });
+        voxels.sort_by_key(|v| (v.x, v.y, v.z));
+        voxels
+    }
+
+    #[test]
+    fn test_edits_keep_pose() {
+        let mut app = init_app();
+        let mut storage = ChunkStorage::new(TestVoxel::Empty, 16);
+        for x in 0..3 {
+            *storage.get_mut(IVec::new(x, 0, 0)).get_mut() = TestVoxel::Hull;
+        }
+        let origin = FVec::new(1.0, 2.0, 3.0);
+        let rotation = Rot::from_rotation_xy(0.7) * Rot::from_rotation_yz(0.3);
+        let e = spawn_storage_body(&mut app, origin, rotation, storage);
+        app.world
+            .insert_one(e, VoxelEdits::<TestVoxel>::default())
+            .unwrap();
+        assert_anchored(&app, e, origin, rotation);
+        app.update();
+        assert_anchored(&app, e, origin, rotation);
+
+        app.world
+            .get_mut::<VoxelEdits<TestVoxel>>(e)
+            .unwrap()
+            .0
+            .extend(vec![
+                (IVec::new(0, 0, 0), TestVoxel::Empty),
+                (IVec::new(2, 1, 0), TestVoxel::Hull),
+                (IVec::new(1, 0, 0), TestVoxel::Armor),
+            ]);
+        app.update();
+        assert_anchored(&app, e, origin, rotation);
+        assert_eq!(app.world.get::<Mass>(e).unwrap().0, 5);
+        assert_eq!(
+            voxels(&*app.world.get::<BBOctreeSet>(e).unwrap()),
+            vec![IVec::new(1, 0, 0), IVec::new(2, 0, 0), IVec::new(2, 1, 0)]
+        );
+        let storage = app.world.get::<ChunkStorage<TestVoxel>>(e).unwrap();
+        assert_eq!(*storage.get(IVec::new(0, 0, 0)), TestVoxel::Empty);
+        assert_eq!(*storage.get(IVec::new(1, 0, 0)), TestVoxel::Armor);
+        // The mass properties are the same as those of the edited voxels.
+        let properties = MassProperties::from_voxels(
+            storage.for_each_map(|(position, voxel)| (position, voxel.mass())),
+        );
+        assert_eq!(app.world.get::<Inertia>(e).unwrap().0, properties.inertia);
+        assert_eq!(
+            app.world.get::<CenterOfMass>(e).unwrap().0,
+            properties.center_of_mass()
+        );
+        assert!(app
+            .world
+            .get::<VoxelEdits<TestVoxel>>(e)
+            .unwrap()
+            .0
+            .is_empty());
+    }
+
+    #[test]
+    fn test_edits_inserted_filled() {
+        let mut app = init_app();
+        let mut storage = ChunkStorage::new(TestVoxel::Empty, 16);
+        *storage.get_mut(IVec::zero()).get_mut() = TestVoxel::Hull;
+        let e = spawn_storage_body(&mut app, FVec::zero(), Rot::identity(), storage);
+        let edits = VoxelEdits(vec![(IVec::unit_x(), TestVoxel::Armor)]);
+        app.world.insert_one(e, edits).unwrap();
+        app.update();
+        assert_eq!(app.world.get::<Mass>(e).unwrap().0, 4);
+        assert_eq!(
+            voxels(&*app.world.get::<BBOctreeSet>(e).unwrap()),
+            vec![IVec::zero(), IVec::unit_x()]
+        );
+    }
+
+    #[test]
+    fn test_repeated_edits_keep_pose() {
+        let mut app = init_app();
+        let mut storage = ChunkStorage::new(TestVoxel::Empty, 16);
+        for x in 0..3 {
+            *storage.get_mut(IVec::new(x, 0, 0)).get_mut() = TestVoxel::Hull;
+        }
+        let origin = FVec::new(1.1, -2.3, 3.7);
+        let rotation = Rot::from_rotation_xy(0.7) * Rot::from_rotation_yz(0.3);
+        let e = spawn_storage_body(&mut app, origin, rotation, storage);
+        app.world
+            .insert_one(e, VoxelEdits::<TestVoxel>::default())
+            .unwrap();
+        let position = app.world.get::<Position>(e).unwrap().0;
+        for i in 0..200 {
+            let voxel = if i % 2 == 0 {
+                TestVoxel::Armor
+            } else {
+                TestVoxel::Empty
+            };
+            app.world
+                .get_mut::<VoxelEdits<TestVoxel>>(e)
+                .unwrap()
+                .0
+                .push((IVec::new((i / 2) % 5, 1, 0), voxel));
+            app.update();
+            assert_anchored(&app, e, origin, rotation);
+        }
+        // Every added voxel has been removed again.
+        assert_eq!(app.world.get::<Mass>(e).unwrap().0, 3);
+        assert_eq!(app.world.get::<Position>(e).unwrap().0, position);
+    }
+}
@@ -0,0 +1,195 @@
+//! Explosions, which push bodies away from a point and destroy the voxels
+//! around it.
+use super::edit::edit_voxels;
+use super::mass_properties::MassVoxel;
+use super::*;
+use crate::octree::EditableOctreeSet;
+use crate::storage::VoxelStorage;
+
+/// An explosion at a point in the world. Sending one of these is all that a
+/// weapon needs to do.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Explosion {
+    pub center: FVec,
+    /// The impulse given to a body at the center, falling off linearly to
+    /// nothing at `blast_radius`.
+    pub impulse: f32,
+    pub blast_radius: f32,
+    /// The radius within which voxels are destroyed.
+    pub damage_radius: f32,
+}
+
+/// Applies every explosion to the bodies with a `Storage` and its collider
+/// `Set`.
+/// A body is pushed at its voxel closest to the explosion, away from the
+/// explosion, so that explosions off to the side of a body turn it. Distances
+/// are measured to the `voxel_point` of each voxel, and the voxels within the
+/// damage radius are replaced with the ambient voxel of the storage through
+/// `edit_voxels`. Bodies without `Momentum` are not pushed, and bodies without
+/// `ChangedBodies` keep their mass.
+/// Every voxel of each body in the world is looked at, so explosions should be
+/// rare compared to ticks.
+/// This should be added to a stage before the one that the other parts of the
+/// bodies are updated from their storage in, such as `stage::PRE_UPDATE`, for
+/// the storage and collider types of the bodies.
+#[allow(clippy::type_complexity)]
+pub fn explode<Storage, Set>(
+    mut reader: Local<EventReader<Explosion>>,
+    events: Res<Events<Explosion>>,
+    mut query: Query<(
+        &mut Storage,
+        &mut Set,
+        &Position,
+        &Rotation,
+        &CenterOfMass,
+        Option<&mut Momentum>,
+        Option<&mut AngularMomentum>,
+        Option<&mut ChangedBodies>,
+    )>,
+) where
+    Storage: 'static + VoxelStorage<Position = IVec> + Send + Sync,
+    Storage::T: MassVoxel,
+    Set: 'static + EditableOctreeSet + Send + Sync, {
+    for explosion in reader.iter(&events) {
+        for (mut storage, mut set, p, r, com, m, am, mut changed) in query.iter_mut() {
+            // The explosion in voxel space.
+            let center = r.0.reversed() * (explosion.center - p.0) + com.0;
+            let mut closest: Option<(IVec, f32)> = None;
+            let mut destroyed = vec![];
+            storage.for_each(|(voxel, value)| {
+                let mass = value.mass();
+                if mass == 0 {
+                    return;
+                }
+                let distance = (voxel_point(voxel) - center).mag();
+                if closest.map_or(true, |(_, closest)| distance < closest) {
+                    closest = Some((voxel, distance));
+                }
+                if distance <= explosion.damage_radius {
+                    destroyed.push(voxel);
+                }
+            });
+            if let (Some((voxel, distance)), Some(mut m), Some(mut am)) = (closest, m, am) {
+                let point = voxel_to_world(voxel_point(voxel), p, r, com);
+                // An explosion right at the voxel pushes from the center of
+                // mass instead.
+                let offset = match point - explosion.center {
+                    offset if offset == FVec::zero() => p.0 - explosion.center,
+                    offset => offset,
+                };
+                let length = offset.mag();
+                if distance < explosion.blast_radius && length > 0.0 {
+                    let falloff = 1.0 - distance / explosion.blast_radius;
+                    let impulse = offset * (explosion.impulse * falloff / length);
+                    m.0 += impulse;
+                    am.0 += (point - p.0).cross(impulse);
+                }
+            }
+            if destroyed.is_empty() {
+                continue;
+            }
+            let ambient = storage.ambient();
+            edit_voxels(
+                &mut *storage,
+                &mut *set,
+                changed.as_deref_mut(),
+                destroyed.into_iter().map(|voxel| (voxel, ambient)),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::octree_set::BBOctreeSet;
+    use crate::octree::{OctreeNode, OctreeSet};
+    use crate::physics::test_util::{self, spawn_storage_body, TestVoxel};
+    use crate::storage::chunk_map::ChunkStorage;
+    use crate::storage::Writer;
+
+    fn init_app() -> App {
+        let mut app = test_util::init_app(0.1);
+        app.add_system_to_stage(
+            stage::PRE_UPDATE,
+            explode::<ChunkStorage<TestVoxel>, BBOctreeSet>.system(),
+        );
+        app.app
+    }
+
+    /// A row of 5 voxels along x, with its voxel origin at `position`, so
+    /// that the row's axis runs along x through `position`.
+    fn spawn_row(app: &mut App, position: FVec) -> Entity {
+        let mut storage = ChunkStorage::new(TestVoxel::Empty, 16);
+        for x in 0..5 {
+            *storage.get_mut(IVec::new(x, 0, 0)).get_mut() = TestVoxel::Hull;
+        }
+        spawn_storage_body(app, position, Rot::identity(), storage)
+    }
+
+    fn volume(set: &BBOctreeSet) -> u64 {
+        let mut volume = 0;
+        set.for_each_full(|node| volume += node.size().pow(3));
+        volume
+    }
+
+    #[test]
+    fn test_explosion() {
+        let mut app = init_app();
+        let near = spawn_row(&mut app, FVec::zero());
+        let far = spawn_row(&mut app, FVec::new(100.0, 0.0, 0.0));
+        app.update();
+        app.resources
+            .get_mut::<Events<Explosion>>()
+            .unwrap()
+            .send(Explosion {
+                center: FVec::new(-1.0, 0.0, 0.0),
+                impulse: 10.0,
+                blast_radius: 10.0,
+                damage_radius: 1.6,
+            });
+        app.update();
+        // Only the end of the row was destroyed.
+        let storage = app.world.get::<ChunkStorage<TestVoxel>>(near).unwrap();
+        assert_eq!(*storage.get(IVec::zero()), TestVoxel::Empty);
+        assert_eq!(*storage.get(IVec::unit_x()), TestVoxel::Hull);
+        assert_eq!(volume(&*app.world.get::<BBOctreeSet>(near).unwrap()), 4);
+        assert_eq!(app.world.get::<Mass>(near).unwrap().0, 4);
+        // It was pushed along its axis at the end, 1 from the explosion,
+        // without turning it.
+        let momentum = app.world.get::<Momentum>(near).unwrap().0;
+        assert!((momentum - FVec::new(9.0, 0.0, 0.0)).mag() < 0.001);
+        assert!(app.world.get::<AngularMomentum>(near).unwrap().0.mag() < 0.001);
+
+        assert_eq!(app.world.get::<Momentum>(far).unwrap().0, FVec::zero());
+        assert_eq!(app.world.get::<Mass>(far).unwrap().0, 5);
+    }
+
+    #[test]
+    fn test_off_axis_explosion() {
+        let mut app = init_app();
+        let e = spawn_row(&mut app, FVec::zero());
+        app.update();
+        app.resources
+            .get_mut::<Events<Explosion>>()
+            .unwrap()
+            .send(Explosion {
+                center: FVec::new(-1.0, 2.5, 0.0),
+                impulse: 10.0,
+                blast_radius: 10.0,
+                damage_radius: 1.0,
+            });
+        app.update();
+        assert_eq!(app.world.get::<Mass>(e).unwrap().0, 5);
+        // The end of the row is pushed away from the explosion, below the
+        // center of mass, turning the row around z.
+        let momentum = app.world.get::<Momentum>(e).unwrap().0;
+        assert!(momentum.x > 0.1 && momentum.y < -0.1);
+        let angular_momentum = app.world.get::<AngularMomentum>(e).unwrap().0;
+        assert!(angular_momentum.x.abs() < 0.001 && angular_momentum.y.abs() < 0.001);
+        assert!(angular_momentum.z > 0.1);
+        app.update();
+        let end = app.world.get::<Rotation>(e).unwrap().0 * FVec::unit_x();
+        assert!(end.y > 0.001);
+    }
+}
@@ -0,0 +1,316 @@
+//! Forces that the plugin applies to bodies on its own every step, such as
+//! gravity and the drag of a nebula.
+use super::*;
+
+/// Settings shared by every field.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct FieldSettings {
+    /// Scales the pull of every `GravityWell`.
+    pub gravitational_constant: f32,
+}
+impl Default for FieldSettings {
+    fn default() -> Self {
+        FieldSettings {
+            gravitational_constant: 1.0,
+        }
+    }
+}
+
+/// The region of world space that a `ForceField` acts within.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FieldBounds {
+    Everywhere,
+    Box { min: FVec, max: FVec },
+    Sphere { center: FVec, radius: f32 },
+}
+
+impl FieldBounds {
+    pub fn contains(&self, point: FVec) -> bool {
+        match *self {
+            FieldBounds::Everywhere => true,
+            FieldBounds::Box { min, max } => {
+                point.x >= min.x
+                    && point.y >= min.y
+                    && point.z >= min.z
+                    && point.x <= max.x
+                    && point.y <= max.y
+                    && point.z <= max.z
+            }
+            FieldBounds::Sphere { center, radius } => (point - center).mag_sq() <= radius * radius,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FieldKind {
+    /// Accelerates every body equally, like the gravity near the surface of a
+    /// planet.
+    Acceleration(FVec),
+    /// Pulls the velocity of every part of a body towards the velocity of the
+    /// surrounding medium. A still medium, such as a nebula, has a `flow` of
+    /// zero, and a current has the velocity it carries bodies along with.
+    /// `coefficient` is the fraction of the relative velocity lost per second,
+    /// and should be well below `2 / timestep`.
+    /// As the parts of a spinning body move through the medium at different
+    /// velocities, this also slows down its spin.
+    Drag { flow: FVec, coefficient: f32 },
+}
+
+/// A field that acts on every body whose center of mass is within its bounds.
+/// Fields are entities of their own, so that a map can have any number of
+/// them.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ForceField {
+    pub bounds: FieldBounds,
+    pub kind: FieldKind,
+}
+
+/// Pulls every body towards the `Position` of this entity, with a force that
+/// falls off with the square of the distance. This can be added to a body, such
+/// as a planet, which then pulls every other body. Bodies are not pulled back
+/// unless they have a well of their own.
+/// `softening` keeps the pull finite at the center of the well, as if its mass
+/// were spread out over about that radius.
+/// As the pull is stronger on the near side of a body, it also turns long
+/// bodies to point towards the well.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct GravityWell {
+    pub mass: f32,
+    pub softening: f32,
+}
+
+/// Adds the force and torque of every field and gravity well to the bodies
+/// they act on. Sleeping bodies are not affected, so that bodies resting on
+/// something in a field stay asleep.
+/// This is added to the end of the "pre-physics" stage, after the mass
+/// properties are recomputed. Like thrust, this only runs on the first substep
+/// of each step.
+#[allow(clippy::type_complexity)]
+pub fn apply_force_fields(
+    substep: Res<Substep>,
+    settings: Res<FieldSettings>,
+    pool: Res<ComputeTaskPool>,
+    fields: Query<&ForceField>,
+    wells: Query<(Entity, &GravityWell, &Position)>,
+    mut bodies: Query<
+        (
+            Entity,
+            &Position,
+            &Rotation,
+            &Mass,
+            &InertiaAroundCenterOfMass,
+            &Momentum,
+            &AngularMomentum,
+            &mut Force,
+            &mut Torque,
+        ),
+        (Without<Static>, Without<Kinematic>, Without<Sleeping>),
+    >,
+) {
+    if !substep.is_first() {
+        return;
+    }
+    let fields = fields.iter().copied().collect::<Vec<_>>();
+    let wells = wells
+        .iter()
+        .map(|(e, well, p)| (e, *well, p.0))
+        .collect::<Vec<_>>();
+    if fields.is_empty() && wells.is_empty() {
+        return;
+    }
+    let gravitational_constant = settings.gravitational_constant;
+    bodies.par_iter_mut(64).for_each(
+        &pool.0,
+        |(e, p, r, m, iacom, momentum, am, mut f, mut t)| {
+            let mass = m.0 as f32;
+            for field in fields.iter() {
+                if !field.bounds.contains(p.0) {
+                    continue;
+                }
+                match field.kind {
+                    FieldKind::Acceleration(acceleration) => f.0 += acceleration * mass,
+                    FieldKind::Drag { flow, coefficient } => {
+                        // Summed over the body, the drag on each part comes
+                        // to the drag on the momentum and angular momentum.
+                        f.0 += coefficient * (flow * mass - momentum.0);
+                        t.0 -= coefficient * am.0;
+                    }
+                }
+            }
+            if wells.is_empty() {
+                return;
+            }
+            let rot_mat = r.0.into_matrix();
+            let inertia = rot_mat * iacom.0 * rot_mat.transposed();
+            for &(well_entity, well, well_position) in wells.iter() {
+                if well_entity == e {
+                    continue;
+                }
+                let delta = p.0 - well_position;
+                let distance_sq = delta.mag_sq() + well.softening * well.softening;
+                if distance_sq == 0.0 {
+                    continue;
+                }
+                let strength =
+                    gravitational_constant * well.mass / (distance_sq * distance_sq.sqrt());
+                f.0 -= delta * (strength * mass);
+                // The gravity gradient torque, from the difference in pull
+                // across the body.
+                t.0 += delta.cross(inertia * delta) * (3.0 * strength / distance_sq);
+            }
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::test_util::*;
+
+    #[test]
+    fn test_uniform_acceleration() {
+        let mut app = init_app(0.1).app;
+        app.world.spawn((ForceField {
+            bounds: FieldBounds::Everywhere,
+            kind: FieldKind::Acceleration(FVec::new(0.0, -1.0, 0.0)),
+        },));
+        let e = spawn_body(
+            &mut app,
+            FVec::zero(),
+            FVec::zero(),
+            &[IVec::zero(), IVec::unit_x()],
+        );
+        for _ in 0..10 {
+            app.update();
+        }
+        assert_close(
+            app.world.get::<Momentum>(e).unwrap().0,
+            FVec::new(0.0, -2.0, 0.0),
+            0.01,
+        );
+        assert!((app.world.get::<Position>(e).unwrap().0.y + 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_bounded_drag() {
+        let mut app = init_app(0.1).app;
+        app.world.spawn((ForceField {
+            bounds: FieldBounds::Sphere {
+                center: FVec::zero(),
+                radius: 2.0,
+            },
+            kind: FieldKind::Drag {
+                flow: FVec::zero(),
+                coefficient: 1.0,
+            },
+        },));
+        let inside = spawn_body(&mut app, FVec::zero(), FVec::unit_x(), &[IVec::zero()]);
+        let outside = spawn_body(
+            &mut app,
+            FVec::new(10.0, 0.0, 0.0),
+            FVec::unit_x(),
+            &[IVec::zero()],
+        );
+        for _ in 0..5 {
+            app.update();
+        }
+        let slowed = app.world.get::<Momentum>(inside).unwrap().0.x;
+        assert!(slowed > 0.5 && slowed < 0.7);
+        assert_eq!(
+            app.world.get::<Momentum>(outside).unwrap().0,
+            FVec::unit_x()
+        );
+    }
+
+    #[test]
+    fn test_current() {
+        let mut app = init_app(0.1).app;
+        app.world.spawn((ForceField {
+            bounds: FieldBounds::Box {
+                min: FVec::broadcast(-100.0),
+                max: FVec::broadcast(100.0),
+            },
+            kind: FieldKind::Drag {
+                flow: FVec::unit_y(),
+                coefficient: 1.0,
+            },
+        },));
+        let e = spawn_body(&mut app, FVec::zero(), FVec::zero(), &[IVec::zero()]);
+        app.world.get_mut::<AngularMomentum>(e).unwrap().0 = FVec::unit_z();
+        for _ in 0..100 {
+            app.update();
+        }
+        // The body is carried along with the current, and stops spinning.
+        assert_close(
+            app.world.get::<Momentum>(e).unwrap().0,
+            FVec::unit_y(),
+            0.01,
+        );
+        assert_close(
+            app.world.get::<AngularMomentum>(e).unwrap().0,
+            FVec::zero(),
+            0.01,
+        );
+    }
+
+    #[test]
+    fn test_gravity_well_orbit() {
+        let mut app = init_app(0.01).app;
+        app.world.spawn((
+            GravityWell {
+                mass: 100.0,
+                softening: 0.0,
+            },
+            Position(FVec::zero()),
+        ));
+        // The speed of a circular orbit is sqrt(GM / r).
+        let e = spawn_body(
+            &mut app,
+            FVec::new(10.0, 0.0, 0.0),
+            FVec::new(0.0, 10.0f32.sqrt(), 0.0),
+            &[IVec::zero()],
+        );
+        let mut min_x: f32 = 10.0;
+        // About one orbit.
+        for _ in 0..2000 {
+            app.update();
+            let position = app.world.get::<Position>(e).unwrap().0;
+            assert!((position.mag() - 10.0).abs() < 0.5);
+            min_x = min_x.min(position.x);
+        }
+        // The body went around to the other side.
+        assert!(min_x < -9.5);
+    }
+
+    #[test]
+    fn test_gravity_gradient_torque() {
+        let mut app = init_app(0.1).app;
+        app.world.spawn((
+            GravityWell {
+                mass: 1000.0,
+                softening: 1.0,
+            },
+            Position(FVec::new(-10.0, -10.0, 0.0)),
+        ));
+        // A rod along x, which turns towards the well, around +z.
+        let rod = spawn_body(
+            &mut app,
+            FVec::zero(),
+            FVec::zero(),
+            &[-IVec::unit_x(), IVec::zero(), IVec::unit_x()],
+        );
+        // A single voxel, which has no long axis to turn.
+        let cube = spawn_body(
+            &mut app,
+            FVec::new(0.0, 0.0, 5.0),
+            FVec::zero(),
+            &[IVec::zero()],
+        );
+        app.update();
+        assert!(app.world.get::<AngularMomentum>(rod).unwrap().0.z > 0.0);
+        assert_eq!(
+            app.world.get::<AngularMomentum>(cube).unwrap().0,
+            FVec::zero()
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! Flight computers, which fire the thrusters of a ship to follow a commanded
+//! motion, so that players need not control each thruster.
+use super::thruster::{Thruster, Thrusters};
+use super::*;
+
+/// The sweeps over the thrusters that `allocate_thrust` makes.
+const ALLOCATION_ITERATIONS: usize = 64;
+/// How strongly `allocate_thrust` prefers lower levels, relative to the effect
+/// of each thruster, when several sets of levels do equally well.
+const ALLOCATION_REGULARIZATION: f32 = 0.0001;
+
+/// What a flight computer should do, in world space.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FlightCommand {
+    /// Accelerate at these rates.
+    Accelerate { linear: FVec, angular: FVec },
+    /// Reach and hold these velocities, accelerating towards them as hard as
+    /// the thrusters allow.
+    Hold {
+        velocity: FVec,
+        angular_velocity: FVec,
+    },
+}
+impl Default for FlightCommand {
+    fn default() -> Self {
+        FlightCommand::Hold {
+            velocity: FVec::zero(),
+            angular_velocity: FVec::zero(),
+        }
+    }
+}
+
+/// Fires the `Thrusters` of a ship to follow a command. A ship with a flight
+/// computer ignores its `Throttle`.
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct FlightComputer {
+    pub command: FlightCommand,
+    /// The level of each thruster, from 0 to 1, in the order of `Thrusters`.
+    pub levels: Vec<f32>,
+    /// The linear acceleration that was commanded but could not be reached
+    /// with the thrusters, in world space.
+    pub linear_residual: FVec,
+    /// The angular acceleration that was commanded but could not be reached
+    /// with the thrusters, in world space.
+    pub angular_residual: FVec,
+}
+
+/// Finds the levels of the thrusters, from 0 to 1, whose accelerations come
+/// closest to the given ones in the least squares sense.
+/// Everything is in voxel space, with `center_of_mass` and
+/// `inertia_around_center_of_mass` as in the body's components.
+/// Returns the levels, and the linear and angular accelerations that they fall
+/// short by.
+/// This is solved by projected coordinate descent, which always converges as
+/// the problem is convex, and is cheap for the tens of thrusters a ship has.
+pub fn allocate_thrust(
+    thrusters: &[(IVec, Thruster)],
+    center_of_mass: FVec,
+    mass: f32,
+    inertia_around_center_of_mass: FMat,
+    linear: FVec,
+    angular: FVec,
+) -> (Vec<f32>, FVec, FVec) {
+    let mut levels = vec![0.0; thrusters.len()];
+    if mass == 0.0 {
+        return (levels, linear, angular);
+    }
+    let inv_inertia = inertia_around_center_of_mass.inversed();
+    // The accelerations of each thruster at full throttle.
+    let effects = thrusters
+        .iter()
+        .map(|(voxel, thruster)| {
+            let offset = voxel_point(*voxel) - center_of_mass;
+            let force = thruster.direction * thruster.max_thrust;
+            (force / mass, inv_inertia * offset.cross(force))
+        })
+        .collect::<Vec<_>>();
+    // The accelerations reached so far, minus the commanded ones.
+    let mut linear_error = -linear;
+    let mut angular_error = -angular;
+    for _ in 0..ALLOCATION_ITERATIONS {
+        for (level, &(l, a)) in levels.iter_mut().zip(effects.iter()) {
+            let norm = l.mag_sq() + a.mag_sq();
+            if norm == 0.0 {
+                continue;
+            }
+            let regularization = ALLOCATION_REGULARIZATION * norm;
+            let gradient = l.dot(linear_error) + a.dot(angular_error) + regularization * *level;
+            let new = (*level - gradient / (norm + regularization))
+                .max(0.0)
+                .min(1.0);
+            linear_error += l * (new - *level);
+            angular_error += a * (new - *level);
+            *level = new;
+        }
+    }
+    (levels, -linear_error, -angular_error)
+}
+
+/// Runs the flight computer of every ship with thrusters.
+/// This is added to the "pre-physics" stage, before the thrust is applied, and
+/// only runs on the first substep of each step.
+#[allow(clippy::type_complexity)]
+pub fn run_flight_computers(
+    timestep: Res<Timestep>,
+    substep: Res<Substep>,
+    mut query: Query<(
+        &Thrusters,
+        &Rotation,
+        &CenterOfMass,
+        &Mass,
+        &InertiaAroundCenterOfMass,
+        &InvMass,
+        &InvInertiaAroundCenterOfMass,
+        &Momentum,
+        &AngularMomentum,
+        &mut FlightComputer,
+    )>,
+) {
+    if !substep.is_first() {
+        return;
+    }
+    for (thrusters, r, com, m, iacom, im, iiacom, momentum, am, mut computer) in query.iter_mut() {
+        let (linear, angular) = match computer.command {
+            FlightCommand::Accelerate { linear, angular } => (linear, angular),
+            FlightCommand::Hold {
+                velocity,
+                angular_velocity,
+            } => (
+                (velocity - momentum.0 * im.0) / timestep.0,
+                (angular_velocity - world_inv_inertia(r, iiacom) * am.0) / timestep.0,
+            ),
+        };
+        let to_voxel_space = r.0.reversed();
+        let (levels, linear_residual, angular_residual) = allocate_thrust(
+            &thrusters.0,
+            com.0,
+            m.0 as f32,
+            iacom.0,
+            to_voxel_space * linear,
+            to_voxel_space * angular,
+        );
+        computer.levels = levels;
+        computer.linear_residual = r.0 * linear_residual;
+        computer.angular_residual = r.0 * angular_residual;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::thruster::Throttle;
+    use super::*;
+    use crate::physics::test_util::*;
+
+    fn thruster(direction: FVec) -> Thruster {
+        Thruster {
+            max_thrust: 1.0,
+            direction,
+        }
+    }
+
+    /// Two pairs of thrusters, above and below the center of mass, pushing
+    /// along x either way.
+    fn pairs() -> Vec<(IVec, Thruster)> {
+        vec![
+            (IVec::unit_y(), thruster(FVec::unit_x())),
+            (-IVec::unit_y(), thruster(-FVec::unit_x())),
+            (IVec::unit_y(), thruster(-FVec::unit_x())),
+            (-IVec::unit_y(), thruster(FVec::unit_x())),
+        ]
+    }
+
+    #[test]
+    fn test_allocate_turn() {
+        let (levels, linear, angular) = allocate_thrust(
+            &pairs(),
+            FVec::zero(),
+            1.0,
+            FMat::identity(),
+            FVec::zero(),
+            -FVec::unit_z(),
+        );
+        // Only the pair that turns around -z fires, and it cancels out along x.
+        for (level, expected) in levels.iter().zip([0.5, 0.5, 0.0, 0.0].iter()) {
+            assert!((level - expected).abs() < 0.001);
+        }
+        assert_close(linear, FVec::zero(), 0.001);
+        assert_close(angular, FVec::zero(), 0.001);
+    }
+
+    #[test]
+    fn test_allocate_residual() {
+        let (levels, linear, angular) = allocate_thrust(
+            &pairs(),
+            FVec::zero(),
+            1.0,
+            FMat::identity(),
+            FVec::new(3.0, 1.0, 0.0),
+            FVec::zero(),
+        );
+        // Two thrusters can only push at 2 along x, and none along y.
+        for (level, expected) in levels.iter().zip([1.0, 0.0, 0.0, 1.0].iter()) {
+            assert!((level - expected).abs() < 0.001);
+        }
+        assert_close(linear, FVec::new(1.0, 1.0, 0.0), 0.001);
+        assert_close(angular, FVec::zero(), 0.001);
+    }
+
+    #[test]
+    fn test_hold_velocity() {
+        let mut app = init_app(0.1).app;
+        let e = app.world.spawn(PhysicsBundle::new(
+            FVec::zero(),
+            Rot::from_rotation_xy(0.5),
+            FVec::zero(),
+            vec![(IVec::zero(), 1)],
+        ));
+        let velocity = FVec::new(0.0, 0.5, 0.5);
+        let thrusters = [FVec::unit_x(), FVec::unit_y(), FVec::unit_z()]
+            .iter()
+            .flat_map(|&d| vec![(IVec::zero(), thruster(d)), (IVec::zero(), thruster(-d))])
+            .collect::<Vec<_>>();
+        app.world
+            .insert(
+                e,
+                (
+                    Thrusters(thrusters),
+                    // This is ignored.
+                    Throttle(FVec::unit_x()),
+                    FlightComputer {
+                        command: FlightCommand::Hold {
+                            velocity,
+                            angular_velocity: FVec::zero(),
+                        },
+                        ..Default::default()
+                    },
+                ),
+            )
+            .unwrap();
+        for _ in 0..20 {
+            app.update();
+        }
+        assert_close(app.world.get::<Momentum>(e).unwrap().0, velocity, 0.001);
+        let computer = app.world.get::<FlightComputer>(e).unwrap();
+        assert_close(computer.linear_residual, FVec::zero(), 0.001);
+        assert_eq!(app.world.get::<AngularMomentum>(e).unwrap().0, FVec::zero());
+    }
+}
@@ -0,0 +1,225 @@
+//! Simulation islands, which are groups of bodies that touch each other
+//! directly or through other bodies.
+//!
+//! No constraint connects bodies from two different islands, so each island
+//! can be solved on its own. Infinitely heavy bodies, such as the ground, are
+//! never changed by the solver, so they do not join the islands of the bodies
+//! touching them. Each island is solved in the same order whatever thread it
+//! runs on, so the result does not depend on the number of threads, and is the
+//! same as solving every constraint together.
+use super::solver::*;
+use bevy::tasks::TaskPool;
+
+/// A constraint between two bodies of a slice of `SolverBody`s.
+pub trait Constraint {
+    /// The indices of the two bodies.
+    fn bodies(&self) -> (usize, usize);
+    fn set_bodies(&mut self, a: usize, b: usize);
+}
+
+impl Constraint for Contact {
+    fn bodies(&self) -> (usize, usize) {
+        (self.a, self.b)
+    }
+
+    fn set_bodies(&mut self, a: usize, b: usize) {
+        self.a = a;
+        self.b = b;
+    }
+}
+
+/// The indices of the bodies and constraints of an island, in increasing
+/// order.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Island {
+    pub bodies: Vec<usize>,
+    pub constraints: Vec<usize>,
+}
+
+fn find(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+/// Splits the bodies that have constraints into islands, ordered by their
+/// first constraint. Infinitely heavy bodies can be in several islands.
+pub fn islands<C: Constraint>(bodies: &[SolverBody], constraints: &[C]) -> Vec<Island> {
+    let dynamic = |i: usize| bodies[i].inv_mass != 0.0;
+    let mut parents = (0..bodies.len()).collect::<Vec<_>>();
+    for constraint in constraints {
+        let (a, b) = constraint.bodies();
+        if dynamic(a) && dynamic(b) {
+            let (a, b) = (find(&mut parents, a), find(&mut parents, b));
+            // The smaller index is kept as the root, so that the islands do
+            // not depend on the order of the constraints.
+            parents[a.max(b)] = a.min(b);
+        }
+    }
+    let mut island_of = vec![None; bodies.len()];
+    let mut islands: Vec<Island> = vec![];
+    for (i, constraint) in constraints.iter().enumerate() {
+        let (a, b) = constraint.bodies();
+        let root = find(&mut parents, if dynamic(a) { a } else { b });
+        let island = *island_of[root].get_or_insert_with(|| {
+            islands.push(Island {
+                bodies: vec![],
+                constraints: vec![],
+            });
+            islands.len() - 1
+        });
+        let island = &mut islands[island];
+        island.constraints.push(i);
+        island.bodies.push(a);
+        island.bodies.push(b);
+    }
+    for island in islands.iter_mut() {
+        island.bodies.sort_unstable();
+        island.bodies.dedup();
+    }
+    islands
+}
+
+/// Solves the constraints of every island in parallel on the `pool`, with
+/// `solve`, which is given the bodies and constraints of one island at a time.
+/// The bodies of the constraints are changed as if `solve` was given all of
+/// them at once.
+pub fn solve_islands<C, F>(
+    pool: &TaskPool,
+    bodies: &mut [SolverBody],
+    constraints: &mut [C],
+    solve: F,
+) where
+    C: 'static + Constraint + Clone + Send + Sync,
+    F: Fn(&mut [SolverBody], &mut [C]) + Send + Sync, {
+    let islands = islands(bodies, constraints);
+    if islands.len() < 2 {
+        solve(bodies, constraints);
+        return;
+    }
+    let solve = &solve;
+    let solved = pool.scope(|s| {
+        for island in islands.iter() {
+            // The bodies and constraints of the island, with the constraints
+            // pointing into the island's bodies.
+            let mut island_bodies = island.bodies.iter().map(|&i| bodies[i]).collect::<Vec<_>>();
+            let local = |i: usize| island.bodies.binary_search(&i).unwrap();
+            let mut island_constraints = island
+                .constraints
+                .iter()
+                .map(|&i| {
+                    let mut constraint = constraints[i].clone();
+                    let (a, b) = constraint.bodies();
+                    constraint.set_bodies(local(a), local(b));
+                    constraint
+                })
+                .collect::<Vec<_>>();
+            s.spawn(async move {
+                solve(&mut island_bodies, &mut island_constraints);
+                (island_bodies, island_constraints)
+            });
+        }
+    });
+    for (island, (island_bodies, island_constraints)) in islands.iter().zip(solved) {
+        for (&i, body) in island.bodies.iter().zip(island_bodies) {
+            // Infinitely heavy bodies can be in several islands, but are not
+            // changed by any of them.
+            if body.inv_mass != 0.0 {
+                bodies[i] = body;
+            }
+        }
+        for (&i, mut constraint) in island.constraints.iter().zip(island_constraints) {
+            let (a, b) = constraints[i].bodies();
+            constraint.set_bodies(a, b);
+            constraints[i] = constraint;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::*;
+    use bevy::tasks::TaskPoolBuilder;
+
+    fn ball(x: f32, velocity: FVec) -> SolverBody {
+        SolverBody::new(
+            FVec::new(x, 0.0, 0.0),
+            velocity,
+            FVec::new(0.0, x, 1.0),
+            1.0,
+            FMat::from_scale(6.0),
+        )
+    }
+
+    fn ground() -> SolverBody {
+        SolverBody::new(
+            FVec::new(0.0, -1.0, 0.0),
+            FVec::zero(),
+            FVec::zero(),
+            0.0,
+            FMat::from_scale(0.0),
+        )
+    }
+
+    /// Two stacks of balls resting on the same ground, far from each other,
+    /// and a ball on its own.
+    fn scene() -> (Vec<SolverBody>, Vec<Contact>) {
+        let bodies = vec![
+            ground(),
+            ball(-10.0, FVec::new(0.0, -1.0, 0.0)),
+            ball(10.0, FVec::new(1.0, -2.0, 0.0)),
+            ball(-10.0, FVec::new(0.5, -3.0, 0.0)),
+            ball(10.0, FVec::new(0.0, 1.0, 0.5)),
+            ball(30.0, FVec::zero()),
+        ];
+        let contact = |a, b, x, y| Contact::new(a, b, FVec::new(x, y, 0.0), FVec::unit_y(), 0.05);
+        let contacts = vec![
+            contact(1, 0, -10.0, -0.5),
+            contact(4, 2, 10.0, 0.5),
+            contact(3, 1, -10.0, 0.5),
+            contact(2, 0, 10.0, -0.5),
+            contact(1, 0, -9.5, -0.5),
+        ];
+        (bodies, contacts)
+    }
+
+    #[test]
+    fn test_islands() {
+        let (bodies, contacts) = scene();
+        assert_eq!(
+            islands(&bodies, &contacts),
+            vec![
+                Island {
+                    bodies: vec![0, 1, 3],
+                    constraints: vec![0, 2, 4],
+                },
+                Island {
+                    bodies: vec![0, 2, 4],
+                    constraints: vec![1, 3],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_thread_count() {
+        let settings = ContactSettings::default();
+        let (mut expected_bodies, mut expected_contacts) = scene();
+        solve(&mut expected_bodies, &mut expected_contacts, &settings, 0.1);
+        for &threads in [1, 2, 4].iter() {
+            let pool = TaskPoolBuilder::new().num_threads(threads).build();
+            let (mut bodies, mut contacts) = scene();
+            solve_islands(&pool, &mut bodies, &mut contacts, |bodies, contacts| {
+                solve(bodies, contacts, &settings, 0.1)
+            });
+            assert_eq!(contacts, expected_contacts);
+            // The ground gathers the impulses of both islands when solved all
+            // at once, but the solver never changes how it moves.
+            assert_eq!(bodies[1..], expected_bodies[1..]);
+            assert_eq!(bodies[0].velocity, FVec::zero());
+        }
+    }
+}
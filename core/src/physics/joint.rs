@@ -0,0 +1,589 @@
+use super::island::{solve_islands, Constraint};
+use super::solver::*;
+use super::*;
+use std::collections::HashMap;
+
+/// A joint between two bodies.
+/// Joints are entities of their own, so that a body can have any number of
+/// them. A joint whose bodies no longer exist is ignored.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Joint {
+    pub a: Entity,
+    pub b: Entity,
+    /// Where the joint is attached to `a`, in the voxel space of `a`.
+    pub a_anchor: FVec,
+    /// Where the joint is attached to `b`, in the voxel space of `b`.
+    pub b_anchor: FVec,
+    pub kind: JointKind,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum JointKind {
+    /// Keeps the anchors together and the rotation of `b` fixed relative to
+    /// `a`, so that the rotation of `b` is the rotation of `a` times
+    /// `relative_rotation`.
+    Fixed { relative_rotation: Rot },
+    /// Keeps the anchors together, only allowing rotation around an axis.
+    Revolute(Hinge),
+    /// Keeps the anchors together, allowing any rotation.
+    Spherical,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Hinge {
+    /// The axis of the hinge in the voxel space of `a`.
+    pub a_axis: FVec,
+    /// The axis of the hinge in the voxel space of `b`.
+    pub b_axis: FVec,
+    /// A direction perpendicular to the axis in the voxel space of `a`.
+    /// The hinge angle is zero when this lines up with `b_reference`.
+    pub a_reference: FVec,
+    /// A direction perpendicular to the axis in the voxel space of `b`.
+    pub b_reference: FVec,
+    /// The smallest and largest angle of `b` around the axis relative to `a`.
+    pub limits: Option<(f32, f32)>,
+    pub motor: Option<Motor>,
+}
+
+/// Drives a hinge towards an angular velocity.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Motor {
+    /// The angular velocity of `b` around the axis relative to `a`.
+    pub target_velocity: f32,
+    /// The largest torque that the motor can apply.
+    pub max_torque: f32,
+}
+
+/// How joints are solved.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct JointSettings {
+    /// The number of passes the solver makes over the joints.
+    pub iterations: usize,
+    /// The fraction of the separation of each joint that is removed every
+    /// timestep.
+    pub position_correction: f32,
+}
+impl Default for JointSettings {
+    fn default() -> Self {
+        JointSettings {
+            iterations: 8,
+            position_correction: 0.2,
+        }
+    }
+}
+
+/// The direction that a row of a joint constrains.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Direction {
+    /// The velocity between the anchors along a direction.
+    Linear {
+        a_point: FVec,
+        b_point: FVec,
+        direction: FVec,
+    },
+    /// The angular velocity between the bodies along a direction.
+    Angular(FVec),
+}
+
+/// A single constraint on the velocity of `a` relative to `b`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct Row {
+    a: usize,
+    b: usize,
+    direction: Direction,
+    mass: f32,
+    /// The relative velocity that the row aims for.
+    target_velocity: f32,
+    /// The relative velocity that removes the separation of the joint.
+    bias_target_velocity: f32,
+    /// The bounds of the total impulse.
+    bounds: (f32, f32),
+    /// The bounds of the total bias impulse.
+    bias_bounds: (f32, f32),
+    impulse: f32,
+    bias_impulse: f32,
+}
+
+impl Row {
+    fn new(bodies: &mut [SolverBody], a: usize, b: usize, direction: Direction) -> Self {
+        let (body_a, body_b) = pair(bodies, a, b);
+        let inv_mass = match direction {
+            Direction::Linear {
+                a_point,
+                b_point,
+                direction,
+            } => {
+                body_a.inv_effective_mass(a_point, direction)
+                    + body_b.inv_effective_mass(b_point, direction)
+            }
+            Direction::Angular(direction) => {
+                body_a.inv_angular_mass(direction) + body_b.inv_angular_mass(direction)
+            }
+        };
+        Row {
+            a,
+            b,
+            direction,
+            mass: if inv_mass == 0.0 { 0.0 } else { 1.0 / inv_mass },
+            target_velocity: 0.0,
+            bias_target_velocity: 0.0,
+            bounds: (f32::NEG_INFINITY, f32::INFINITY),
+            bias_bounds: (f32::NEG_INFINITY, f32::INFINITY),
+            impulse: 0.0,
+            bias_impulse: 0.0,
+        }
+    }
+
+    fn velocity(&self, a: &SolverBody, b: &SolverBody, bias: bool) -> f32 {
+        match self.direction {
+            Direction::Linear {
+                a_point,
+                b_point,
+                direction,
+            } => {
+                let (a_velocity, b_velocity) = if bias {
+                    (a.bias_velocity_at(a_point), b.bias_velocity_at(b_point))
+                } else {
+                    (a.velocity_at(a_point), b.velocity_at(b_point))
+                };
+                (a_velocity - b_velocity).dot(direction)
+            }
+            Direction::Angular(direction) => {
+                let (a_velocity, b_velocity) = if bias {
+                    (a.bias_angular_velocity, b.bias_angular_velocity)
+                } else {
+                    (a.angular_velocity, b.angular_velocity)
+                };
+                (a_velocity - b_velocity).dot(direction)
+            }
+        }
+    }
+
+    fn apply(&self, a: &mut SolverBody, b: &mut SolverBody, impulse: f32, bias: bool) {
+        match self.direction {
+            Direction::Linear {
+                a_point,
+                b_point,
+                direction,
+            } => {
+                let impulse = direction * impulse;
+                if bias {
+                    a.apply_bias_impulse(impulse, a_point);
+                    b.apply_bias_impulse(-impulse, b_point);
+                } else {
+                    a.apply_impulse(impulse, a_point);
+                    b.apply_impulse(-impulse, b_point);
+                }
+            }
+            Direction::Angular(direction) => {
+                let impulse = direction * impulse;
+                if bias {
+                    a.apply_bias_angular_impulse(impulse);
+                    b.apply_bias_angular_impulse(-impulse);
+                } else {
+                    a.apply_angular_impulse(impulse);
+                    b.apply_angular_impulse(-impulse);
+                }
+            }
+        }
+    }
+
+    fn solve(&mut self, bodies: &mut [SolverBody]) {
+        let (a, b) = pair(bodies, self.a, self.b);
+        let old_impulse = self.impulse;
+        self.impulse = (old_impulse
+            + (self.target_velocity - self.velocity(a, b, false)) * self.mass)
+            .max(self.bounds.0)
+            .min(self.bounds.1);
+        self.apply(a, b, self.impulse - old_impulse, false);
+
+        let old_bias_impulse = self.bias_impulse;
+        self.bias_impulse = (old_bias_impulse
+            + (self.bias_target_velocity - self.velocity(a, b, true)) * self.mass)
+            .max(self.bias_bounds.0)
+            .min(self.bias_bounds.1);
+        self.apply(a, b, self.bias_impulse - old_bias_impulse, true);
+    }
+}
+
+impl Constraint for Row {
+    fn bodies(&self) -> (usize, usize) {
+        (self.a, self.b)
+    }
+
+    fn set_bodies(&mut self, a: usize, b: usize) {
+        self.a = a;
+        self.b = b;
+    }
+}
+
+/// The world space pose of a body that a joint needs.
+#[derive(Copy, Clone)]
+struct Pose {
+    position: Position,
+    rotation: Rotation,
+    center_of_mass: CenterOfMass,
+}
+
+impl Pose {
+    fn world_point(&self, position: FVec) -> FVec {
+        voxel_to_world(
+            position,
+            &self.position,
+            &self.rotation,
+            &self.center_of_mass,
+        )
+    }
+}
+
+/// Creates the rows of a joint between the bodies at indices `a` and `b`.
+fn joint_rows(
+    joint: &Joint,
+    (a, a_pose): (usize, &Pose),
+    (b, b_pose): (usize, &Pose),
+    bodies: &mut [SolverBody],
+    bias: f32,
+    timestep: f32,
+) -> Vec<Row> {
+    let mut rows = vec![];
+    let a_point = a_pose.world_point(joint.a_anchor);
+    let b_point = b_pose.world_point(joint.b_anchor);
+    let separation = a_point - b_point;
+    for &direction in [FVec::unit_x(), FVec::unit_y(), FVec::unit_z()].iter() {
+        let mut row = Row::new(
+            bodies,
+            a,
+            b,
+            Direction::Linear {
+                a_point,
+                b_point,
+                direction,
+            },
+        );
+        row.bias_target_velocity = -bias * separation.dot(direction);
+        rows.push(row);
+    }
+    // Constrains the rotation of `b` relative to `a` to zero along each
+    // direction, where `error` is the current rotation vector of `b` relative
+    // to `a`.
+    let mut add_angular = |directions: &[FVec], error: FVec, bodies: &mut [SolverBody]| {
+        for &direction in directions {
+            let mut row = Row::new(bodies, a, b, Direction::Angular(direction));
+            row.bias_target_velocity = bias * error.dot(direction);
+            rows.push(row);
+        }
+    };
+    match joint.kind {
+        JointKind::Fixed { relative_rotation } => {
+            let target = a_pose.rotation.0 * relative_rotation;
+            let error = rotation_vector(b_pose.rotation.0 * target.reversed());
+            add_angular(
+                &[FVec::unit_x(), FVec::unit_y(), FVec::unit_z()],
+                error,
+                bodies,
+            );
+        }
+        JointKind::Revolute(hinge) => {
+            let a_axis = (a_pose.rotation.0 * hinge.a_axis).normalized();
+            let b_axis = (b_pose.rotation.0 * hinge.b_axis).normalized();
+            let [first, second] = tangents(a_axis);
+            add_angular(&[first, second], a_axis.cross(b_axis), bodies);
+
+            let a_reference = a_pose.rotation.0 * hinge.a_reference;
+            let b_reference = b_pose.rotation.0 * hinge.b_reference;
+            let angle = a_reference
+                .cross(b_reference)
+                .dot(a_axis)
+                .atan2(a_reference.dot(b_reference));
+            // The velocity of this row is the rate of change of the angle.
+            let direction = Direction::Angular(-a_axis);
+            if let Some((lower, upper)) = hinge.limits {
+                if angle < lower {
+                    let mut row = Row::new(bodies, a, b, direction);
+                    row.bias_target_velocity = bias * (lower - angle);
+                    row.bounds = (0.0, f32::INFINITY);
+                    row.bias_bounds = row.bounds;
+                    rows.push(row);
+                } else if angle > upper {
+                    let mut row = Row::new(bodies, a, b, direction);
+                    row.bias_target_velocity = bias * (upper - angle);
+                    row.bounds = (f32::NEG_INFINITY, 0.0);
+                    row.bias_bounds = row.bounds;
+                    rows.push(row);
+                }
+            }
+            if let Some(motor) = hinge.motor {
+                let mut row = Row::new(bodies, a, b, direction);
+                let max_impulse = motor.max_torque * timestep;
+                row.target_velocity = motor.target_velocity;
+                row.bounds = (-max_impulse, max_impulse);
+                row.bias_bounds = (0.0, 0.0);
+                rows.push(row);
+            }
+        }
+        JointKind::Spherical => {}
+    }
+    rows
+}
+
+/// Solves every joint, changing the momentum and pose of the jointed bodies.
+/// Bodies without a `Momentum` or `AngularMomentum` are treated as infinitely
+/// heavy. Joints between sleeping bodies are skipped until one of them is
+/// woken, and a joint with an awake body wakes the other.
+/// The joints of independent groups of bodies are solved in parallel.
+/// This is added to the "joints" stage of the physics schedule.
+#[allow(clippy::type_complexity)]
+pub fn solve_joints(
+    commands: &mut Commands,
+    pool: Res<ComputeTaskPool>,
+    timestep: Res<Timestep>,
+    substep: Res<Substep>,
+    settings: Res<JointSettings>,
+    joints: Query<&Joint>,
+    mut query: Query<(
+        &mut Position,
+        &mut Rotation,
+        &CenterOfMass,
+        &InvMass,
+        &InvInertiaAroundCenterOfMass,
+        Option<&Kinematic>,
+        Option<&mut Momentum>,
+        Option<&mut AngularMomentum>,
+        Option<&Sleeping>,
+    )>,
+) {
+    let timestep = substep.length(&timestep);
+    let mut indices = HashMap::new();
+    let mut entities = vec![];
+    let mut poses = vec![];
+    let mut bodies = vec![];
+    let mut sleeping = vec![];
+    let mut index_of = |entity: Entity| -> Option<usize> {
+        if let Some(&index) = indices.get(&entity) {
+            return Some(index);
+        }
+        let (p, r, com, im, iiacom, k, m, am, s) = query.get_mut(entity).ok()?;
+        let dynamic = m.is_some() && am.is_some() && im.0 != 0.0;
+        let (velocity, angular_velocity) = match (k, &m, &am) {
+            (Some(k), _, _) => (k.velocity, k.angular_velocity),
+            (None, Some(m), Some(am)) => (m.0 * im.0, world_inv_inertia(&r, iiacom) * am.0),
+            _ => (FVec::zero(), FVec::zero()),
+        };
+        bodies.push(SolverBody::new(
+            p.0,
+            velocity,
+            angular_velocity,
+            if dynamic { im.0 } else { 0.0 },
+            if dynamic {
+                world_inv_inertia(&r, iiacom)
+            } else {
+                FMat::from_scale(0.0)
+            },
+        ));
+        poses.push(Pose {
+            position: *p,
+            rotation: *r,
+            center_of_mass: *com,
+        });
+        sleeping.push(s.is_some());
+        entities.push(entity);
+        indices.insert(entity, bodies.len() - 1);
+        Some(bodies.len() - 1)
+    };
+    let mut jointed = vec![];
+    for joint in joints.iter() {
+        if joint.a == joint.b {
+            continue;
+        }
+        if let (Some(a), Some(b)) = (index_of(joint.a), index_of(joint.b)) {
+            jointed.push((*joint, a, b));
+        }
+    }
+    jointed.retain(|&(_, a, b)| {
+        let awake = |i: usize| bodies[i].inv_mass != 0.0 && !sleeping[i];
+        awake(a) || awake(b)
+    });
+    for &(_, a, b) in jointed.iter() {
+        for &i in [a, b].iter() {
+            if sleeping[i] {
+                sleeping[i] = false;
+                commands.remove_one::<Sleeping>(entities[i]);
+            }
+        }
+    }
+    let bias = settings.position_correction / timestep;
+    let mut rows = vec![];
+    for (joint, a, b) in jointed {
+        rows.extend(joint_rows(
+            &joint,
+            (a, &poses[a]),
+            (b, &poses[b]),
+            &mut bodies,
+            bias,
+            timestep,
+        ));
+    }
+    let iterations = settings.iterations;
+    solve_islands(&pool.0, &mut bodies, &mut rows, |bodies, rows| {
+        for _ in 0..iterations {
+            for row in rows.iter_mut() {
+                row.solve(bodies);
+            }
+        }
+    });
+    for ((entity, solved), sleeping) in entities.into_iter().zip(bodies.into_iter()).zip(sleeping) {
+        if solved.inv_mass == 0.0 || sleeping {
+            continue;
+        }
+        let (mut p, mut r, _, _, _, _, m, am, _) = query.get_mut(entity).unwrap();
+        if let (Some(mut m), Some(mut am)) = (m, am) {
+            m.0 += solved.impulse;
+            am.0 += solved.angular_impulse;
+        }
+        p.0 += solved.bias_velocity * timestep;
+        r.0 = integrate_rotation(r.0, solved.bias_angular_velocity * timestep);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::test_util::*;
+
+    fn spawn_static_cube(app: &mut App) -> Entity {
+        app.world.spawn(StaticBundle::new(
+            FVec::zero(),
+            Rot::identity(),
+            vec![(IVec::zero(), 1)],
+        ))
+    }
+
+    /// A joint from the right face of `a` to the left face of `b`.
+    fn spawn_joint(app: &mut App, a: Entity, b: Entity, kind: JointKind) -> Joint {
+        let joint = Joint {
+            a,
+            b,
+            a_anchor: FVec::new(1.0, 0.5, 0.5),
+            b_anchor: FVec::new(0.0, 0.5, 0.5),
+            kind,
+        };
+        app.world.spawn((joint,));
+        joint
+    }
+
+    fn hinge(limits: Option<(f32, f32)>, motor: Option<Motor>) -> JointKind {
+        JointKind::Revolute(Hinge {
+            a_axis: FVec::unit_z(),
+            b_axis: FVec::unit_z(),
+            a_reference: FVec::unit_x(),
+            b_reference: FVec::unit_x(),
+            limits,
+            motor,
+        })
+    }
+
+    fn pose(app: &App, e: Entity) -> Pose {
+        Pose {
+            position: *app.world.get::<Position>(e).unwrap(),
+            rotation: *app.world.get::<Rotation>(e).unwrap(),
+            center_of_mass: *app.world.get::<CenterOfMass>(e).unwrap(),
+        }
+    }
+
+    fn separation(app: &App, joint: &Joint) -> f32 {
+        (pose(app, joint.a).world_point(joint.a_anchor)
+            - pose(app, joint.b).world_point(joint.b_anchor))
+        .mag()
+    }
+
+    #[test]
+    fn test_spherical() {
+        let mut app = init_app(0.05).app;
+        let a = spawn_body(
+            &mut app,
+            FVec::zero(),
+            FVec::new(2.0, 0.0, 0.0),
+            &[IVec::zero()],
+        );
+        let b = spawn_body(&mut app, FVec::unit_x(), FVec::zero(), &[IVec::zero()]);
+        let joint = spawn_joint(&mut app, a, b, JointKind::Spherical);
+        for _ in 0..20 {
+            app.update();
+        }
+        assert!(separation(&app, &joint) < 0.05);
+        // `a` tows `b` along, conserving momentum.
+        for &e in [a, b].iter() {
+            let momentum = app.world.get::<Momentum>(e).unwrap().0;
+            assert!((momentum - FVec::new(1.0, 0.0, 0.0)).mag() < 0.05);
+        }
+    }
+
+    #[test]
+    fn test_fixed() {
+        let mut app = init_app(0.05).app;
+        let a = spawn_body(
+            &mut app,
+            FVec::zero(),
+            FVec::new(0.5, 0.0, 0.0),
+            &[IVec::zero()],
+        );
+        let b = spawn_body(&mut app, FVec::unit_x(), FVec::zero(), &[IVec::zero()]);
+        app.world.get_mut::<AngularMomentum>(a).unwrap().0 = FVec::new(0.3, 0.2, 1.0);
+        let joint = spawn_joint(
+            &mut app,
+            a,
+            b,
+            JointKind::Fixed {
+                relative_rotation: Rot::identity(),
+            },
+        );
+        for i in 0..60 {
+            app.update();
+            if i > 5 {
+                let a_rotation = app.world.get::<Rotation>(a).unwrap().0;
+                let b_rotation = app.world.get::<Rotation>(b).unwrap().0;
+                assert!((a_rotation * FVec::unit_x() - b_rotation * FVec::unit_x()).mag() < 0.05);
+            }
+        }
+        assert!(separation(&app, &joint) < 0.05);
+    }
+
+    #[test]
+    fn test_hinge_motor() {
+        let mut app = init_app(0.05).app;
+        let a = spawn_static_cube(&mut app);
+        let b = spawn_body(&mut app, FVec::unit_x(), FVec::zero(), &[IVec::zero()]);
+        let motor = Motor {
+            target_velocity: 1.0,
+            max_torque: 100.0,
+        };
+        let joint = spawn_joint(&mut app, a, b, hinge(None, Some(motor)));
+        for _ in 0..40 {
+            app.update();
+        }
+        assert!(separation(&app, &joint) < 0.05);
+        let angular_velocity = world_inv_inertia(
+            &*app.world.get::<Rotation>(b).unwrap(),
+            &*app.world.get::<InvInertiaAroundCenterOfMass>(b).unwrap(),
+        ) * app.world.get::<AngularMomentum>(b).unwrap().0;
+        assert!((angular_velocity - FVec::unit_z()).mag() < 0.05);
+    }
+
+    #[test]
+    fn test_hinge_limits() {
+        let mut app = init_app(0.05).app;
+        let a = spawn_static_cube(&mut app);
+        let b = spawn_body(&mut app, FVec::unit_x(), FVec::zero(), &[IVec::zero()]);
+        let motor = Motor {
+            target_velocity: 2.0,
+            max_torque: 5.0,
+        };
+        let joint = spawn_joint(&mut app, a, b, hinge(Some((-0.5, 0.5)), Some(motor)));
+        for _ in 0..60 {
+            app.update();
+        }
+        assert!(separation(&app, &joint) < 0.05);
+        let x = app.world.get::<Rotation>(b).unwrap().0 * FVec::unit_x();
+        assert!((x.y.atan2(x.x) - 0.5).abs() < 0.1);
+    }
+}
@@ -0,0 +1,140 @@
+use super::voxel_inertia;
+use crate::for_each::ForEachMut;
+use crate::geometry::*;
+use crate::octree::{OctreeNode, OctreeSet};
+
+/// A voxel type with a mass. Empty voxels have none, and the ambient voxel of
+/// a storage should be empty.
+pub trait MassVoxel {
+    fn mass(&self) -> i64;
+}
+
+/// The mass, first moment and inertia of a body, in the body's voxel
+/// coordinates. The inertia is around the origin of the body.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct MassProperties {
+    pub mass: i64,
+    pub total_mass_position: LVec,
+    pub inertia: LMat,
+}
+
+/// The sum of `p + i` over `0 <= i < s`.
+fn sum(p: i64, s: i64) -> i64 {
+    s * p + s * (s - 1) / 2
+}
+
+/// The sum of `(p + i)^2` over `0 <= i < s`.
+fn sum_squares(p: i64, s: i64) -> i64 {
+    s * p * p + p * s * (s - 1) + (s - 1) * s * (2 * s - 1) / 6
+}
+
+impl MassProperties {
+    pub fn from_voxels(mut masses_fn: impl ForEachMut<(IVec, i64)>) -> Self {
+        let mut properties = MassProperties::default();
+        masses_fn.for_each_mut(|(pos, mass)| properties.add_voxel(pos, mass));
+        properties
+    }
+
+    /// Finds the mass properties of every full node in an octree, where each
+    /// voxel within a node has the mass given by `density`.
+    pub fn from_octree<S: OctreeSet>(set: &S, mut density: impl FnMut(S::Node) -> i64) -> Self {
+        let mut properties = MassProperties::default();
+        set.for_each_full(|node| properties.add_cube(node.position(), node.size(), density(node)));
+        properties
+    }
+
+    /// The center of mass, or the origin if there is no mass.
+    pub fn center_of_mass(&self) -> FVec {
+        if self.mass == 0 {
+            FVec::zero()
+        } else {
+            self.total_mass_position.as_f32() / (self.mass as f32)
+        }
+    }
+
+    pub fn add_voxel(&mut self, pos: IVec, mass: i64) {
+        self.mass += mass;
+        self.total_mass_position += LVec::from(pos) * mass;
+        self.inertia += voxel_inertia(pos, mass);
+    }
+
+    /// Adds a cube of voxels with its bottom corner at `pos`, each with a mass
+    /// of `density`. This is exactly the same as adding each voxel.
+    pub fn add_cube(&mut self, pos: IVec, size: u64, density: i64) {
+        let s = size as i64;
+        let count = s * s * s;
+        let (x, y, z) = (pos.x as i64, pos.y as i64, pos.z as i64);
+        let sums = LVec(sum(x, s), sum(y, s), sum(z, s));
+        let squares = LVec(sum_squares(x, s), sum_squares(y, s), sum_squares(z, s)) * (s * s);
+        // The sum of the product of two coordinates over every voxel.
+        let products = LVec(sums.1 * sums.2, sums.0 * sums.2, sums.0 * sums.1) * s;
+        self.mass += count * density;
+        self.total_mass_position += sums * (s * s * density);
+        self.inertia += (LMat::identity() * count
+            + LMat::create(
+                [squares.1 + squares.2, -products.2, -products.1],
+                [-products.2, squares.0 + squares.2, -products.0],
+                [-products.1, -products.0, squares.0 + squares.1],
+            ))
+            * density;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::linear_octree_set::LinearOctreeSet;
+
+    #[test]
+    fn test_cube() {
+        for &(pos, size) in [
+            (IVec::zero(), 1),
+            (IVec::new(-3, 5, 2), 2),
+            (IVec::new(7, -8, -1), 4),
+            (IVec::new(-16, -16, 48), 16),
+        ]
+        .iter()
+        {
+            let mut cube = MassProperties::default();
+            cube.add_cube(pos, size, 3);
+            let mut voxels = MassProperties::default();
+            let size = size as i32;
+            for x in 0..size {
+                for y in 0..size {
+                    for z in 0..size {
+                        voxels.add_voxel(pos + IVec::new(x, y, z), 3);
+                    }
+                }
+            }
+            assert_eq!(cube, voxels);
+        }
+    }
+
+    #[test]
+    fn test_octree() {
+        let mut positions = vec![];
+        for x in -9..7 {
+            for y in -4..12 {
+                for z in -6..3 {
+                    if (x * 7 + y * 3 + z * 5).rem_euclid(11) < 8 {
+                        positions.push(IVec::new(x, y, z));
+                    }
+                }
+            }
+        }
+        let set = LinearOctreeSet::from_positions(&positions);
+        let density = |node: <LinearOctreeSet as OctreeSet>::Node| 1 + node.size() as i64;
+        let mut voxels = MassProperties::default();
+        set.for_each_full(|node| {
+            let size = node.size() as i32;
+            for x in 0..size {
+                for y in 0..size {
+                    for z in 0..size {
+                        voxels.add_voxel(node.position() + IVec::new(x, y, z), density(node));
+                    }
+                }
+            }
+        });
+        assert_eq!(MassProperties::from_octree(&set, density), voxels);
+    }
+}
@@ -0,0 +1,265 @@
+//! Putting resting bodies to sleep, so that idle bodies cost nothing to
+//! simulate.
+use super::*;
+
+/// When bodies are put to sleep.
+/// The thresholds are on velocities rather than momenta, so that they work the
+/// same for bodies of any size.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SleepSettings {
+    /// The speed below which a body is resting.
+    pub linear_threshold: f32,
+    /// The angular speed, in radians per second, below which a body is resting.
+    pub angular_threshold: f32,
+    /// How long, in seconds, a body must rest before it is put to sleep.
+    pub time_to_sleep: f32,
+}
+impl Default for SleepSettings {
+    fn default() -> Self {
+        SleepSettings {
+            linear_threshold: 0.05,
+            angular_threshold: 0.05,
+            time_to_sleep: 1.0,
+        }
+    }
+}
+
+/// Marks a body that is asleep. Sleeping bodies have no momentum, and are
+/// skipped by the integrators. They are only collided with awake bodies.
+/// A sleeping body is woken by contacts and joints with awake bodies, by any
+/// applied force, torque or momentum, and by voxel edits. `octree_collide` also
+/// wakes it when a body it rests on moves, changes, or is despawned.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct Sleeping;
+
+/// How long a body has been resting, in seconds.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct SleepTimer(pub f32);
+
+/// Puts bodies that have rested for long enough to sleep.
+/// This is added to the "sleep" stage of the physics schedule, and only runs on
+/// the last substep of each step, once its forces have been cleared.
+#[allow(clippy::type_complexity)]
+pub fn update_sleep(
+    commands: &mut Commands,
+    timestep: Res<Timestep>,
+    substep: Res<Substep>,
+    settings: Res<SleepSettings>,
+    mut query: Query<
+        (
+            Entity,
+            &Rotation,
+            &InvMass,
+            &InvInertiaAroundCenterOfMass,
+            &mut Momentum,
+            &mut AngularMomentum,
+            &mut SleepTimer,
+        ),
+        (Without<Sleeping>, Without<Static>, Without<Kinematic>),
+    >,
+) {
+    if !substep.is_last() {
+        return;
+    }
+    for (e, r, im, iiacom, mut m, mut am, mut timer) in query.iter_mut() {
+        let velocity = m.0 * im.0;
+        let angular_velocity = world_inv_inertia(r, iiacom) * am.0;
+        if velocity.mag() > settings.linear_threshold
+            || angular_velocity.mag() > settings.angular_threshold
+        {
+            timer.0 = 0.0;
+            continue;
+        }
+        timer.0 += timestep.0;
+        if timer.0 >= settings.time_to_sleep {
+            // The timer is reset here, so that a woken body rests for the
+            // whole time again before sleeping.
+            timer.0 = 0.0;
+            m.0 = FVec::zero();
+            am.0 = FVec::zero();
+            commands.insert_one(e, Sleeping);
+        }
+    }
+}
+
+/// Wakes sleeping bodies that have been pushed or edited since the last tick.
+/// This is added to the start of the "pre-physics" stage, before the voxel
+/// edits are applied.
+#[allow(clippy::type_complexity)]
+pub fn wake_bodies(
+    commands: &mut Commands,
+    query: Query<
+        (
+            Entity,
+            &Force,
+            &Torque,
+            &Momentum,
+            &AngularMomentum,
+            &ChangedBodies,
+        ),
+        With<Sleeping>,
+    >,
+) {
+    for (e, f, t, m, am, cb) in query.iter() {
+        let zero = FVec::zero();
+        if f.0 != zero || t.0 != zero || m.0 != zero || am.0 != zero || !cb.0.is_empty() {
+            commands.remove_one::<Sleeping>(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::linear_octree_set::LinearOctreeSet;
+    use crate::physics::test_util::{self, add_octree_collide, spawn_octree_body};
+
+    fn init_app() -> App {
+        let mut app = test_util::init_app(0.1);
+        add_octree_collide::<LinearOctreeSet>(&mut app);
+        app.app
+    }
+
+    fn is_sleeping(app: &App, e: Entity) -> bool {
+        app.world.get::<Sleeping>(e).is_ok()
+    }
+
+    #[test]
+    fn test_falls_asleep() {
+        let mut app = init_app();
+        let resting = spawn_octree_body(
+            &mut app,
+            FVec::zero(),
+            FVec::new(0.01, 0.0, 0.0),
+            &[IVec::zero()],
+        );
+        let moving = spawn_octree_body(
+            &mut app,
+            FVec::new(0.0, 5.0, 0.0),
+            FVec::new(1.0, 0.0, 0.0),
+            &[IVec::zero()],
+        );
+        for _ in 0..9 {
+            app.update();
+        }
+        assert!(!is_sleeping(&app, resting));
+        for _ in 0..3 {
+            app.update();
+        }
+        assert!(is_sleeping(&app, resting));
+        assert!(!is_sleeping(&app, moving));
+        assert_eq!(app.world.get::<Momentum>(resting).unwrap().0, FVec::zero());
+
+        // Sleeping bodies are not integrated.
+        let position = app.world.get::<Position>(resting).unwrap().0;
+        app.update();
+        assert_eq!(app.world.get::<Position>(resting).unwrap().0, position);
+    }
+
+    #[test]
+    fn test_wakes_on_force() {
+        let mut app = init_app();
+        let e = spawn_octree_body(&mut app, FVec::zero(), FVec::zero(), &[IVec::zero()]);
+        for _ in 0..12 {
+            app.update();
+        }
+        assert!(is_sleeping(&app, e));
+        app.world.get_mut::<Force>(e).unwrap().0 = FVec::new(1.0, 0.0, 0.0);
+        app.update();
+        assert!(!is_sleeping(&app, e));
+        assert!(app.world.get::<Position>(e).unwrap().0.x > 0.0);
+    }
+
+    #[test]
+    fn test_wakes_on_voxel_edit() {
+        let mut app = init_app();
+        let e = spawn_octree_body(&mut app, FVec::zero(), FVec::zero(), &[IVec::zero()]);
+        for _ in 0..12 {
+            app.update();
+        }
+        assert!(is_sleeping(&app, e));
+        app.world
+            .get_mut::<ChangedBodies>(e)
+            .unwrap()
+            .0
+            .push((IVec::unit_x(), 1));
+        app.update();
+        assert!(!is_sleeping(&app, e));
+        assert_eq!(app.world.get::<Mass>(e).unwrap().0, 2);
+    }
+
+    #[test]
+    fn test_wakes_on_contact() {
+        let mut app = init_app();
+        let sleeper = spawn_octree_body(&mut app, FVec::zero(), FVec::zero(), &[IVec::zero()]);
+        for _ in 0..12 {
+            app.update();
+        }
+        assert!(is_sleeping(&app, sleeper));
+        let other = spawn_octree_body(
+            &mut app,
+            FVec::new(-3.0, 0.0, 0.0),
+            FVec::new(2.0, 0.0, 0.0),
+            &[IVec::zero()],
+        );
+        for _ in 0..20 {
+            app.update();
+        }
+        assert!(!is_sleeping(&app, sleeper));
+        assert!(!is_sleeping(&app, other));
+        assert!(app.world.get::<Position>(sleeper).unwrap().0.x > 0.5);
+    }
+
+    /// A body resting on a static one, which is asleep under gravity.
+    fn spawn_resting(app: &mut App, x: f32) -> (Entity, Entity) {
+        let support = app.world.spawn(StaticBundle::new(
+            FVec::new(x, 0.0, 0.0),
+            Rot::identity(),
+            vec![(IVec::zero(), 1)],
+        ));
+        app.world
+            .insert_one(support, LinearOctreeSet::from_positions(&[IVec::zero()]))
+            .unwrap();
+        let body = spawn_octree_body(app, FVec::new(x, 0.98, 0.0), FVec::zero(), &[IVec::zero()]);
+        (support, body)
+    }
+
+    #[test]
+    fn test_wakes_when_support_is_removed() {
+        let mut app = init_app();
+        // Resting bodies keep half a step of gravity, which should not keep
+        // them awake here.
+        app.resources.insert(SleepSettings {
+            linear_threshold: 1.0,
+            ..Default::default()
+        });
+        app.world.spawn((field::ForceField {
+            bounds: field::FieldBounds::Everywhere,
+            kind: field::FieldKind::Acceleration(FVec::new(0.0, -10.0, 0.0)),
+        },));
+        let (despawned, first) = spawn_resting(&mut app, 0.0);
+        let (emptied, second) = spawn_resting(&mut app, 10.0);
+        let (_, third) = spawn_resting(&mut app, 20.0);
+        for _ in 0..15 {
+            app.update();
+        }
+        for &e in [first, second, third].iter() {
+            assert!(is_sleeping(&app, e));
+        }
+        let resting = app.world.get::<Position>(third).unwrap().0;
+        app.world.despawn(despawned).unwrap();
+        app.world
+            .insert_one(emptied, LinearOctreeSet::from_positions(&[]))
+            .unwrap();
+        for _ in 0..5 {
+            app.update();
+        }
+        for &e in [first, second].iter() {
+            assert!(!is_sleeping(&app, e));
+            assert!(app.world.get::<Position>(e).unwrap().0.y < 0.5);
+        }
+        // Bodies on untouched supports stay asleep.
+        assert!(is_sleeping(&app, third));
+        assert_eq!(app.world.get::<Position>(third).unwrap().0, resting);
+    }
+}
@@ -0,0 +1,427 @@
+//! Capturing the physics state of every body, and restoring it later, for
+//! rollback, replays and debugging.
+use super::deterministic::*;
+use super::sleep::SleepTimer;
+use super::stepping::{Interpolation, StepClock};
+use super::*;
+use crate::octree::OctreeSet;
+use bevy::ecs::Component;
+
+/// Declares `BodyComponent`, with a variant named after each physics
+/// component that a body can have, and the functions that capture and restore
+/// them.
+macro_rules! body_components {
+    ($($name: ident),* $(,)?) => {
+        /// A physics component of a body.
+        #[derive(Clone, PartialEq, Debug)]
+        pub enum BodyComponent {
+            $($name($name),)*
+        }
+
+        /// The physics components that a body has.
+        fn get_components(world: &World, e: Entity) -> Vec<BodyComponent> {
+            let mut components = vec![];
+            $(
+                if let Ok(component) = world.get::<$name>(e) {
+                    components.push(BodyComponent::$name((*component).clone()));
+                }
+            )*
+            components
+        }
+
+        /// Sets every physics component of a body to its value in
+        /// `components`, removing the ones that are not there.
+        fn restore_components(world: &mut World, e: Entity, components: &[BodyComponent]) {
+            $(
+                let value = components.iter().find_map(|component| match component {
+                    BodyComponent::$name(value) => Some(value),
+                    _ => None,
+                });
+                restore(world, e, value);
+            )*
+        }
+    };
+}
+
+body_components!(
+    Position,
+    Rotation,
+    PreviousPosition,
+    PreviousRotation,
+    Momentum,
+    AngularMomentum,
+    Force,
+    Torque,
+    TotalMassPosition,
+    Mass,
+    Inertia,
+    ChangedBodies,
+    CenterOfMass,
+    InvMass,
+    InertiaAroundCenterOfMass,
+    InvInertiaAroundCenterOfMass,
+    VoxelAnchor,
+    Kinematic,
+    Sleeping,
+    SleepTimer,
+    FixedPosition,
+    FixedRotation,
+    FixedMomentum,
+    FixedAngularMomentum,
+    FixedForce,
+    FixedTorque,
+    FixedCenterOfMass,
+    FixedInvMass,
+    FixedInvInertia,
+);
+
+/// State of a body outside of its physics components that is captured along
+/// with them, such as its voxels.
+pub trait BodyState: Sized {
+    fn get(world: &World, e: Entity) -> Option<Self>;
+    /// Puts the state of a body back, or removes it if it is `None`.
+    fn restore(world: &mut World, e: Entity, state: Option<&Self>);
+}
+
+/// Nothing beyond the physics components.
+impl BodyState for () {
+    fn get(_: &World, _: Entity) -> Option<Self> {
+        None
+    }
+    fn restore(_: &mut World, _: Entity, _: Option<&Self>) {}
+}
+
+/// The storage and collider of a body, for bodies whose voxels can change.
+/// Voxels are only edited through `edit_voxels`, which changes the generation
+/// of the collider along with the storage, so two of these are equal when
+/// their colliders have the same generation, and they are only put back when
+/// the generation has changed.
+#[derive(Clone)]
+pub struct BodyVoxels<Storage, Set> {
+    pub storage: Storage,
+    pub collider: Set,
+}
+
+impl<Storage, Set: OctreeSet> PartialEq for BodyVoxels<Storage, Set> {
+    fn eq(&self, other: &Self) -> bool {
+        self.collider.generation() == other.collider.generation()
+    }
+}
+
+impl<Storage, Set: OctreeSet> std::fmt::Debug for BodyVoxels<Storage, Set> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BodyVoxels")
+            .field("generation", &self.collider.generation())
+            .finish()
+    }
+}
+
+impl<Storage, Set> BodyState for BodyVoxels<Storage, Set>
+where
+    Storage: Component + Clone,
+    Set: Component + Clone + OctreeSet, {
+    fn get(world: &World, e: Entity) -> Option<Self> {
+        Some(BodyVoxels {
+            storage: get::<Storage>(world, e)?,
+            collider: get::<Set>(world, e)?,
+        })
+    }
+    fn restore(world: &mut World, e: Entity, voxels: Option<&Self>) {
+        match voxels {
+            Some(voxels) => {
+                let generation = world.get::<Set>(e).ok().map(|set| set.generation());
+                if generation != Some(voxels.collider.generation())
+                    || world.get::<Storage>(e).is_err()
+                {
+                    let (storage, collider) = (voxels.storage.clone(), voxels.collider.clone());
+                    world.insert(e, (storage, collider)).unwrap();
+                }
+            }
+            None => {
+                if world.get::<Storage>(e).is_ok() {
+                    world.remove_one::<Storage>(e).unwrap();
+                }
+                if world.get::<Set>(e).is_ok() {
+                    world.remove_one::<Set>(e).unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// The physics components of a body at some tick, along with its `State`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct BodySnapshot<State = ()> {
+    pub entity: Entity,
+    pub components: Vec<BodyComponent>,
+    pub state: Option<State>,
+}
+
+/// The physics state of every body with a `Position` and `Rotation`, in the
+/// order of their entities, so that two snapshots of the same state are equal,
+/// along with the state of the stepping.
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct PhysicsSnapshot<State = ()> {
+    pub bodies: Vec<BodySnapshot<State>>,
+    pub clock: StepClock,
+    pub substep: Substep,
+    pub interpolation: Interpolation,
+}
+
+/// The number of times that a snapshot has been restored.
+/// Systems that keep state of their own between ticks, such as the collision
+/// caches of `octree_collide`, drop it whenever this changes, so that what is
+/// simulated after a restore does not depend on what was simulated before it.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct RestoreCount(pub u64);
+
+fn get<T: Component + Clone>(world: &World, e: Entity) -> Option<T> {
+    world.get::<T>(e).ok().map(|component| (*component).clone())
+}
+
+/// Sets a component to its value in a snapshot, adding or removing it as
+/// needed. Components that are already right are not touched, so that they
+/// are not marked as changed.
+fn restore<T: Component + Clone + PartialEq>(world: &mut World, e: Entity, value: Option<&T>) {
+    match value {
+        Some(value) => {
+            if let Ok(mut component) = world.get_mut::<T>(e) {
+                if *component != *value {
+                    *component = value.clone();
+                }
+                return;
+            }
+            world.insert_one(e, value.clone()).unwrap();
+        }
+        None => {
+            if world.get::<T>(e).is_ok() {
+                world.remove_one::<T>(e).unwrap();
+            }
+        }
+    }
+}
+
+impl PhysicsPlugin {
+    /// Takes a snapshot of the physics components of every body, and of the
+    /// stepping.
+    /// This should be called between ticks, outside of the physics schedule.
+    pub fn snapshot(world: &World, resources: &Resources) -> PhysicsSnapshot {
+        PhysicsPlugin::snapshot_with(world, resources)
+    }
+
+    /// Takes a snapshot like `snapshot`, which also captures the `State` of
+    /// every body, such as its `BodyVoxels`. Voxels that are still queued in
+    /// `VoxelEdits` are not captured.
+    pub fn snapshot_with<State: BodyState>(
+        world: &World,
+        resources: &Resources,
+    ) -> PhysicsSnapshot<State> {
+        let mut bodies = world
+            .query::<(Entity, &Position, &Rotation)>()
+            .map(|(e, _, _)| e)
+            .collect::<Vec<_>>();
+        bodies.sort();
+        let bodies = bodies
+            .into_iter()
+            .map(|e| BodySnapshot {
+                entity: e,
+                components: get_components(world, e),
+                state: State::get(world, e),
+            })
+            .collect();
+        PhysicsSnapshot {
+            bodies,
+            clock: *resources.get::<StepClock>().unwrap(),
+            substep: *resources.get::<Substep>().unwrap(),
+            interpolation: *resources.get::<Interpolation>().unwrap(),
+        }
+    }
+
+    /// Puts every body in a snapshot back into the state it was in, in place,
+    /// along with the stepping. Bodies that have been despawned since are not
+    /// brought back, and bodies that have been spawned since are left alone.
+    pub fn restore<State: BodyState>(
+        world: &mut World,
+        resources: &mut Resources,
+        snapshot: &PhysicsSnapshot<State>,
+    ) {
+        *resources.get_mut::<StepClock>().unwrap() = snapshot.clock;
+        *resources.get_mut::<Substep>().unwrap() = snapshot.substep;
+        *resources.get_mut::<Interpolation>().unwrap() = snapshot.interpolation;
+        resources.get_mut::<RestoreCount>().unwrap().0 += 1;
+        for body in snapshot.bodies.iter() {
+            let e = body.entity;
+            if !world.contains(e) {
+                continue;
+            }
+            restore_components(world, e, &body.components);
+            State::restore(world, e, body.state.as_ref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::linear_octree_set::LinearOctreeSet;
+    use crate::octree::octree_set::BBOctreeSet;
+    use crate::physics::edit::{apply_voxel_edits, VoxelEdits};
+    use crate::physics::test_util::{
+        add_octree_collide, spawn_octree_body, spawn_storage_body, TestVoxel,
+    };
+    use crate::storage::chunk_map::ChunkStorage;
+    use crate::storage::{VoxelStorage, Writer};
+    use bevy::tasks::{ComputeTaskPool, TaskPoolBuilder};
+
+    /// The app is built without the core plugin, so that frames take no time
+    /// unless `run` says they do, and the steps run do not depend on how fast
+    /// the test runs.
+    fn init_app<Set>(stepping: Stepping, substepping: Substepping) -> AppBuilder
+    where
+        Set: 'static + OctreeSet + Send + Sync,
+        Set::Node: Send + Sync, {
+        let mut app = App::build();
+        app.add_resource(Time::default())
+            .add_resource(ComputeTaskPool(TaskPoolBuilder::new().build()))
+            .add_plugin(PhysicsPlugin {
+                timestep: 0.1,
+                stepping,
+                substepping,
+                ..Default::default()
+            });
+        add_octree_collide::<Set>(&mut app);
+        app
+    }
+
+    /// Runs frames that each take `frame_time`, taking a snapshot after each.
+    fn run(app: &mut App, frames: usize, frame_time: f64) -> Vec<PhysicsSnapshot> {
+        (0..frames)
+            .map(|_| {
+                app.resources.get_mut::<StepClock>().unwrap().accumulated += frame_time;
+                app.update();
+                PhysicsPlugin::snapshot(&app.world, &app.resources)
+            })
+            .collect()
+    }
+
+    fn momentum<State>(body: &BodySnapshot<State>) -> Option<&Momentum> {
+        body.components
+            .iter()
+            .find_map(|component| match component {
+                BodyComponent::Momentum(momentum) => Some(momentum),
+                _ => None,
+            })
+    }
+
+    fn check_resimulate(stepping: Stepping, substepping: Substepping, frame_time: f64) {
+        let mut app = init_app::<LinearOctreeSet>(stepping, substepping).app;
+        let l = [IVec::zero(), IVec::unit_x(), IVec::unit_y()];
+        spawn_octree_body(&mut app, FVec::zero(), FVec::new(1.0, 0.2, 0.0), &l);
+        spawn_octree_body(&mut app, FVec::new(3.0, 0.5, 0.0), -FVec::unit_x(), &l);
+        // This one falls asleep during the trajectory.
+        let resting = spawn_octree_body(&mut app, FVec::new(0.0, 10.0, 0.0), FVec::zero(), &l);
+        run(&mut app, 5, frame_time);
+        let snapshot = PhysicsPlugin::snapshot(&app.world, &app.resources);
+        // Restoring drops the collision caches, so the trajectory to compare
+        // with is run from a restore as well.
+        PhysicsPlugin::restore(&mut app.world, &mut app.resources, &snapshot);
+        let trajectory = run(&mut app, 20, frame_time);
+        assert!(app.world.get::<Sleeping>(resting).is_ok());
+        assert!(trajectory
+            .iter()
+            .any(|s| momentum(&s.bodies[0]) != momentum(&snapshot.bodies[0])));
+
+        PhysicsPlugin::restore(&mut app.world, &mut app.resources, &snapshot);
+        assert_eq!(
+            PhysicsPlugin::snapshot(&app.world, &app.resources),
+            snapshot
+        );
+        assert!(app.world.get::<Sleeping>(resting).is_err());
+        assert_eq!(run(&mut app, 20, frame_time), trajectory);
+    }
+
+    #[test]
+    fn test_resimulate() {
+        check_resimulate(Stepping::EveryUpdate, Substepping::default(), 0.1);
+        // Some of these frames run no steps, and some run two.
+        check_resimulate(
+            Stepping::Fixed { max_steps: 4 },
+            Substepping::default(),
+            0.07,
+        );
+        check_resimulate(Stepping::EveryUpdate, Substepping::Fixed(3), 0.1);
+        check_resimulate(
+            Stepping::Fixed { max_steps: 4 },
+            Substepping::Adaptive {
+                max_substeps: 4,
+                max_travel: 0.05,
+            },
+            0.07,
+        );
+    }
+
+    #[test]
+    fn test_resimulate_edits() {
+        type Voxels = BodyVoxels<ChunkStorage<TestVoxel>, BBOctreeSet>;
+        let mut app = init_app::<BBOctreeSet>(Stepping::EveryUpdate, Substepping::default());
+        app.add_system_to_stage(
+            stage::PRE_UPDATE,
+            apply_voxel_edits::<ChunkStorage<TestVoxel>, BBOctreeSet>.system(),
+        );
+        let mut app = app.app;
+        let spawn = |app: &mut App, x: f32, velocity: f32| {
+            let mut storage = ChunkStorage::new(TestVoxel::Empty, 16);
+            for y in 0..3 {
+                *storage.get_mut(IVec::new(0, y, 0)).get_mut() = TestVoxel::Hull;
+            }
+            let e = spawn_storage_body(app, FVec::new(x, 0.0, 0.0), Rot::identity(), storage);
+            app.world.get_mut::<Momentum>(e).unwrap().0 = FVec::new(3.0 * velocity, 0.0, 0.0);
+            app.world
+                .insert_one(e, VoxelEdits::<TestVoxel>::default())
+                .unwrap();
+            e
+        };
+        let a = spawn(&mut app, 0.0, 1.0);
+        spawn(&mut app, 3.0, -1.0);
+        // The top of `a` is removed before the bodies meet, so that they only
+        // touch along the rest of it.
+        let removed = IVec::new(0, 2, 0);
+        let run = |app: &mut App| {
+            (0..20)
+                .map(|frame| {
+                    if frame == 3 {
+                        let mut edits = app.world.get_mut::<VoxelEdits<TestVoxel>>(a).unwrap();
+                        edits.0.push((removed, TestVoxel::Empty));
+                    }
+                    app.resources.get_mut::<StepClock>().unwrap().accumulated += 0.1;
+                    app.update();
+                    // Edits give the collider a new generation, so the
+                    // voxels themselves are compared after the run instead.
+                    PhysicsPlugin::snapshot(&app.world, &app.resources)
+                })
+                .collect::<Vec<_>>()
+        };
+        let snapshot = PhysicsPlugin::snapshot_with::<Voxels>(&app.world, &app.resources);
+        PhysicsPlugin::restore(&mut app.world, &mut app.resources, &snapshot);
+        let trajectory = run(&mut app);
+        let voxels = Voxels::get(&app.world, a).unwrap();
+        assert_eq!(*voxels.storage.get(removed), TestVoxel::Empty);
+        assert_eq!(app.world.get::<Mass>(a).unwrap().0, 2);
+        assert!(trajectory
+            .iter()
+            .any(|s| momentum(&s.bodies[0]) != momentum(&snapshot.bodies[0])));
+
+        PhysicsPlugin::restore(&mut app.world, &mut app.resources, &snapshot);
+        assert_eq!(
+            PhysicsPlugin::snapshot_with::<Voxels>(&app.world, &app.resources),
+            snapshot
+        );
+        assert_eq!(
+            *Voxels::get(&app.world, a).unwrap().storage.get(removed),
+            TestVoxel::Hull
+        );
+        assert_eq!(run(&mut app), trajectory);
+        let voxels = Voxels::get(&app.world, a).unwrap();
+        assert_eq!(*voxels.storage.get(removed), TestVoxel::Empty);
+    }
+}
@@ -0,0 +1,416 @@
+//! A sequential impulse solver for contacts between bodies.
+//!
+//! Contacts are resolved on the velocity level. Penetration is removed with a
+//! split impulse, which moves the bodies apart using separate bias velocities
+//! so that it never adds momentum.
+use crate::geometry::*;
+
+/// How contacts between bodies are resolved.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ContactSettings {
+    /// The fraction of the approaching speed that is kept after a collision.
+    pub restitution: f32,
+    /// The Coulomb friction coefficient.
+    pub friction: f32,
+    /// The number of passes the solver makes over the contacts.
+    pub iterations: usize,
+    /// The fraction of the penetration that is removed every timestep.
+    pub position_correction: f32,
+    /// How far bodies may penetrate before they are pushed apart.
+    pub slop: f32,
+    /// Collisions slower than this do not bounce, which keeps resting
+    /// contacts stable.
+    pub restitution_threshold: f32,
+}
+impl Default for ContactSettings {
+    fn default() -> Self {
+        ContactSettings {
+            restitution: 0.2,
+            friction: 0.5,
+            iterations: 8,
+            position_correction: 0.2,
+            slop: 0.01,
+            restitution_threshold: 0.5,
+        }
+    }
+}
+
+/// A body while its contacts are solved.
+/// Infinitely heavy bodies have a zero inverse mass and inertia.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SolverBody {
+    /// The position of the center of mass.
+    pub position: FVec,
+    pub velocity: FVec,
+    pub angular_velocity: FVec,
+    pub inv_mass: f32,
+    /// The inverse inertia around the center of mass, in world space.
+    pub inv_inertia: FMat,
+    /// The total impulse applied to the body.
+    pub impulse: FVec,
+    /// The total angular impulse applied to the body.
+    pub angular_impulse: FVec,
+    /// The velocity that removes penetration. This does not add momentum.
+    pub bias_velocity: FVec,
+    /// The angular velocity that removes penetration.
+    pub bias_angular_velocity: FVec,
+}
+
+impl SolverBody {
+    pub fn new(
+        position: FVec,
+        velocity: FVec,
+        angular_velocity: FVec,
+        inv_mass: f32,
+        inv_inertia: FMat,
+    ) -> Self {
+        SolverBody {
+            position,
+            velocity,
+            angular_velocity,
+            inv_mass,
+            inv_inertia,
+            impulse: FVec::zero(),
+            angular_impulse: FVec::zero(),
+            bias_velocity: FVec::zero(),
+            bias_angular_velocity: FVec::zero(),
+        }
+    }
+
+    /// The velocity of a point attached to the body.
+    pub(crate) fn velocity_at(&self, point: FVec) -> FVec {
+        self.velocity + self.angular_velocity.cross(point - self.position)
+    }
+
+    pub(crate) fn bias_velocity_at(&self, point: FVec) -> FVec {
+        self.bias_velocity + self.bias_angular_velocity.cross(point - self.position)
+    }
+
+    /// The inverse of the mass felt by an impulse along `direction` at a point.
+    pub(crate) fn inv_effective_mass(&self, point: FVec, direction: FVec) -> f32 {
+        let arm = (point - self.position).cross(direction);
+        self.inv_mass + arm.dot(self.inv_inertia * arm)
+    }
+
+    pub(crate) fn apply_impulse(&mut self, impulse: FVec, point: FVec) {
+        let angular_impulse = (point - self.position).cross(impulse);
+        self.impulse += impulse;
+        self.angular_impulse += angular_impulse;
+        self.velocity += impulse * self.inv_mass;
+        self.angular_velocity += self.inv_inertia * angular_impulse;
+    }
+
+    pub(crate) fn apply_bias_impulse(&mut self, impulse: FVec, point: FVec) {
+        self.bias_velocity += impulse * self.inv_mass;
+        self.bias_angular_velocity += self.inv_inertia * (point - self.position).cross(impulse);
+    }
+
+    /// The inverse of the inertia felt by an angular impulse along `direction`.
+    pub(crate) fn inv_angular_mass(&self, direction: FVec) -> f32 {
+        direction.dot(self.inv_inertia * direction)
+    }
+
+    pub(crate) fn apply_angular_impulse(&mut self, angular_impulse: FVec) {
+        self.angular_impulse += angular_impulse;
+        self.angular_velocity += self.inv_inertia * angular_impulse;
+    }
+
+    pub(crate) fn apply_bias_angular_impulse(&mut self, angular_impulse: FVec) {
+        self.bias_angular_velocity += self.inv_inertia * angular_impulse;
+    }
+}
+
+/// A contact point between the bodies at indices `a` and `b`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Contact {
+    pub a: usize,
+    pub b: usize,
+    pub point: FVec,
+    /// The contact normal, pointing from `b` towards `a`.
+    pub normal: FVec,
+    /// How far the bodies penetrate along the normal.
+    pub depth: f32,
+    /// The velocity of `a` relative to `b` along the normal, before solving.
+    pub normal_velocity: f32,
+    /// The impulse applied to `a` by the solver. `b` receives the opposite.
+    pub impulse: FVec,
+}
+
+impl Contact {
+    pub fn new(a: usize, b: usize, point: FVec, normal: FVec, depth: f32) -> Self {
+        Contact {
+            a,
+            b,
+            point,
+            normal,
+            depth,
+            normal_velocity: 0.0,
+            impulse: FVec::zero(),
+        }
+    }
+}
+
+/// What is precomputed for each contact before iterating.
+struct ContactState {
+    tangents: [FVec; 2],
+    normal_mass: f32,
+    tangent_masses: [f32; 2],
+    /// The normal velocity that the solver aims for.
+    target_velocity: f32,
+    /// The normal velocity that the split impulse aims for.
+    bias_target_velocity: f32,
+    normal_impulse: f32,
+    tangent_impulses: [f32; 2],
+    bias_impulse: f32,
+}
+
+/// Two distinct bodies from a slice.
+pub(crate) fn pair(
+    bodies: &mut [SolverBody],
+    a: usize,
+    b: usize,
+) -> (&mut SolverBody, &mut SolverBody) {
+    assert_ne!(a, b);
+    if a < b {
+        let (l, r) = bodies.split_at_mut(b);
+        (&mut l[a], &mut r[0])
+    } else {
+        let (l, r) = bodies.split_at_mut(a);
+        (&mut r[0], &mut l[b])
+    }
+}
+
+/// Two unit vectors perpendicular to the normal and to each other.
+pub(crate) fn tangents(normal: FVec) -> [FVec; 2] {
+    let other = if normal.x.abs() < 0.57 {
+        FVec::unit_x()
+    } else {
+        FVec::unit_y()
+    };
+    let first = normal.cross(other).normalized();
+    [first, normal.cross(first)]
+}
+
+/// Solves the contacts, changing the velocities and bias velocities of the
+/// bodies, and filling in the impulse and normal velocity of each contact.
+pub fn solve(
+    bodies: &mut [SolverBody],
+    contacts: &mut [Contact],
+    settings: &ContactSettings,
+    timestep: f32,
+) {
+    let mut states = contacts
+        .iter_mut()
+        .map(|contact| {
+            let (a, b) = pair(bodies, contact.a, contact.b);
+            let point = contact.point;
+            let inv_mass = |direction: FVec| {
+                a.inv_effective_mass(point, direction) + b.inv_effective_mass(point, direction)
+            };
+            let tangents = tangents(contact.normal);
+            let relative_velocity = a.velocity_at(point) - b.velocity_at(point);
+            contact.normal_velocity = relative_velocity.dot(contact.normal);
+            contact.impulse = FVec::zero();
+            ContactState {
+                tangents,
+                normal_mass: 1.0 / inv_mass(contact.normal),
+                tangent_masses: [1.0 / inv_mass(tangents[0]), 1.0 / inv_mass(tangents[1])],
+                target_velocity: if contact.normal_velocity < -settings.restitution_threshold {
+                    -settings.restitution * contact.normal_velocity
+                } else {
+                    0.0
+                },
+                bias_target_velocity: settings.position_correction / timestep
+                    * (contact.depth - settings.slop).max(0.0),
+                normal_impulse: 0.0,
+                tangent_impulses: [0.0; 2],
+                bias_impulse: 0.0,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    for _ in 0..settings.iterations {
+        for (contact, state) in contacts.iter_mut().zip(states.iter_mut()) {
+            let (a, b) = pair(bodies, contact.a, contact.b);
+            let point = contact.point;
+
+            // Friction is limited by the normal impulse from the last pass.
+            let relative_velocity = a.velocity_at(point) - b.velocity_at(point);
+            let old_tangent_impulses = state.tangent_impulses;
+            for i in 0..2 {
+                state.tangent_impulses[i] -=
+                    relative_velocity.dot(state.tangents[i]) * state.tangent_masses[i];
+            }
+            let max_friction = settings.friction * state.normal_impulse;
+            let magnitude =
+                (state.tangent_impulses[0].powi(2) + state.tangent_impulses[1].powi(2)).sqrt();
+            if magnitude > max_friction {
+                let scale = max_friction / magnitude;
+                state.tangent_impulses[0] *= scale;
+                state.tangent_impulses[1] *= scale;
+            }
+            let friction = state.tangents[0]
+                * (state.tangent_impulses[0] - old_tangent_impulses[0])
+                + state.tangents[1] * (state.tangent_impulses[1] - old_tangent_impulses[1]);
+            a.apply_impulse(friction, point);
+            b.apply_impulse(-friction, point);
+            contact.impulse += friction;
+
+            let normal_velocity = (a.velocity_at(point) - b.velocity_at(point)).dot(contact.normal);
+            let old_impulse = state.normal_impulse;
+            state.normal_impulse = (old_impulse
+                + (state.target_velocity - normal_velocity) * state.normal_mass)
+                .max(0.0);
+            let impulse = contact.normal * (state.normal_impulse - old_impulse);
+            a.apply_impulse(impulse, point);
+            b.apply_impulse(-impulse, point);
+            contact.impulse += impulse;
+
+            let bias_velocity =
+                (a.bias_velocity_at(point) - b.bias_velocity_at(point)).dot(contact.normal);
+            let old_bias_impulse = state.bias_impulse;
+            state.bias_impulse = (old_bias_impulse
+                + (state.bias_target_velocity - bias_velocity) * state.normal_mass)
+                .max(0.0);
+            let bias_impulse = contact.normal * (state.bias_impulse - old_bias_impulse);
+            a.apply_bias_impulse(bias_impulse, point);
+            b.apply_bias_impulse(-bias_impulse, point);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::test_util::assert_close;
+
+    fn ball(position: FVec, velocity: FVec) -> SolverBody {
+        SolverBody::new(position, velocity, FVec::zero(), 1.0, FMat::from_scale(6.0))
+    }
+
+    fn ground() -> SolverBody {
+        SolverBody::new(
+            FVec::new(0.0, -1.0, 0.0),
+            FVec::zero(),
+            FVec::zero(),
+            0.0,
+            FMat::from_scale(0.0),
+        )
+    }
+
+    fn energy(body: &SolverBody) -> f32 {
+        let linear = if body.inv_mass == 0.0 {
+            0.0
+        } else {
+            body.velocity.mag_sq() / body.inv_mass
+        };
+        let angular = body
+            .angular_velocity
+            .dot(body.inv_inertia.inversed() * body.angular_velocity);
+        0.5 * (linear + angular)
+    }
+
+    #[test]
+    fn test_restitution() {
+        for &restitution in [0.0, 0.5, 1.0].iter() {
+            let settings = ContactSettings {
+                restitution,
+                ..Default::default()
+            };
+            let mut bodies = [
+                ball(FVec::new(-0.5, 0.0, 0.0), FVec::new(2.0, 0.0, 0.0)),
+                ball(FVec::new(0.5, 0.0, 0.0), FVec::new(-2.0, 0.0, 0.0)),
+            ];
+            let mut contacts = [Contact::new(
+                0,
+                1,
+                FVec::zero(),
+                FVec::new(-1.0, 0.0, 0.0),
+                0.0,
+            )];
+            solve(&mut bodies, &mut contacts, &settings, 0.1);
+            assert_close(
+                bodies[0].velocity,
+                FVec::new(-2.0 * restitution, 0.0, 0.0),
+                0.01,
+            );
+            assert_close(
+                bodies[1].velocity,
+                FVec::new(2.0 * restitution, 0.0, 0.0),
+                0.01,
+            );
+            assert_eq!(contacts[0].normal_velocity, -4.0);
+            assert_close(
+                contacts[0].impulse,
+                FVec::new(-2.0 * (1.0 + restitution), 0.0, 0.0),
+                0.01,
+            );
+            assert_close(bodies[0].impulse, contacts[0].impulse, 0.01);
+        }
+    }
+
+    #[test]
+    fn test_friction() {
+        // A body that cannot rotate, landing on the ground while sliding.
+        let mut body = ball(FVec::zero(), FVec::new(1.0, -1.0, 0.0));
+        body.inv_inertia = FMat::from_scale(0.0);
+        let mut bodies = [body, ground()];
+        let mut contacts = [Contact::new(
+            0,
+            1,
+            FVec::new(0.0, -0.5, 0.0),
+            FVec::unit_y(),
+            0.0,
+        )];
+        let settings = ContactSettings {
+            restitution: 0.0,
+            friction: 0.25,
+            ..Default::default()
+        };
+        solve(&mut bodies, &mut contacts, &settings, 0.1);
+        assert_close(bodies[0].velocity, FVec::new(0.75, 0.0, 0.0), 0.01);
+        assert_eq!(bodies[1].velocity, FVec::zero());
+    }
+
+    #[test]
+    fn test_split_impulse() {
+        let mut bodies = [ball(FVec::zero(), FVec::zero()), ground()];
+        let mut contacts = [Contact::new(
+            0,
+            1,
+            FVec::new(0.0, -0.5, 0.0),
+            FVec::unit_y(),
+            0.5,
+        )];
+        solve(&mut bodies, &mut contacts, &ContactSettings::default(), 0.1);
+        // Penetration is removed without adding momentum.
+        assert_eq!(bodies[0].velocity, FVec::zero());
+        assert_eq!(bodies[0].impulse, FVec::zero());
+        assert!(bodies[0].bias_velocity.y > 0.0);
+    }
+
+    #[test]
+    fn test_no_energy_gain() {
+        // A spinning body hitting the ground off center at several points.
+        let mut body = ball(FVec::new(0.3, 0.0, 0.1), FVec::new(0.5, -3.0, 0.2));
+        body.angular_velocity = FVec::new(1.0, 2.0, -4.0);
+        body.inv_inertia = FMat::from_nonuniform_scale(FVec::new(1.0, 0.5, 0.25));
+        for &restitution in [0.0, 0.5, 1.0].iter() {
+            let mut bodies = [body, ground()];
+            let mut contacts = [
+                Contact::new(0, 1, FVec::new(-0.5, -0.5, -0.5), FVec::unit_y(), 0.1),
+                Contact::new(0, 1, FVec::new(0.5, -0.5, -0.5), FVec::unit_y(), 0.05),
+                Contact::new(0, 1, FVec::new(0.5, -0.5, 0.5), FVec::unit_y(), 0.0),
+            ];
+            let settings = ContactSettings {
+                restitution,
+                ..Default::default()
+            };
+            solve(&mut bodies, &mut contacts, &settings, 0.1);
+            assert!(energy(&bodies[0]) <= energy(&body) * 1.0001);
+            for contact in contacts.iter() {
+                assert!(contact.impulse.dot(contact.normal) >= 0.0);
+            }
+        }
+    }
+}
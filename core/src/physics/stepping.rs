@@ -0,0 +1,357 @@
+//! Running the physics schedule at a fixed rate, independent of the frame
+//! rate, splitting each step into substeps, and blending between physics
+//! states for display.
+use super::*;
+use bevy::ecs::ShouldRun;
+use ultraviolet::Lerp;
+
+/// How often the physics schedule is run.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Stepping {
+    /// Runs one step every app update, whatever the frame time.
+    /// This is mostly useful for tests.
+    EveryUpdate,
+    /// Runs as many steps as fit in the time since the last update, carrying
+    /// the remainder over to the next one. At most `max_steps` are run per
+    /// update, and any time beyond that is dropped, so that a slow frame
+    /// slows the simulation down rather than making it fall further behind.
+    Fixed { max_steps: u32 },
+}
+impl Default for Stepping {
+    fn default() -> Self {
+        Stepping::Fixed { max_steps: 4 }
+    }
+}
+
+/// How many substeps each physics step is split into.
+/// The physics schedule is run once for every substep, integrating, colliding
+/// and resolving over the length of the substep, which keeps fast, heavy
+/// bodies from sinking deep into each other within a single step.
+/// Forces, thrust, sleeping, diagnostics, stress and deterministic bodies are
+/// still only stepped once per step.
+/// New contacts are only searched for on the first substep. Only the pairs
+/// that were in contact, or close to it, at the start of the step are collided
+/// on the later substeps.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Substepping {
+    /// Every step is split into the same number of substeps.
+    Fixed(u32),
+    /// Each step gets enough substeps that no body moves further than
+    /// `max_travel` in one, up to `max_substeps`.
+    /// Every body is integrated as many times as the fastest body needs.
+    Adaptive { max_substeps: u32, max_travel: f32 },
+}
+impl Default for Substepping {
+    fn default() -> Self {
+        Substepping::Fixed(1)
+    }
+}
+
+impl Substepping {
+    /// The substeps needed by a body moving at `speed` in a step of
+    /// `timestep`.
+    pub fn substeps_for(&self, speed: f32, timestep: f32) -> u32 {
+        match *self {
+            Substepping::Fixed(substeps) => substeps.max(1),
+            Substepping::Adaptive {
+                max_substeps,
+                max_travel,
+            } => {
+                let substeps = (speed * timestep / max_travel).ceil();
+                if substeps >= max_substeps as f32 {
+                    max_substeps.max(1)
+                } else {
+                    (substeps as u32).max(1)
+                }
+            }
+        }
+    }
+}
+
+/// The substep that the physics schedule is running.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Substep {
+    pub index: u32,
+    /// The substeps of the current step.
+    pub count: u32,
+}
+impl Default for Substep {
+    fn default() -> Self {
+        Substep { index: 0, count: 1 }
+    }
+}
+
+impl Substep {
+    pub fn is_first(&self) -> bool {
+        self.index == 0
+    }
+
+    pub fn is_last(&self) -> bool {
+        self.index + 1 >= self.count
+    }
+
+    /// The length of the substep, in a step of `timestep`.
+    pub fn length(&self, timestep: &Timestep) -> f32 {
+        timestep.0 / self.count as f32
+    }
+}
+
+/// How far the time of the current frame is between the previous and the
+/// current physics state, from 0 to 1.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Interpolation(pub f32);
+impl Default for Interpolation {
+    fn default() -> Self {
+        Interpolation(1.0)
+    }
+}
+
+/// The position of a body before the last physics step.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct PreviousPosition(pub FVec);
+/// The rotation of a body before the last physics step.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct PreviousRotation(pub Rot);
+
+/// The time that has passed but not been simulated yet.
+/// This is kept as a resource, so that snapshots can capture it.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct StepClock {
+    pub accumulated: f64,
+    /// The steps run so far this frame.
+    pub steps: u32,
+    in_frame: bool,
+}
+
+impl StepClock {
+    /// Adds the time of a new frame.
+    pub fn start_frame(&mut self, delta: f64) {
+        self.accumulated += delta;
+        self.steps = 0;
+        self.in_frame = true;
+    }
+
+    /// Whether to run another step this frame, taking its time off the
+    /// accumulator if so.
+    pub fn step(&mut self, timestep: f64, max_steps: u32) -> bool {
+        if self.accumulated >= timestep && self.steps < max_steps {
+            self.accumulated -= timestep;
+            self.steps += 1;
+            return true;
+        }
+        self.accumulated %= timestep;
+        self.in_frame = false;
+        false
+    }
+
+    pub fn alpha(&self, timestep: f64) -> f32 {
+        (self.accumulated / timestep) as f32
+    }
+}
+
+/// The run criteria of the physics schedule.
+/// The schedule is run once for every substep of each step.
+pub fn step_criteria(
+    time: Res<Time>,
+    timestep: Res<Timestep>,
+    stepping: Res<Stepping>,
+    mut substep: ResMut<Substep>,
+    mut interpolation: ResMut<Interpolation>,
+    mut clock: ResMut<StepClock>,
+) -> ShouldRun {
+    // The rest of the substeps of a step are run before starting another.
+    if !substep.is_last() {
+        substep.index += 1;
+        return ShouldRun::YesAndLoop;
+    }
+    let step = match *stepping {
+        Stepping::EveryUpdate => {
+            clock.in_frame = !clock.in_frame;
+            clock.in_frame
+        }
+        Stepping::Fixed { max_steps } => {
+            let timestep = timestep.0 as f64;
+            if !clock.in_frame {
+                clock.start_frame(time.delta_seconds_f64());
+            }
+            let step = clock.step(timestep, max_steps);
+            if !step {
+                interpolation.0 = clock.alpha(timestep);
+            }
+            step
+        }
+    };
+    if step {
+        // The number of substeps is chosen by `choose_substeps`.
+        substep.index = 0;
+        ShouldRun::YesAndLoop
+    } else {
+        ShouldRun::No
+    }
+}
+
+/// Chooses the number of substeps of a step from the speeds of the bodies.
+/// This is added to the start of the "pre-physics" stage.
+#[allow(clippy::type_complexity)]
+pub fn choose_substeps(
+    timestep: Res<Timestep>,
+    substepping: Res<Substepping>,
+    mut substep: ResMut<Substep>,
+    dynamic: Query<(&InvMass, &Momentum), (Without<Static>, Without<Kinematic>, Without<Sleeping>)>,
+    kinematic: Query<&Kinematic>,
+) {
+    if !substep.is_first() {
+        return;
+    }
+    let speeds = dynamic
+        .iter()
+        .map(|(im, m)| (m.0 * im.0).mag())
+        .chain(kinematic.iter().map(|k| k.velocity.mag()));
+    substep.count = speeds
+        .map(|speed| substepping.substeps_for(speed, timestep.0))
+        .fold(substepping.substeps_for(0.0, timestep.0), u32::max);
+}
+
+/// Remembers the pose of every body before it is stepped.
+/// This is added to the start of the "pre-physics" stage, and only runs on the
+/// first substep of each step.
+pub fn store_previous_state(
+    pool: Res<ComputeTaskPool>,
+    substep: Res<Substep>,
+    mut query: Query<(
+        &Position,
+        &Rotation,
+        &mut PreviousPosition,
+        &mut PreviousRotation,
+    )>,
+) {
+    if !substep.is_first() {
+        return;
+    }
+    query
+        .par_iter_mut(128)
+        .for_each(&pool.0, |(p, r, mut pp, mut pr)| {
+            pp.0 = p.0;
+            pr.0 = r.0;
+        });
+}
+
+/// The pose of a body between its previous and current physics states, for
+/// display.
+pub fn interpolate_pose(
+    pp: &PreviousPosition,
+    pr: &PreviousRotation,
+    p: &Position,
+    r: &Rotation,
+    interpolation: &Interpolation,
+) -> (Position, Rotation) {
+    let alpha = interpolation.0;
+    let delta = rotation_vector(r.0 * pr.0.reversed());
+    (
+        Position(pp.0.lerp(p.0, alpha)),
+        Rotation(integrate_rotation(pr.0, delta * alpha)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::contact::CollisionEvent;
+    use super::*;
+    use crate::octree::octree_set::BBOctreeSet;
+    use crate::octree::test_util::solid_storage;
+    use crate::octree::EditableOctreeSet;
+    use crate::physics::test_util::{add_octree_collide, init_app};
+
+    fn unit_set() -> BBOctreeSet {
+        BBOctreeSet::from_chunk_storage(&solid_storage(vec![IVec::zero()]))
+    }
+
+    fn collisions(app: &App) -> usize {
+        let events = app.resources.get::<Events<CollisionEvent>>().unwrap();
+        events.get_reader().iter(&events).count()
+    }
+
+    fn set_stepping(app: &mut App, stepping: Stepping) {
+        *app.resources.get_mut::<Stepping>().unwrap() = stepping;
+    }
+
+    /// Edits made in a frame that runs no steps are still seen by the next
+    /// step.
+    #[test]
+    fn test_edits_between_steps() {
+        let mut app = init_app(0.1);
+        add_octree_collide::<BBOctreeSet>(&mut app);
+        let mut app = app.app;
+        let ground = app.world.spawn(StaticBundle::new(
+            FVec::zero(),
+            Rot::identity(),
+            vec![(IVec::zero(), 1)],
+        ));
+        app.world.insert_one(ground, unit_set()).unwrap();
+        let body = app.world.spawn(PhysicsBundle::new(
+            FVec::new(0.95, 0.0, 0.0),
+            Rot::identity(),
+            FVec::zero(),
+            vec![(IVec::zero(), 1)],
+        ));
+        app.world.insert_one(body, unit_set()).unwrap();
+        app.update();
+        assert!(collisions(&app) > 0);
+
+        // A frame that runs no steps, as when less than a timestep has
+        // passed.
+        set_stepping(&mut app, Stepping::Fixed { max_steps: 0 });
+        app.world
+            .get_mut::<BBOctreeSet>(ground)
+            .unwrap()
+            .clear(IVec::zero());
+        app.world
+            .get_mut::<ChangedBodies>(body)
+            .unwrap()
+            .0
+            .push((IVec::new(1, 0, 0), 1));
+        app.update();
+        assert_eq!(app.world.get::<Mass>(body).unwrap().0, 1);
+
+        set_stepping(&mut app, Stepping::EveryUpdate);
+        app.update();
+        assert_eq!(app.world.get::<Mass>(body).unwrap().0, 2);
+        assert_eq!(collisions(&app), 0);
+    }
+
+    #[test]
+    fn test_step_clock() {
+        let mut clock = StepClock::default();
+        let run_frame = |clock: &mut StepClock, delta: f64| {
+            clock.start_frame(delta);
+            let mut steps = 0;
+            while clock.step(0.25, 3) {
+                steps += 1;
+            }
+            steps
+        };
+        assert_eq!(run_frame(&mut clock, 0.125), 0);
+        assert_eq!(clock.alpha(0.25), 0.5);
+        assert_eq!(run_frame(&mut clock, 0.625), 3);
+        assert_eq!(clock.alpha(0.25), 0.0);
+        // A long frame only runs the most steps, dropping the rest.
+        assert_eq!(run_frame(&mut clock, 2.125), 3);
+        assert_eq!(clock.alpha(0.25), 0.5);
+        assert_eq!(run_frame(&mut clock, 0.125), 1);
+    }
+
+    #[test]
+    fn test_interpolate_pose() {
+        let pp = PreviousPosition(FVec::zero());
+        let pr = PreviousRotation(Rot::identity());
+        let p = Position(FVec::new(2.0, 0.0, 0.0));
+        let r = Rotation(integrate_rotation(Rot::identity(), FVec::unit_z()));
+        let (position, rotation) = interpolate_pose(&pp, &pr, &p, &r, &Interpolation(0.5));
+        assert!((position.0 - FVec::unit_x()).mag() < 0.0001);
+        let half = integrate_rotation(Rot::identity(), FVec::unit_z() * 0.5);
+        assert!((rotation.0 * FVec::unit_x() - half * FVec::unit_x()).mag() < 0.0001);
+        let (position, rotation) = interpolate_pose(&pp, &pr, &p, &r, &Interpolation(1.0));
+        assert_eq!(position, p);
+        assert!((rotation.0 * FVec::unit_y() - r.0 * FVec::unit_y()).mag() < 0.0001);
+    }
+}
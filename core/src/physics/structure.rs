@@ -0,0 +1,454 @@
+//! Structural integrity, so that ships break apart under loads that their
+//! voxels cannot carry.
+//!
+//! Every voxel with mass is a node of the structure, linked to the voxels that
+//! share a face with it. Each tick, the forces on a body from contacts and
+//! thrusters, minus the forces needed to accelerate each voxel along with the
+//! rest of the rigid body, are spread through the links. This is done by
+//! finding the flow of least squares through the links that balances every
+//! voxel, which is the solution of a graph Laplacian, like the currents
+//! through a network of resistors. Links that carry more than the weaker of
+//! their voxels' strengths break, and that voxel is removed.
+use super::contact::CollisionEvent;
+use super::edit::edit_voxels;
+use super::flight::FlightComputer;
+use super::mass_properties::MassVoxel;
+use super::thruster::{thrust_level, Throttle, Thrusters};
+use super::*;
+use crate::octree::EditableOctreeSet;
+use crate::storage::VoxelStorage;
+use std::collections::HashMap;
+
+/// The most iterations that the stress solve makes.
+const STRESS_ITERATIONS: usize = 256;
+/// The residual of the stress solve, relative to the loads, at which it stops.
+const STRESS_TOLERANCE: f32 = 0.0001;
+
+/// A voxel type, with how much load it can carry.
+/// Voxels without mass are not part of the structure.
+pub trait StructuralVoxel: MassVoxel {
+    /// The largest force that a link to this voxel can carry.
+    fn strength(&self) -> f32;
+}
+
+/// The voxels of a body and the links between them, along with the load that
+/// each link carried in the last tick.
+/// This is kept up to date with the body's voxels by `update_structure`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Structure {
+    voxels: Vec<IVec>,
+    masses: Vec<f32>,
+    strengths: Vec<f32>,
+    index: HashMap<IVec, usize>,
+    links: Vec<(usize, usize)>,
+    /// The connected part that each voxel is in.
+    parts: Vec<usize>,
+    part_count: usize,
+    /// The load carried by each link in the last tick, in the order of
+    /// `links`.
+    pub loads: Vec<f32>,
+    /// The voxels that broke in the last tick, which are removed by
+    /// `break_voxels`.
+    pub broken: Vec<IVec>,
+}
+
+fn dot(a: &[FVec], b: &[FVec]) -> f32 {
+    a.iter().zip(b.iter()).map(|(a, b)| a.dot(*b)).sum()
+}
+
+fn find(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    let mut i = i;
+    while parents[i] != root {
+        let next = parents[i];
+        parents[i] = root;
+        i = next;
+    }
+    root
+}
+
+impl Structure {
+    /// Creates a structure from the position, mass and strength of every voxel
+    /// with mass.
+    pub fn new(voxels: impl IntoIterator<Item = (IVec, i64, f32)>) -> Self {
+        let mut positions = vec![];
+        let mut masses = vec![];
+        let mut strengths = vec![];
+        for (position, mass, strength) in voxels {
+            positions.push(position);
+            masses.push(mass as f32);
+            strengths.push(strength);
+        }
+        let index = positions
+            .iter()
+            .enumerate()
+            .map(|(i, &position)| (position, i))
+            .collect::<HashMap<_, _>>();
+        let mut links = vec![];
+        let mut parents = (0..positions.len()).collect::<Vec<_>>();
+        for (i, &position) in positions.iter().enumerate() {
+            for &offset in [IVec::unit_x(), IVec::unit_y(), IVec::unit_z()].iter() {
+                if let Some(&j) = index.get(&(position + offset)) {
+                    links.push((i, j));
+                    let (a, b) = (find(&mut parents, i), find(&mut parents, j));
+                    parents[a] = b;
+                }
+            }
+        }
+        let mut roots = HashMap::new();
+        let parts = (0..positions.len())
+            .map(|i| {
+                let root = find(&mut parents, i);
+                let next = roots.len();
+                *roots.entry(root).or_insert(next)
+            })
+            .collect::<Vec<_>>();
+        Structure {
+            loads: vec![0.0; links.len()],
+            voxels: positions,
+            masses,
+            strengths,
+            index,
+            links,
+            parts,
+            part_count: roots.len(),
+            broken: vec![],
+        }
+    }
+
+    /// The links between voxels, with the load that each carried in the last
+    /// tick.
+    pub fn links(&self) -> impl Iterator<Item = (IVec, IVec, f32)> + '_ {
+        self.links
+            .iter()
+            .zip(self.loads.iter())
+            .map(move |(&(a, b), &load)| (self.voxels[a], self.voxels[b], load))
+    }
+
+    /// How many parts the structure is in, that are not linked to each other.
+    /// A body in more than one part should be split.
+    pub fn part_count(&self) -> usize {
+        self.part_count
+    }
+
+    fn laplacian(&self, x: &[FVec], out: &mut [FVec]) {
+        for value in out.iter_mut() {
+            *value = FVec::zero();
+        }
+        for &(a, b) in self.links.iter() {
+            let difference = x[a] - x[b];
+            out[a] += difference;
+            out[b] -= difference;
+        }
+    }
+
+    /// Spreads the loads on the voxels through the links, setting the load
+    /// carried by each link. The loads on each part should sum to zero, and
+    /// what they do not is taken off of every voxel of the part equally.
+    pub fn spread_loads(&mut self, mut loads: Vec<FVec>) {
+        let mut totals = vec![(FVec::zero(), 0.0); self.part_count];
+        for (load, &part) in loads.iter().zip(self.parts.iter()) {
+            totals[part].0 += *load;
+            totals[part].1 += 1.0;
+        }
+        for (load, &part) in loads.iter_mut().zip(self.parts.iter()) {
+            *load -= totals[part].0 / totals[part].1;
+        }
+        // The flow through each link is the difference of a potential at its
+        // ends, which is found by conjugate gradients.
+        let n = loads.len();
+        let mut potentials = vec![FVec::zero(); n];
+        let mut direction = loads.clone();
+        let mut product = vec![FVec::zero(); n];
+        let mut residual = loads;
+        let mut residual_sq: f32 = dot(&residual, &residual);
+        let tolerance = residual_sq * STRESS_TOLERANCE * STRESS_TOLERANCE;
+        for _ in 0..STRESS_ITERATIONS {
+            if residual_sq <= tolerance {
+                break;
+            }
+            self.laplacian(&direction, &mut product);
+            let step = residual_sq / dot(&direction, &product);
+            if !step.is_finite() {
+                break;
+            }
+            for ((u, r), (d, p)) in potentials
+                .iter_mut()
+                .zip(residual.iter_mut())
+                .zip(direction.iter().zip(product.iter()))
+            {
+                *u += *d * step;
+                *r -= *p * step;
+            }
+            let new_residual_sq = dot(&residual, &residual);
+            let ratio = new_residual_sq / residual_sq;
+            residual_sq = new_residual_sq;
+            for (d, r) in direction.iter_mut().zip(residual.iter()) {
+                *d = *r + *d * ratio;
+            }
+        }
+        self.loads = self
+            .links
+            .iter()
+            .map(|&(a, b)| (potentials[a] - potentials[b]).mag())
+            .collect();
+    }
+
+    /// The voxels to remove for the links that carry more than they can, being
+    /// the weaker voxel of each link.
+    fn overloaded(&self) -> Vec<IVec> {
+        let mut broken = vec![];
+        for (&(a, b), &load) in self.links.iter().zip(self.loads.iter()) {
+            let weaker = if self.strengths[a] <= self.strengths[b] {
+                a
+            } else {
+                b
+            };
+            if load > self.strengths[weaker] && !broken.contains(&self.voxels[weaker]) {
+                broken.push(self.voxels[weaker]);
+            }
+        }
+        broken
+    }
+}
+
+/// Builds the structure of every body whose `Storage` has changed.
+/// This should be added to a stage before the physics schedule, for the
+/// storage type of the bodies.
+pub fn update_structure<Storage>(
+    commands: &mut Commands,
+    query: Query<(Entity, &Storage), Changed<Storage>>,
+) where
+    Storage: 'static + VoxelStorage<Position = IVec> + Send + Sync,
+    Storage::T: StructuralVoxel, {
+    for (e, storage) in query.iter() {
+        let mut voxels = vec![];
+        storage.for_each(|(position, voxel)| {
+            let mass = voxel.mass();
+            if mass > 0 {
+                voxels.push((position, mass, voxel.strength()));
+            }
+        });
+        commands.insert_one(e, Structure::new(voxels));
+    }
+}
+
+/// Finds the loads on the structure of every awake body, and the voxels that
+/// break under them.
+/// The forces from contacts and thrusters are counted. Forces applied to the
+/// center of mass, such as from force fields, accelerate every voxel alike, so
+/// they put no load on the structure.
+/// This is added to the "structure" stage, after the contacts are resolved, and
+/// only runs on the last substep of each step.
+#[allow(clippy::type_complexity)]
+pub fn compute_stress(
+    timestep: Res<Timestep>,
+    substep: Res<Substep>,
+    mut reader: Local<EventReader<CollisionEvent>>,
+    events: Res<Events<CollisionEvent>>,
+    mut query: Query<
+        (
+            Entity,
+            &Rotation,
+            &InvInertiaAroundCenterOfMass,
+            &AngularMomentum,
+            Option<&Thrusters>,
+            Option<&Throttle>,
+            Option<&FlightComputer>,
+            &mut Structure,
+        ),
+        Without<Sleeping>,
+    >,
+) {
+    if !substep.is_last() {
+        return;
+    }
+    // The forces from contacts on each body, at their voxels, in world space.
+    // The impulses of every substep of the step are averaged over it.
+    let mut contacts = HashMap::<Entity, Vec<(IVec, FVec)>>::new();
+    for event in reader.iter(&events) {
+        let force = event.impulse / timestep.0;
+        contacts
+            .entry(event.a)
+            .or_default()
+            .push((event.a_voxel, force));
+        contacts
+            .entry(event.b)
+            .or_default()
+            .push((event.b_voxel, -force));
+    }
+    for (e, r, iiacom, am, thrusters, throttle, computer, mut structure) in query.iter_mut() {
+        if structure.voxels.is_empty() {
+            continue;
+        }
+        let to_voxel_space = r.0.reversed();
+        let mut forces = vec![FVec::zero(); structure.voxels.len()];
+        for &(voxel, force) in contacts.get(&e).into_iter().flatten() {
+            if let Some(&i) = structure.index.get(&voxel) {
+                forces[i] += to_voxel_space * force;
+            }
+        }
+        for (i, (voxel, thruster)) in thrusters.iter().flat_map(|t| t.0.iter()).enumerate() {
+            let level = thrust_level(i, thruster, throttle, computer);
+            if let Some(&j) = structure.index.get(voxel) {
+                forces[j] += thruster.direction * (thruster.max_thrust * level);
+            }
+        }
+        let angular_velocity = to_voxel_space * (world_inv_inertia(r, iiacom) * am.0);
+        if angular_velocity == FVec::zero() && forces.iter().all(|&f| f == FVec::zero()) {
+            structure.loads.iter_mut().for_each(|load| *load = 0.0);
+            structure.broken.clear();
+            continue;
+        }
+        // The motion of the rigid body under these forces, with the mass
+        // properties of the structure itself.
+        let mass: f32 = structure.masses.iter().sum();
+        let center = structure
+            .voxels
+            .iter()
+            .zip(structure.masses.iter())
+            .map(|(&v, &m)| FVec::new(v.x as f32, v.y as f32, v.z as f32) * m)
+            .fold(FVec::zero(), Add::add)
+            / mass;
+        let offsets = structure
+            .voxels
+            .iter()
+            .map(|&v| FVec::new(v.x as f32, v.y as f32, v.z as f32) - center)
+            .collect::<Vec<_>>();
+        let mut inertia = FMat::from_scale(0.0);
+        let mut force = FVec::zero();
+        let mut torque = FVec::zero();
+        for ((&offset, &m), &f) in offsets
+            .iter()
+            .zip(structure.masses.iter())
+            .zip(forces.iter())
+        {
+            // Each voxel is a cube of side 1.
+            let point: FMat = inertia_of_position(*offset.as_array(), m).into();
+            inertia = inertia + point + FMat::identity() * (m / 6.0);
+            force += f;
+            torque += offset.cross(f);
+        }
+        let acceleration = force / mass;
+        let angular_acceleration =
+            inertia.inversed() * (torque - angular_velocity.cross(inertia * angular_velocity));
+        let loads = offsets
+            .iter()
+            .zip(structure.masses.iter())
+            .zip(forces.iter())
+            .map(|((&offset, &m), &f)| {
+                let voxel_acceleration = acceleration
+                    + angular_acceleration.cross(offset)
+                    + angular_velocity.cross(angular_velocity.cross(offset));
+                f - voxel_acceleration * m
+            })
+            .collect();
+        structure.spread_loads(loads);
+        structure.broken = structure.overloaded();
+    }
+}
+
+/// Removes the voxels that broke from every body, replacing them with the
+/// ambient voxel of the `Storage`, and removing them from the collider `Set`.
+/// This should be added to a stage before the one that the structures and
+/// thrusters are updated in, such as `stage::PRE_UPDATE`, so that they see the
+/// changed storage.
+pub fn break_voxels<Storage, Set>(
+    mut query: Query<(&mut Storage, &mut Set, &mut Structure, &mut ChangedBodies)>,
+) where
+    Storage: 'static + VoxelStorage<Position = IVec> + Send + Sync,
+    Storage::T: StructuralVoxel,
+    Set: 'static + EditableOctreeSet + Send + Sync, {
+    for (mut storage, mut collider, mut structure, mut changed) in query.iter_mut() {
+        if structure.broken.is_empty() {
+            continue;
+        }
+        let ambient = storage.ambient();
+        let edits = std::mem::take(&mut structure.broken)
+            .into_iter()
+            .map(|voxel| (voxel, ambient));
+        edit_voxels(&mut *storage, &mut *collider, Some(&mut *changed), edits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::octree_set::BBOctreeSet;
+    use crate::physics::test_util::{self, spawn_storage_body, TestVoxel};
+    use crate::storage::chunk_map::ChunkStorage;
+    use crate::storage::Writer;
+
+    fn init_app() -> App {
+        let mut app = test_util::init_app(0.1);
+        app.add_system_to_stage(
+            stage::PRE_UPDATE,
+            break_voxels::<ChunkStorage<TestVoxel>, BBOctreeSet>.system(),
+        )
+        .add_system(update_structure::<ChunkStorage<TestVoxel>>.system());
+        app.app
+    }
+
+    /// Two 3x3x3 blocks of hull on either side of the origin, joined by a
+    /// single voxel.
+    fn spawn_dumbbell(app: &mut App, neck: TestVoxel) -> Entity {
+        let mut storage = ChunkStorage::new(TestVoxel::Empty, 16);
+        for x in -3..=3 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    if x != 0 {
+                        *storage.get_mut(IVec::new(x, y, z)).get_mut() = TestVoxel::Hull;
+                    }
+                }
+            }
+        }
+        *storage.get_mut(IVec::zero()).get_mut() = neck;
+        spawn_storage_body(app, FVec::zero(), Rot::identity(), storage)
+    }
+
+    /// Spins a body around z at 1 radian per second.
+    fn spin(app: &mut App, e: Entity) {
+        app.update();
+        let inertia = app.world.get::<InertiaAroundCenterOfMass>(e).unwrap().0;
+        app.world.get_mut::<AngularMomentum>(e).unwrap().0 = inertia * FVec::unit_z();
+    }
+
+    #[test]
+    fn test_spinning_load() {
+        let mut app = init_app();
+        let e = spawn_dumbbell(&mut app, TestVoxel::Hull);
+        spin(&mut app, e);
+        app.update();
+        let structure = app.world.get::<Structure>(e).unwrap();
+        assert_eq!(structure.part_count(), 1);
+        // The neck holds each block of 27 voxels, centered 2 from the axis,
+        // in its orbit.
+        let max = structure
+            .links()
+            .map(|(_, _, load)| load)
+            .fold(0.0, f32::max);
+        assert!((max - 54.0).abs() < 0.5);
+        assert!(structure.broken.is_empty());
+    }
+
+    #[test]
+    fn test_weak_neck_breaks() {
+        let mut app = init_app();
+        let e = spawn_dumbbell(&mut app, TestVoxel::Neck);
+        spin(&mut app, e);
+        app.update();
+        assert_eq!(
+            app.world.get::<Structure>(e).unwrap().broken,
+            vec![IVec::zero()]
+        );
+        app.update();
+        let storage = app.world.get::<ChunkStorage<TestVoxel>>(e).unwrap();
+        assert_eq!(*storage.get(IVec::zero()), TestVoxel::Empty);
+        assert_eq!(app.world.get::<Mass>(e).unwrap().0, 54);
+        app.update();
+        assert_eq!(app.world.get::<Structure>(e).unwrap().part_count(), 2);
+    }
+}
@@ -0,0 +1,190 @@
+//! Thrusters, which push ships from the voxels they are built out of.
+use super::flight::FlightComputer;
+use super::*;
+use crate::storage::VoxelStorage;
+
+/// A thruster, as described by its voxel type.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Thruster {
+    /// The force at full throttle.
+    pub max_thrust: f32,
+    /// The direction that the thruster pushes the ship in, in voxel space.
+    /// This should be normalized.
+    pub direction: FVec,
+}
+
+/// A voxel type, some of which are thrusters.
+pub trait ThrusterVoxel {
+    fn thruster(&self) -> Option<Thruster>;
+}
+
+/// The thrusters of a ship, with the voxels they are in.
+/// This is kept up to date with the ship's voxels by `update_thrusters`, so
+/// that a destroyed thruster stops pushing.
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct Thrusters(pub Vec<(IVec, Thruster)>);
+
+/// The control input of a ship, in its voxel space.
+/// Each thruster fires with the part of this along its direction, clamped to
+/// between 0 and 1, so a throttle along x fires every thruster that pushes
+/// along x.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct Throttle(pub FVec);
+
+impl Throttle {
+    /// How far the throttle opens a thruster, from 0 to 1.
+    pub fn level(&self, thruster: &Thruster) -> f32 {
+        thruster.direction.dot(self.0).max(0.0).min(1.0)
+    }
+}
+
+/// Finds the thrusters of every ship whose `Storage` has changed.
+/// This should be added to a stage before the physics schedule, for the
+/// storage type of the ships.
+pub fn update_thrusters<Storage>(
+    commands: &mut Commands,
+    query: Query<(Entity, &Storage), Changed<Storage>>,
+) where
+    Storage: 'static + VoxelStorage<Position = IVec> + Send + Sync,
+    Storage::T: ThrusterVoxel, {
+    for (e, storage) in query.iter() {
+        let mut thrusters = vec![];
+        storage.for_each(|(position, voxel)| {
+            if let Some(thruster) = voxel.thruster() {
+                thrusters.push((position, thruster));
+            }
+        });
+        commands.insert_one(e, Thrusters(thrusters));
+    }
+}
+
+/// How far the `i`th thruster of a ship fires, from 0 to 1.
+pub(super) fn thrust_level(
+    i: usize,
+    thruster: &Thruster,
+    throttle: Option<&Throttle>,
+    computer: Option<&FlightComputer>,
+) -> f32 {
+    match (computer, throttle) {
+        (Some(computer), _) => computer.levels.get(i).copied().unwrap_or(0.0),
+        (None, Some(throttle)) => throttle.level(thruster),
+        (None, None) => 0.0,
+    }
+}
+
+/// Pushes every ship with its thrusters, as set by its `FlightComputer` if it
+/// has one, or else by its `Throttle`.
+/// The force of each thruster is applied at its voxel, the same point that the
+/// voxel's mass is counted at.
+/// This is added to the "pre-physics" stage, before sleeping bodies are woken,
+/// so that thrust wakes sleeping ships. The forces last for the whole step, so
+/// they are only applied on its first substep.
+#[allow(clippy::type_complexity)]
+pub fn apply_thrust(
+    substep: Res<Substep>,
+    mut query: Query<(
+        &Thrusters,
+        Option<&Throttle>,
+        Option<&FlightComputer>,
+        &Position,
+        &Rotation,
+        &CenterOfMass,
+        &mut Force,
+        &mut Torque,
+    )>,
+) {
+    if !substep.is_first() {
+        return;
+    }
+    for (thrusters, throttle, computer, p, r, com, mut f, mut t) in query.iter_mut() {
+        for (i, (voxel, thruster)) in thrusters.0.iter().enumerate() {
+            let level = thrust_level(i, thruster, throttle, computer);
+            if level == 0.0 {
+                continue;
+            }
+            let force = r.0 * thruster.direction * (thruster.max_thrust * level);
+            let position = voxel_to_world(voxel_point(*voxel), p, r, com);
+            apply_force(force, position, (&mut f, &mut t, p));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::test_util::{self, spawn_storage_body, TestVoxel};
+    use crate::storage::chunk_map::ChunkStorage;
+    use crate::storage::Writer;
+
+    fn init_app() -> App {
+        let mut app = test_util::init_app(0.1);
+        app.add_system(update_thrusters::<ChunkStorage<TestVoxel>>.system());
+        app.app
+    }
+
+    /// A column of hull with an engine on either end.
+    fn spawn_ship(app: &mut App, throttle: FVec) -> Entity {
+        let mut storage = ChunkStorage::new(TestVoxel::Empty, 16);
+        for &y in [-1, 0, 1].iter() {
+            *storage.get_mut(IVec::new(0, y, 0)).get_mut() = TestVoxel::Hull;
+        }
+        for &y in [-1, 1].iter() {
+            *storage.get_mut(IVec::new(-1, y, 0)).get_mut() = TestVoxel::Engine;
+        }
+        let e = spawn_storage_body(app, FVec::zero(), Rot::identity(), storage);
+        app.world.insert_one(e, Throttle(throttle)).unwrap();
+        e
+    }
+
+    #[test]
+    fn test_thrust() {
+        let mut app = init_app();
+        let e = spawn_ship(&mut app, FVec::unit_x());
+        for _ in 0..10 {
+            app.update();
+        }
+        assert_eq!(app.world.get::<Thrusters>(e).unwrap().0.len(), 2);
+        let momentum = app.world.get::<Momentum>(e).unwrap().0;
+        assert!((momentum - FVec::new(2.0, 0.0, 0.0)).mag() < 0.001);
+        assert!(app.world.get::<AngularMomentum>(e).unwrap().0.mag() < 0.001);
+
+        // The engines only push forwards.
+        app.world.get_mut::<Throttle>(e).unwrap().0 = -FVec::unit_x();
+        app.update();
+        assert_eq!(app.world.get::<Momentum>(e).unwrap().0, momentum);
+    }
+
+    #[test]
+    fn test_destroyed_thruster() {
+        let mut app = init_app();
+        let e = spawn_ship(&mut app, FVec::unit_x());
+        app.update();
+        let position = IVec::new(-1, 1, 0);
+        *app.world
+            .get_mut::<ChunkStorage<TestVoxel>>(e)
+            .unwrap()
+            .get_mut(position)
+            .get_mut() = TestVoxel::Empty;
+        app.world
+            .get_mut::<ChangedBodies>(e)
+            .unwrap()
+            .0
+            .push((position, -1));
+        for _ in 0..3 {
+            app.update();
+        }
+        assert_eq!(
+            app.world.get::<Thrusters>(e).unwrap().0,
+            vec![(
+                IVec::new(-1, -1, 0),
+                Thruster {
+                    max_thrust: 1.0,
+                    direction: FVec::unit_x(),
+                }
+            )]
+        );
+        // The remaining engine is below the center of mass, so pushing along
+        // x turns the ship around +z.
+        assert!(app.world.get::<AngularMomentum>(e).unwrap().0.z > 0.0);
+    }
+}
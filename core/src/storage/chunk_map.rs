@@ -17,6 +17,7 @@ fn convert_from_point(a: PointN<[i32; 3]>) -> IVec {
     a.0.into()
 }
 
+#[derive(Clone)]
 pub struct ChunkStorage<T: 'static + Eq + Copy> {
     pub map: ChunkHashMap3<T, ChunkIndex>,
 }
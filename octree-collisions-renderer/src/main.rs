@@ -9,15 +9,19 @@ use bevy::tasks::TaskPool;
 use bevy_orbit_controls::*;
 use building_blocks::mesh::*;
 use building_blocks::prelude::*;
-use counterproduction_core::collision::{octree::OctreeCollisionResolver, *};
 use counterproduction_core::for_each::ForEach;
 use counterproduction_core::geometry::FVec;
 use counterproduction_core::geometry::IVec;
 use counterproduction_core::geometry::Rot;
 use counterproduction_core::octree::octree_set::BBOctreeSet;
+use counterproduction_core::physics::contact::*;
+use counterproduction_core::physics::diagnostics::*;
+use counterproduction_core::physics::explosion::*;
+use counterproduction_core::physics::stepping::*;
+use counterproduction_core::physics::structure::*;
+use counterproduction_core::physics::thruster::*;
 use counterproduction_core::physics::Position;
 use counterproduction_core::physics::*;
-use itertools::Itertools;
 
 use counterproduction_core::storage::chunk_map::ChunkStorage;
 use counterproduction_core::storage::*;
@@ -35,18 +39,32 @@ fn main() {
         .add_system(display_sync_transform_system.system())
         .add_system(auto_mesh_system.system())
         .add_system(octree_generator.system())
-        .add_system(energy_printer.system())
+        .add_system(update_thrusters::<ChunkStorage<SimpleVoxel>>.system())
+        .add_system(update_structure::<ChunkStorage<SimpleVoxel>>.system())
+        .add_system_to_stage(
+            stage::PRE_UPDATE,
+            break_voxels::<ChunkStorage<SimpleVoxel>, BBOctreeSet>.system(),
+        )
+        .add_system_to_stage(
+            stage::PRE_UPDATE,
+            explode::<ChunkStorage<SimpleVoxel>, BBOctreeSet>.system(),
+        )
+        .add_system(diagnostics_printer.system())
         .add_stage_before(
             stage::UPDATE,
             "physics-schedule",
-            Schedule::default()
-                // .with_run_criteria(FixedTimestep::step(1.0 / 60.0))
-                .with_stage(
-                    "collide",
-                    SystemStage::serial().with_system(octree_collide.system()),
-                ),
+            Schedule::default().with_stage(
+                "collide",
+                SystemStage::serial().with_system(octree_collide::<BBOctreeSet>.system()),
+            ),
         )
-        .add_plugin(PhysicsPlugin::new(1.0 / 60.0, "physics-schedule"))
+        .add_plugin(PhysicsPlugin {
+            substepping: Substepping::Adaptive {
+                max_substeps: 4,
+                max_travel: 0.25,
+            },
+            ..PhysicsPlugin::new(1.0 / 60.0, "physics-schedule")
+        })
         .run();
 }
 
@@ -101,6 +119,10 @@ fn startup_create_storage(
     {
         let mut storage = ChunkStorage::new(Empty.into(), 16);
         chain_link_2(&mut storage, IVec::zero(), 2, 10, 15);
+        // Engines on either side, pushing the link along x.
+        for &z in [-10, 10].iter() {
+            *storage.get_mut(IVec::new(-18, 0, z)).get_mut() = Engine.into();
+        }
         let physics = PhysicsBundle::new(
             FVec::new(20.0, 0.0, 0.0),
             Rot::identity(),
@@ -117,6 +139,7 @@ fn startup_create_storage(
                 storage,
                 ChunkMeshes(vec![]),
                 GlobalTransform::default(),
+                Throttle(FVec::new(0.2, 0.0, 0.0)),
             ))
             .with_bundle(physics);
     }
@@ -143,70 +166,25 @@ fn startup_create_storage(
     }
 }
 
-fn apply_force_to_entity(mut query: Query<(&mut Force, &mut Torque, &Position)>) {
-    for mut q in query.iter_mut() {
-        apply_force_bundle(
-            FVec::new(1.0, 0.0, 0.0) * 20.0,
-            q.2 .0 + FVec::new(0.0, 0.0, 2.0),
-            &mut q,
-        );
-        apply_force_bundle(
-            FVec::new(-1.0, 0.0, 0.0) * 20.0,
-            q.2 .0 + FVec::new(0.0, 0.0, -2.0),
-            &mut q,
+fn diagnostics_printer(mut printed: Local<usize>, diagnostics: Res<PhysicsDiagnostics>) {
+    if let Some(totals) = diagnostics.latest() {
+        println!(
+            "Energy: {:?}, momentum: {:?}, angular momentum: {:?}",
+            totals.kinetic_energy(),
+            totals.linear_momentum,
+            totals.angular_momentum
         );
     }
-}
-#[allow(clippy::many_single_char_names)]
-fn octree_collide(
-    mut query: Query<(
-        &BBOctreeSet,
-        &mut Force,
-        &mut Torque,
-        &Position,
-        &Rotation,
-        &Mass,
-    )>,
-) {
-    // println!("Colliding");
-    let mut v = query.iter_mut().collect::<Vec<_>>();
-    for (i, j) in (0..v.len()).tuple_combinations() {
-        assert!(i < j);
-        let (l, r) = v.split_at_mut(j);
-        let (o1, ref mut f1, ref mut t1, p1, r1, m1) = l[i];
-        let (o2, ref mut f2, ref mut t2, p2, r2, m2) = r[0];
-        let x = Positioned::new(o1, p1.0, r1.0);
-        let y = Positioned::new(o2, p2.0, r2.0);
-        let collisions = OctreeCollisionResolver::collide(x, y);
-        if !collisions.is_empty() {
-            // println!(
-            //     "Entities {:?} and {:?} have collided: {:?}",
-            //     e1,
-            //     e2,
-            //     collisions.len()
-            // );
-            for (a_pos, b_pos, penetration) in collisions {
-                apply_collision(
-                    (f1, t1, p1, r1, m1),
-                    (f2, t2, p2, r2, m2),
-                    FVec::new(a_pos.x as f32, a_pos.y as f32, a_pos.z as f32),
-                    FVec::new(b_pos.x as f32, b_pos.y as f32, b_pos.z as f32),
-                    penetration,
-                );
-            }
-        }
+    for (tick, violation) in diagnostics.violations.iter().skip(*printed) {
+        println!("Violation at tick {}: {:?}", tick, violation);
     }
+    *printed = diagnostics.violations.len();
 }
-fn energy_printer(query: Query<&Momentum>) {
-    let mut total_ke = 0.0;
-    for v in query.iter() {
-        total_ke += v.0.mag_sq();
-    }
-    println!("Energy: {:?}", total_ke);
-}
+/// Builds the collider of new bodies. Later edits to their voxels keep it up to
+/// date themselves.
 fn octree_generator(
     commands: &mut Commands,
-    query: Query<(Entity, &ChunkStorage<SimpleVoxel>), Changed<ChunkStorage<SimpleVoxel>>>,
+    query: Query<(Entity, &ChunkStorage<SimpleVoxel>), Added<ChunkStorage<SimpleVoxel>>>,
 ) {
     for (e, storage) in query.iter() {
         commands.insert_one(e, BBOctreeSet::from_chunk_storage(storage));
@@ -215,9 +193,18 @@ fn octree_generator(
 
 fn display_sync_transform_system(
     commands: &mut Commands,
-    query: Query<(Entity, &Position, &Rotation, &CenterOfMass)>,
+    interpolation: Res<Interpolation>,
+    query: Query<(
+        Entity,
+        &PreviousPosition,
+        &PreviousRotation,
+        &Position,
+        &Rotation,
+        &CenterOfMass,
+    )>,
 ) {
-    for (e, &Position(s), &Rotation(r), &CenterOfMass(com)) in query.iter() {
+    for (e, pp, pr, p, r, &CenterOfMass(com)) in query.iter() {
+        let (Position(s), Rotation(r)) = interpolate_pose(pp, pr, p, r, &interpolation);
         commands.insert_one(
             e,
             Transform {
@@ -1,6 +1,10 @@
 use bevy::prelude::*;
 use building_blocks::mesh::MaterialVoxel;
 use building_blocks::prelude::IsEmpty;
+use counterproduction_core::geometry::FVec;
+use counterproduction_core::physics::mass_properties::MassVoxel;
+use counterproduction_core::physics::structure::StructuralVoxel;
+use counterproduction_core::physics::thruster::{Thruster, ThrusterVoxel};
 use enum_dispatch::*;
 
 #[enum_dispatch]
@@ -8,6 +12,7 @@ use enum_dispatch::*;
 pub enum SimpleVoxel {
     Empty,
     Solid,
+    Engine,
 }
 #[enum_dispatch(SimpleVoxel)]
 pub trait SimpleVoxelType: Copy + Eq {
@@ -41,6 +46,44 @@ impl SimpleVoxelType for Solid {
         1
     }
 }
+/// A thruster, which pushes along x.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Engine;
+impl SimpleVoxelType for Engine {
+    fn color(self) -> Color {
+        Color::rgb(0.85, 0.45, 0.15)
+    }
+    fn collidable(self) -> bool {
+        true
+    }
+    fn mass(self) -> i64 {
+        1
+    }
+}
+impl ThrusterVoxel for SimpleVoxel {
+    fn thruster(&self) -> Option<Thruster> {
+        match self {
+            SimpleVoxel::Engine(_) => Some(Thruster {
+                max_thrust: 20.0,
+                direction: FVec::unit_x(),
+            }),
+            _ => None,
+        }
+    }
+}
+impl MassVoxel for SimpleVoxel {
+    fn mass(&self) -> i64 {
+        SimpleVoxelType::mass(*self)
+    }
+}
+impl StructuralVoxel for SimpleVoxel {
+    fn strength(&self) -> f32 {
+        match self {
+            SimpleVoxel::Engine(_) => 200.0,
+            _ => 500.0,
+        }
+    }
+}
 impl IsEmpty for SimpleVoxel {
     fn is_empty(&self) -> bool {
         *self == SimpleVoxel::Empty(Empty)
@@ -0,0 +1,148 @@
+//! Runs a physics scenario without a window, so that changes to the physics
+//! can be checked without watching them.
+//!
+//! Usage: `scenario-runner SCENARIO [--trajectories PATH] [--diagnostics PATH]`
+//!
+//! The scenario is a JSON file, as described by `scenario::Scenario`. The
+//! trajectories and diagnostics of every tick are written as JSON or CSV,
+//! depending on the extension of their paths. The exit code is 1 if any of
+//! the scenario's assertions fail, and 2 if the scenario could not be run.
+use record::Recording;
+use scenario::Scenario;
+use std::process::exit;
+
+mod record;
+mod scenario;
+
+struct Arguments {
+    scenario: String,
+    trajectories: Option<String>,
+    diagnostics: Option<String>,
+}
+
+fn parse_arguments() -> Result<Arguments, String> {
+    let mut args = std::env::args().skip(1);
+    let mut scenario = None;
+    let mut trajectories = None;
+    let mut diagnostics = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trajectories" => trajectories = args.next(),
+            "--diagnostics" => diagnostics = args.next(),
+            _ if scenario.is_none() => scenario = Some(arg),
+            _ => return Err(format!("unexpected argument {:?}", arg)),
+        }
+    }
+    Ok(Arguments {
+        scenario: scenario.ok_or("no scenario was given")?,
+        trajectories,
+        diagnostics,
+    })
+}
+
+fn load(path: &str) -> Result<Scenario, String> {
+    let file = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    serde_json::from_str(&file).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Writes records as CSV if the path ends in `.csv`, and as JSON otherwise.
+fn write<T: serde::Serialize>(
+    path: &str,
+    records: &T,
+    csv: impl FnOnce() -> String,
+) -> Result<(), String> {
+    let contents = if path.ends_with(".csv") {
+        csv()
+    } else {
+        serde_json::to_string_pretty(records).map_err(|e| e.to_string())?
+    };
+    std::fs::write(path, contents).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn write_recording(arguments: &Arguments, recording: &Recording) -> Result<(), String> {
+    if let Some(path) = &arguments.trajectories {
+        write(path, &recording.trajectories, || {
+            recording.trajectories_csv()
+        })?;
+    }
+    if let Some(path) = &arguments.diagnostics {
+        write(path, &recording.diagnostics, || recording.diagnostics_csv())?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let arguments = parse_arguments().unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        eprintln!("usage: scenario-runner SCENARIO [--trajectories PATH] [--diagnostics PATH]");
+        exit(2);
+    });
+    let scenario = load(&arguments.scenario).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        exit(2);
+    });
+    let recording = record::run(&scenario);
+    if let Err(e) = write_recording(&arguments, &recording) {
+        eprintln!("error: {}", e);
+        exit(2);
+    }
+    let results: Vec<_> = scenario
+        .assertions
+        .iter()
+        .map(|a| recording.check(a))
+        .collect();
+    for e in results.iter().filter_map(|r| r.as_ref().err()) {
+        eprintln!("assertion failed: {}", e);
+    }
+    let passed = results.iter().filter(|r| r.is_ok()).count();
+    println!(
+        "Ran {} ticks of {} bodies, {} of {} assertions passed.",
+        scenario.ticks,
+        scenario.bodies.len(),
+        passed,
+        results.len()
+    );
+    if passed < results.len() {
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scenario::Assertion;
+
+    #[test]
+    fn test_collision_scenario() {
+        let scenario = load(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/scenarios/collision.json"
+        ))
+        .unwrap();
+        let recording = record::run(&scenario);
+        assert_eq!(recording.diagnostics.len(), scenario.ticks);
+        assert_eq!(recording.trajectories.len(), scenario.ticks * 3);
+        for assertion in scenario.assertions.iter() {
+            assert_eq!(recording.check(assertion), Ok(()));
+        }
+        let right = recording.trajectories.iter().find(|r| r.body == "right");
+        let w = scenario::vec(right.unwrap().angular_velocity);
+        assert!((w - scenario::vec([0.0, 0.5, 0.0])).mag() < 0.001);
+        for d in recording.diagnostics.iter() {
+            assert!(scenario::vec(d.linear_momentum).mag() < 0.001);
+        }
+        // The boxes bounced off each other, so they no longer move towards
+        // each other.
+        let failing = Assertion::Velocity {
+            body: "left".to_string(),
+            tick: None,
+            velocity: [1.0, 0.0, 0.0],
+            tolerance: 0.1,
+        };
+        assert!(recording.check(&failing).is_err());
+        assert_eq!(
+            recording.trajectories_csv().lines().count(),
+            scenario.ticks * 3 + 1
+        );
+    }
+}
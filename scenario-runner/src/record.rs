@@ -0,0 +1,219 @@
+//! Running a scenario, and what is recorded of it.
+use crate::scenario::*;
+use bevy::prelude::*;
+use counterproduction_core::geometry::*;
+use counterproduction_core::octree::linear_octree_set::LinearOctreeSet;
+use counterproduction_core::physics::contact::octree_collide;
+use counterproduction_core::physics::diagnostics::PhysicsDiagnostics;
+use counterproduction_core::physics::stepping::{Stepping, Substepping};
+use counterproduction_core::physics::*;
+use serde::Serialize;
+use std::fmt::Write;
+
+fn array(v: FVec) -> [f32; 3] {
+    [v.x, v.y, v.z]
+}
+
+/// The state of a body after a tick.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct BodyRecord {
+    pub tick: usize,
+    pub body: String,
+    /// The world position of the center of mass.
+    pub position: [f32; 3],
+    /// The rotor, as `[s, xy, xz, yz]`.
+    pub rotation: [f32; 4],
+    pub velocity: [f32; 3],
+    pub angular_velocity: [f32; 3],
+}
+
+/// The diagnostics after a tick.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct DiagnosticsRecord {
+    pub tick: usize,
+    pub kinetic_energy: f32,
+    pub linear_energy: f32,
+    pub angular_energy: f32,
+    pub linear_momentum: [f32; 3],
+    pub angular_momentum: [f32; 3],
+    /// What the diagnostics flagged during the tick.
+    pub violations: Vec<String>,
+}
+
+/// Everything recorded of a scenario, in the order of the ticks.
+#[derive(Clone, PartialEq, Default, Debug, Serialize)]
+pub struct Recording {
+    pub trajectories: Vec<BodyRecord>,
+    pub diagnostics: Vec<DiagnosticsRecord>,
+}
+
+/// Runs a scenario without a window, recording every tick.
+pub fn run(scenario: &Scenario) -> Recording {
+    let mut app = App::build();
+    app.add_plugin(bevy::reflect::ReflectPlugin)
+        .add_plugin(bevy::core::CorePlugin)
+        .add_plugin(PhysicsPlugin {
+            timestep: scenario.timestep as f64,
+            physics_schedule_name: None,
+            stepping: Stepping::EveryUpdate,
+            substepping: Substepping::Fixed(scenario.substeps),
+        })
+        .add_resource(PhysicsDiagnostics {
+            energy_tolerance: scenario.energy_tolerance,
+            momentum_tolerance: scenario.momentum_tolerance,
+            ..Default::default()
+        })
+        .stage("physics-schedule", |schedule: &mut Schedule| {
+            schedule
+                .add_system_to_stage("pre-physics", set_initial_angular_velocity.system())
+                .add_system_to_stage("collide", octree_collide::<LinearOctreeSet>.system())
+        });
+    let mut app = app.app;
+    for body in scenario.bodies.iter() {
+        body.spawn(&mut app.world);
+    }
+    let mut recording = Recording::default();
+    for tick in 0..scenario.ticks {
+        app.update();
+        let mut bodies = app
+            .world
+            .query::<(
+                &BodyName,
+                &Position,
+                &Rotation,
+                &InvMass,
+                &InvInertiaAroundCenterOfMass,
+                Option<&Momentum>,
+                Option<&AngularMomentum>,
+                Option<&Kinematic>,
+            )>()
+            .map(|(name, p, r, im, iiacom, m, am, k)| {
+                let (velocity, angular_velocity) = match (k, m, am) {
+                    (Some(k), _, _) => (k.velocity, k.angular_velocity),
+                    (None, Some(m), Some(am)) => (m.0 * im.0, world_inv_inertia(r, iiacom) * am.0),
+                    _ => (FVec::zero(), FVec::zero()),
+                };
+                BodyRecord {
+                    tick,
+                    body: name.0.clone(),
+                    position: array(p.0),
+                    rotation: [r.0.s, r.0.bv.xy, r.0.bv.xz, r.0.bv.yz],
+                    velocity: array(velocity),
+                    angular_velocity: array(angular_velocity),
+                }
+            })
+            .collect::<Vec<_>>();
+        bodies.sort_by(|a, b| a.body.cmp(&b.body));
+        recording.trajectories.extend(bodies);
+        let diagnostics = app.resources.get::<PhysicsDiagnostics>().unwrap();
+        let totals = diagnostics.latest().unwrap_or_default();
+        recording.diagnostics.push(DiagnosticsRecord {
+            tick,
+            kinetic_energy: totals.kinetic_energy(),
+            linear_energy: totals.linear_energy,
+            angular_energy: totals.angular_energy,
+            linear_momentum: array(totals.linear_momentum),
+            angular_momentum: array(totals.angular_momentum),
+            violations: diagnostics
+                .violations
+                .iter()
+                .filter(|(t, _)| *t == diagnostics.tick - 1)
+                .map(|(_, violation)| format!("{:?}", violation))
+                .collect(),
+        });
+    }
+    recording
+}
+
+impl Recording {
+    fn body(&self, body: &str, tick: Option<usize>) -> Result<&BodyRecord, String> {
+        let tick = tick.unwrap_or_else(|| self.diagnostics.len().saturating_sub(1));
+        self.trajectories
+            .iter()
+            .find(|record| record.tick == tick && record.body == body)
+            .ok_or_else(|| format!("there is no body {:?} at tick {}", body, tick))
+    }
+
+    /// Checks an assertion against the recording, describing how it failed.
+    pub fn check(&self, assertion: &Assertion) -> Result<(), String> {
+        let within = |found: [f32; 3], expected: [f32; 3], tolerance: f32| {
+            let distance = (vec(found) - vec(expected)).mag();
+            distance <= tolerance
+        };
+        match assertion {
+            Assertion::NoViolations => {
+                match self.diagnostics.iter().find(|d| !d.violations.is_empty()) {
+                    Some(d) => Err(format!("tick {}: {}", d.tick, d.violations.join(", "))),
+                    None => Ok(()),
+                }
+            }
+            Assertion::Position {
+                body,
+                tick,
+                position,
+                tolerance,
+            } => {
+                let record = self.body(body, *tick)?;
+                if within(record.position, *position, *tolerance) {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "{:?} is at {:?} at tick {}, not {:?}",
+                        body, record.position, record.tick, position
+                    ))
+                }
+            }
+            Assertion::Velocity {
+                body,
+                tick,
+                velocity,
+                tolerance,
+            } => {
+                let record = self.body(body, *tick)?;
+                if within(record.velocity, *velocity, *tolerance) {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "{:?} moves at {:?} at tick {}, not {:?}",
+                        body, record.velocity, record.tick, velocity
+                    ))
+                }
+            }
+        }
+    }
+
+    pub fn trajectories_csv(&self) -> String {
+        let mut csv = String::from("tick,body,x,y,z,s,xy,xz,yz,vx,vy,vz,wx,wy,wz\n");
+        for r in self.trajectories.iter() {
+            let values = r
+                .position
+                .iter()
+                .chain(r.rotation.iter())
+                .chain(r.velocity.iter())
+                .chain(r.angular_velocity.iter())
+                .map(|value| value.to_string())
+                .collect::<Vec<_>>();
+            writeln!(csv, "{},{},{}", r.tick, r.body, values.join(",")).unwrap();
+        }
+        csv
+    }
+
+    pub fn diagnostics_csv(&self) -> String {
+        let mut csv = String::from(
+            "tick,kinetic_energy,linear_energy,angular_energy,px,py,pz,lx,ly,lz,violations\n",
+        );
+        for d in self.diagnostics.iter() {
+            let values = [d.kinetic_energy, d.linear_energy, d.angular_energy]
+                .iter()
+                .chain(d.linear_momentum.iter())
+                .chain(d.angular_momentum.iter())
+                .map(|value| value.to_string())
+                .collect::<Vec<_>>();
+            // Violations are separated by semicolons, so that they stay in
+            // one column.
+            let violations = d.violations.join(";").replace(',', "");
+            writeln!(csv, "{},{},{}", d.tick, values.join(","), violations).unwrap();
+        }
+        csv
+    }
+}
@@ -0,0 +1,238 @@
+//! The scenario file format, and spawning the bodies it describes.
+use bevy::prelude::*;
+use counterproduction_core::geometry::*;
+use counterproduction_core::octree::linear_octree_set::LinearOctreeSet;
+use counterproduction_core::physics::*;
+use serde::Deserialize;
+use ultraviolet::Bivec3;
+
+fn default_timestep() -> f32 {
+    1.0 / 60.0
+}
+
+fn default_substeps() -> u32 {
+    1
+}
+
+fn default_density() -> i64 {
+    1
+}
+
+/// A physics scenario, as loaded from a JSON file.
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct Scenario {
+    #[serde(default = "default_timestep")]
+    pub timestep: f32,
+    /// The substeps that each tick is split into.
+    #[serde(default = "default_substeps")]
+    pub substeps: u32,
+    /// The ticks to run the scenario for.
+    pub ticks: usize,
+    pub bodies: Vec<BodyDescription>,
+    /// The relative drift in kinetic energy that the diagnostics flag.
+    #[serde(default)]
+    pub energy_tolerance: Option<f32>,
+    /// The relative drift in linear and angular momentum that the diagnostics
+    /// flag.
+    #[serde(default)]
+    pub momentum_tolerance: Option<f32>,
+    /// What must hold for the scenario to pass.
+    #[serde(default)]
+    pub assertions: Vec<Assertion>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyKind {
+    Dynamic,
+    Static,
+    Kinematic,
+}
+impl Default for BodyKind {
+    fn default() -> Self {
+        BodyKind::Dynamic
+    }
+}
+
+/// The voxels of a body, in its voxel space.
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shape {
+    Voxels(Vec<[i32; 3]>),
+    /// A box of voxels with its minimum corner at the origin.
+    Box {
+        size: [i32; 3],
+    },
+    /// A ball of voxels centered on the origin.
+    Sphere {
+        radius: i32,
+    },
+}
+
+impl Shape {
+    pub fn voxels(&self) -> Vec<IVec> {
+        match self {
+            Shape::Voxels(voxels) => voxels
+                .iter()
+                .map(|&v| IVec::new(v[0], v[1], v[2]))
+                .collect(),
+            Shape::Box { size } => {
+                let mut voxels = vec![];
+                for x in 0..size[0] {
+                    for y in 0..size[1] {
+                        for z in 0..size[2] {
+                            voxels.push(IVec::new(x, y, z));
+                        }
+                    }
+                }
+                voxels
+            }
+            &Shape::Sphere { radius } => {
+                let mut voxels = vec![];
+                for x in -radius..=radius {
+                    for y in -radius..=radius {
+                        for z in -radius..=radius {
+                            if x * x + y * y + z * z <= radius * radius {
+                                voxels.push(IVec::new(x, y, z));
+                            }
+                        }
+                    }
+                }
+                voxels
+            }
+        }
+    }
+}
+
+/// A body of a scenario. Every vector is in world space.
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct BodyDescription {
+    /// The name that the body is reported and asserted on with.
+    pub name: String,
+    #[serde(default)]
+    pub kind: BodyKind,
+    pub shape: Shape,
+    /// The mass of each voxel.
+    #[serde(default = "default_density")]
+    pub density: i64,
+    /// The world position of the origin of the voxels.
+    #[serde(default)]
+    pub position: [f32; 3],
+    /// The rotation vector of the body, whose magnitude is the angle.
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    #[serde(default)]
+    pub velocity: [f32; 3],
+    #[serde(default)]
+    pub angular_velocity: [f32; 3],
+}
+
+/// The name of a body, for finding it in the world.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BodyName(pub String);
+
+/// The angular velocity that a dynamic body starts with. Angular momentum
+/// depends on the inertia, which is only known once the physics has computed
+/// it, so this is turned into angular momentum in the first tick.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct InitialAngularVelocity(pub FVec);
+
+pub(crate) fn vec(v: [f32; 3]) -> FVec {
+    FVec::new(v[0], v[1], v[2])
+}
+
+fn rotation(v: FVec) -> Rot {
+    let angle = v.mag();
+    if angle == 0.0 {
+        Rot::identity()
+    } else {
+        Rot::from_angle_plane(angle, Bivec3::from_normalized_axis(v / angle))
+    }
+}
+
+impl BodyDescription {
+    pub fn spawn(&self, world: &mut World) -> Entity {
+        let voxels = self.shape.voxels();
+        let masses = voxels
+            .iter()
+            .map(|&v| (v, self.density))
+            .collect::<Vec<_>>();
+        let position = vec(self.position);
+        let rotation = rotation(vec(self.rotation));
+        let velocity = vec(self.velocity);
+        let angular_velocity = vec(self.angular_velocity);
+        let e = match self.kind {
+            BodyKind::Dynamic => {
+                let e = world.spawn(PhysicsBundle::new(position, rotation, velocity, masses));
+                world
+                    .insert_one(e, InitialAngularVelocity(angular_velocity))
+                    .unwrap();
+                e
+            }
+            BodyKind::Static => world.spawn(StaticBundle::new(position, rotation, masses)),
+            BodyKind::Kinematic => world.spawn(KinematicBundle::new(
+                position,
+                rotation,
+                velocity,
+                angular_velocity,
+                masses,
+            )),
+        };
+        world
+            .insert(
+                e,
+                (
+                    BodyName(self.name.clone()),
+                    LinearOctreeSet::from_positions(&voxels),
+                ),
+            )
+            .unwrap();
+        e
+    }
+}
+
+/// Gives dynamic bodies their initial angular velocity.
+/// This is added to the end of the "pre-physics" stage, after the inertia is
+/// computed.
+pub fn set_initial_angular_velocity(
+    commands: &mut Commands,
+    mut query: Query<(
+        Entity,
+        &InitialAngularVelocity,
+        &Rotation,
+        &InvInertiaAroundCenterOfMass,
+        &mut AngularMomentum,
+    )>,
+) {
+    for (e, w, r, iiacom, mut am) in query.iter_mut() {
+        if w.0 != FVec::zero() {
+            am.0 = world_inv_inertia(r, iiacom).inversed() * w.0;
+        }
+        commands.remove_one::<InitialAngularVelocity>(e);
+    }
+}
+
+/// Something that must hold at a tick of a scenario.
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Assertion {
+    /// The diagnostics flag nothing during the whole scenario.
+    NoViolations,
+    /// The center of mass of a body is within `tolerance` of `position`.
+    Position {
+        body: String,
+        /// The tick to check at, or the last one if this is not given.
+        #[serde(default)]
+        tick: Option<usize>,
+        position: [f32; 3],
+        tolerance: f32,
+    },
+    /// The velocity of a body is within `tolerance` of `velocity`.
+    Velocity {
+        body: String,
+        #[serde(default)]
+        tick: Option<usize>,
+        velocity: [f32; 3],
+        tolerance: f32,
+    },
+}